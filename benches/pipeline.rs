@@ -0,0 +1,194 @@
+//! Timings of decoding, fingerprinting and matching synthetic songs, run with
+//! `cargo bench`
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+use shazoom::database::{Database, DatabaseBuilder, DatabaseConfig, EarlyExit};
+use shazoom::encoder::{ShortNoise, Song, WavOptions};
+
+/// Pairs of tones changing every quarter second over pink noise 20 dB below them, the
+/// same for every `seed`
+fn synthetic_song(seed: u64, length: Duration, sample_rate: usize) -> Song {
+	let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+	let mut random = move || {
+		state ^= state << 13;
+		state ^= state >> 7;
+		state ^= state << 17;
+		state
+	};
+	let sample_count = (length.as_secs_f64() * sample_rate as f64) as usize;
+	let mut samples = Vec::with_capacity(sample_count);
+	while samples.len() < sample_count {
+		let note = Duration::from_millis(250);
+		let mut low = Song::sine(200. + (random() % 800) as f32, note, sample_rate);
+		let high = Song::sine(1000. + (random() % 3000) as f32, note, sample_rate);
+		low.samples
+			.iter_mut()
+			.zip(&high.samples)
+			.for_each(|(low, high)| *low = low.saturating_add(*high));
+		samples.extend(low.samples);
+	}
+	samples.truncate(sample_count);
+	let tones = Song {
+		sample_rate,
+		samples,
+	};
+	let noise = Song::pink_noise(length, sample_rate, seed);
+	Song::mix(
+		&tones,
+		&noise,
+		20.,
+		Duration::ZERO,
+		length,
+		ShortNoise::Loop,
+	)
+	.expect("neither tones nor noise are silent")
+}
+
+fn decoding(c: &mut Criterion) {
+	let config = DatabaseConfig::builder().build();
+	let song = synthetic_song(0, Duration::from_secs(180), config.sample_rate());
+	let wav = Song::to_wav(song, WavOptions::default());
+	c.bench_function("from_wav 3 min", |b| {
+		b.iter_batched(|| wav.clone(), Song::from_wav, BatchSize::LargeInput)
+	});
+}
+
+fn fingerprinting(c: &mut Criterion) {
+	let config = DatabaseConfig::builder().build();
+	let song = synthetic_song(0, Duration::from_secs(180), config.sample_rate());
+	c.bench_function("constellation_map 3 min", |b| {
+		b.iter(|| {
+			song.constellation_map(config.constellation())
+				.for_each(drop)
+		})
+	});
+	let constellation_map: Vec<_> = song.constellation_map(config.constellation()).collect();
+	c.bench_function("signatures 3 min", |b| {
+		b.iter_batched(
+			|| constellation_map.clone(),
+			|constellation_map| config.pair_peaks(constellation_map.into_iter()).count(),
+			BatchSize::LargeInput,
+		)
+	});
+}
+
+fn building(c: &mut Criterion) {
+	const SONG_COUNT: u64 = 20;
+	let config = DatabaseConfig::builder().build();
+	let songs_dir = std::env::temp_dir().join(format!("shazoom-bench-{}", std::process::id()));
+	std::fs::create_dir_all(&songs_dir).expect("the temporary directory is writable");
+	let names: Vec<_> = (0..SONG_COUNT)
+		.map(|seed| {
+			let name = format!("synthetic_{seed}.wav");
+			let song = synthetic_song(seed, Duration::from_secs(60), config.sample_rate());
+			std::fs::write(
+				songs_dir.join(&name),
+				Song::to_wav(song, WavOptions::default()),
+			)
+			.expect("the temporary directory is writable");
+			name
+		})
+		.collect();
+	let mut group = c.benchmark_group("build");
+	group.sample_size(10);
+	group.bench_function("20 songs of 1 min", |b| {
+		b.iter(|| {
+			let mut builder = DatabaseBuilder::new(config, songs_dir.as_path(), None::<&Path>);
+			for name in &names {
+				builder.add_song(name).expect("the songs were just written");
+			}
+			builder.build(config)
+		})
+	});
+	group.finish();
+	let _ = std::fs::remove_dir_all(&songs_dir);
+}
+
+fn matching(c: &mut Criterion) {
+	const SONG_COUNT: u64 = 100;
+	const SONG_LENGTH: Duration = Duration::from_secs(120);
+	let config = DatabaseConfig::builder().build();
+	let mut db = Database::new(config);
+	let songs: Vec<_> = (0..SONG_COUNT)
+		.map(|seed| synthetic_song(seed, SONG_LENGTH, config.sample_rate()))
+		.collect();
+	for (seed, song) in songs.iter().enumerate() {
+		let name = format!("synthetic_{seed}.wav");
+		db.add_song(name.clone().into(), config.song_data(name.as_ref(), song));
+	}
+	db.finalize();
+	let noise = Song::white_noise(SONG_LENGTH, config.sample_rate(), SONG_COUNT);
+	let mut group = c.benchmark_group("match_sample 100 songs");
+	for secs in [5, 15, 60] {
+		let sample = Song::mix(
+			&songs[SONG_COUNT as usize / 2],
+			&noise,
+			10.,
+			Duration::from_secs(30),
+			Duration::from_secs(secs),
+			ShortNoise::Loop,
+		)
+		.expect("neither song nor noise are silent");
+		group.bench_with_input(
+			BenchmarkId::from_parameter(format!("{secs} s")),
+			&sample,
+			|b, sample| b.iter(|| db.match_sample(sample)),
+		);
+	}
+	group.finish();
+	let sample = Song::mix(
+		&songs[SONG_COUNT as usize / 2],
+		&noise,
+		10.,
+		Duration::from_secs(30),
+		Duration::from_secs(60),
+		ShortNoise::Loop,
+	)
+	.expect("neither song nor noise are silent");
+	let expected = db.match_sample(&sample);
+	let mut group = c.benchmark_group("match_sample 100 songs 60 s");
+	// Just one thread pool on single core machines, as IDs must be unique
+	let mut thread_counts = vec![1, rayon::current_num_threads()];
+	thread_counts.dedup();
+	for threads in thread_counts {
+		let thread_pool = rayon::ThreadPoolBuilder::new()
+			.num_threads(threads)
+			.build()
+			.expect("a thread pool can be built");
+		db.set_thread_pool(Some(Arc::new(thread_pool)));
+		assert_eq!(db.match_sample(&sample), expected);
+		group.bench_function(BenchmarkId::new("threads", threads), |b| {
+			b.iter(|| db.match_sample(&sample))
+		});
+	}
+	group.finish();
+	db.set_thread_pool(None);
+	let mut group = c.benchmark_group("match_sample 100 songs 60 s early exit");
+	for early_exit in [
+		None,
+		Some(EarlyExit {
+			check_every: 8,
+			min_lead: 3.,
+			min_aligned: 200,
+		}),
+	] {
+		db.set_early_exit(early_exit);
+		let matches = db.match_sample(&sample);
+		assert_eq!(matches[0].id, expected[0].id);
+		assert_eq!(matches[0].early_exit, early_exit.is_some());
+		let name = match early_exit {
+			Some(_) => "on",
+			None => "off",
+		};
+		group.bench_function(name, |b| b.iter(|| db.match_sample(&sample)));
+	}
+	group.finish();
+}
+
+criterion_group!(benches, decoding, fingerprinting, building, matching);
+criterion_main!(benches);
@@ -0,0 +1,169 @@
+//! Inspection and cleanup of the fingerprint cache, one directory per `DatabaseConfig`
+
+use std::{
+	collections::HashSet,
+	ffi::OsString,
+	path::{Path, PathBuf},
+};
+
+use clap::Subcommand;
+use log::error;
+
+use shazoom::database::{
+	DatabaseConfig, SongData, SongEntry, SongFilter, CACHE_EXTENSION, CONFIG_FILE_NAME,
+	LEGACY_CACHE_EXTENSION,
+};
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum CacheCommand {
+	/// Show every cached config with its song count and size on disk
+	List,
+	/// Check that every cached song decodes
+	Verify,
+	/// Remove the caches of other configs, and of songs no longer in the songs directory
+	Prune {
+		/// Delete them, otherwise only show what would be deleted
+		#[arg(long)]
+		confirm: bool,
+	},
+}
+
+fn dir_entries(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+	let mut entries: Vec<_> = std::fs::read_dir(dir)?
+		.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+		.collect();
+	entries.sort();
+	Ok(entries)
+}
+
+/// Cached song files of a config directory, i.e. everything but its config file
+fn song_files(config_dir: &Path) -> Vec<PathBuf> {
+	match dir_entries(config_dir) {
+		Ok(entries) => entries
+			.into_iter()
+			.filter(|path| path.is_file() && !path.ends_with(CONFIG_FILE_NAME))
+			.collect(),
+		Err(err) => {
+			error!("Failed to read cache directory {config_dir:?}, {err}");
+			Vec::new()
+		}
+	}
+}
+
+fn read_config(config_dir: &Path) -> Result<DatabaseConfig, Box<dyn std::error::Error>> {
+	let bytes = std::fs::read(config_dir.join(CONFIG_FILE_NAME))?;
+	Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Names of the cache files the songs currently in `songs_dir` would be cached under
+fn expected_song_files(songs_dir: &Path, song_filter: &SongFilter) -> HashSet<OsString> {
+	let songs = match song_filter.find_songs(songs_dir) {
+		Ok(songs) => songs,
+		Err(err) => {
+			error!("Failed to read songs directory {songs_dir:?}, {err}");
+			Vec::new()
+		}
+	};
+	songs
+		.into_iter()
+		.flat_map(|song| {
+			let entry = SongEntry {
+				path: songs_dir.join(&song),
+				name: song.into_os_string(),
+			};
+			[CACHE_EXTENSION, LEGACY_CACHE_EXTENSION].map(|ext| {
+				[
+					entry.cached_file_name(ext),
+					entry.legacy_cached_file_name(ext),
+				]
+			})
+		})
+		.flatten()
+		.collect()
+}
+
+pub fn run(
+	command: &CacheCommand,
+	cache_dir: &Path,
+	songs_dir: &Path,
+	song_filter: &SongFilter,
+	config: &DatabaseConfig,
+) {
+	let config_dirs: Vec<_> = match dir_entries(cache_dir) {
+		Ok(entries) => entries.into_iter().filter(|path| path.is_dir()).collect(),
+		Err(err) => {
+			error!("Failed to read cache directory {cache_dir:?}, {err}");
+			return;
+		}
+	};
+	match command {
+		CacheCommand::List => {
+			for config_dir in config_dirs {
+				let songs = song_files(&config_dir);
+				let size: u64 = songs
+					.iter()
+					.chain(std::iter::once(&config_dir.join(CONFIG_FILE_NAME)))
+					.filter_map(|path| path.metadata().ok())
+					.map(|metadata| metadata.len())
+					.sum();
+				println!("{config_dir:?}: {} songs, {size} bytes", songs.len());
+				match read_config(&config_dir) {
+					Ok(config) => println!("\t{config:?}"),
+					Err(err) => println!("\tUnreadable {CONFIG_FILE_NAME}, {err}"),
+				}
+			}
+		}
+		CacheCommand::Verify => {
+			let (mut checked, mut corrupt) = (0, 0);
+			for song_file in config_dirs.iter().flat_map(|dir| song_files(dir)) {
+				checked += 1;
+				if let Err(err) = SongData::from_cache_file(&song_file) {
+					println!("{song_file:?}: {err}");
+					corrupt += 1;
+				}
+			}
+			println!("Checked {checked} cached songs, {corrupt} of them corrupt");
+		}
+		CacheCommand::Prune { confirm } => {
+			// A legacy named directory is migrated on the next build rather than pruned
+			let current_dir_names = [config.cached_dir_name(), config.legacy_cached_dir_name()];
+			let expected_song_files = expected_song_files(songs_dir, song_filter);
+			let stale: Vec<_> = config_dirs
+				.into_iter()
+				.flat_map(|config_dir| {
+					if !config_dir
+						.file_name()
+						.is_some_and(|name| current_dir_names.iter().any(|current| current == name))
+					{
+						return vec![config_dir];
+					}
+					song_files(&config_dir)
+						.into_iter()
+						.filter(|path| {
+							path.file_name()
+								.is_some_and(|name| !expected_song_files.contains(name))
+						})
+						.collect()
+				})
+				.collect();
+			for path in stale.iter() {
+				if !confirm {
+					println!("Would delete {path:?}");
+					continue;
+				}
+				let removed = if path.is_dir() {
+					std::fs::remove_dir_all(path)
+				} else {
+					std::fs::remove_file(path)
+				};
+				match removed {
+					Ok(()) => println!("Deleted {path:?}"),
+					Err(err) => error!("Failed to delete {path:?}, {err}"),
+				}
+			}
+			if !confirm && !stale.is_empty() {
+				println!("Run again with --confirm to delete them");
+			}
+		}
+	}
+}
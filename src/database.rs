@@ -1,110 +1,1142 @@
 //! Handles management of the song fingerprints
 
 use std::{
-	ffi::OsString,
+	borrow::Cow,
+	collections::{BTreeMap, HashMap, HashSet, VecDeque},
+	ffi::{OsStr, OsString},
 	hash::{DefaultHasher, Hash, Hasher},
-	path::PathBuf,
+	path::{Path, PathBuf},
+	sync::Arc,
 };
 
-use crate::Args;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use rayon::prelude::*;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
 
-use crate::encoder::{self, Freq, Signature, TimeStamp};
+use crate::encoder::{
+	self, Bucketing, ConstellationConfig, DecodeError, Freq, HashableF32, PackedSignature,
+	PeakPicking, Signature, TimeStamp, Window,
+};
+use crate::metadata::SongMeta;
+
+/// Bumped whenever the layout of cached song data changes, so stale caches are
+/// ignored instead of misread
+const CACHE_LAYOUT_VERSION: u32 = 1;
+/// Extension of the binary song data cache files
+pub const CACHE_EXTENSION: &str = "bin";
+/// Extension of the JSON song data cache files, only read when no binary one exists
+pub const LEGACY_CACHE_EXTENSION: &str = "json";
+/// Name of the file in every config's cache directory holding that config
+pub const CONFIG_FILE_NAME: &str = "config.json";
+/// Name of the file at the root of the cache directory mapping song names to the ids
+/// they were given, shared by every config so ids don't change between them
+const SONG_IDS_FILE_NAME: &str = "song_ids.json";
+/// The signatures of a query's slices are looked up in parallel in runs of at least this
+/// many slices, as every run pays for a table of offsets that has to be merged
+const MIN_QUERY_SLICES_PER_JOB: usize = 16;
+/// Weight of a signature held by every song when matching by `idf_weight`, as well as
+/// of every signature without it. Weights are kept as integers so sums of them don't
+/// depend on the order they're added in
+const IDF_WEIGHT_SCALE: usize = 256;
+/// Shortest slice a valid `DatabaseConfig` may have
+pub const MIN_SLICE_SIZE: std::time::Duration = std::time::Duration::from_millis(10);
+/// Default for `Database::set_offset_tolerance`
+pub const DEFAULT_OFFSET_TOLERANCE: Offset = 1;
+/// Leading bytes of every zstd frame, used to tell compressed cache files apart
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// Starts of a recording a hop apart that `align` matches, a fraction of a hop from
+/// each other, when slices overlap
+const ALIGNMENT_STEPS: u32 = 4;
+
+/// 64-bit FNV-1a over the concatenation of `chunks`, unlike `DefaultHasher` its output
+/// never changes between Rust releases
+fn fnv1a(chunks: &[&[u8]]) -> u64 {
+	chunks
+		.iter()
+		.flat_map(|chunk| chunk.iter())
+		.fold(0xcbf29ce484222325, |hash, &byte| {
+			(hash ^ byte as u64).wrapping_mul(0x100000001b3)
+		})
+}
+
+/// Appends `value` as a LEB128 varint, 7 bits per byte with the high bit set on all
+/// but the last
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+	while value >= 0x80 {
+		bytes.push(value as u8 | 0x80);
+		value >>= 7;
+	}
+	bytes.push(value as u8);
+}
+
+/// Reads a varint written by `write_varint` off the front of `bytes`
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, &'static str> {
+	let mut value = 0;
+	for shift in (0..64).step_by(7) {
+		let (&byte, rest) = bytes.split_first().ok_or("truncated varint")?;
+		*bytes = rest;
+		value |= ((byte & 0x7f) as u64) << shift;
+		if byte & 0x80 == 0 {
+			return Ok(value);
+		}
+	}
+	Err("varint longer than 64 bits")
+}
+
+/// The wrapping difference `value - previous` zigzag encoded, so small steps either
+/// way become small varints
+fn delta(value: u64, previous: u64) -> u64 {
+	let delta = value.wrapping_sub(previous) as i64;
+	((delta << 1) ^ (delta >> 63)) as u64
+}
+
+/// Inverse of `delta`
+fn undelta(encoded: u64, previous: u64) -> u64 {
+	let delta = (encoded >> 1) as i64 ^ -((encoded & 1) as i64);
+	previous.wrapping_add(delta as u64)
+}
 
+/// Index of a song in its database, stable across saving and loading
 pub type SongId = u32;
+/// A song holding a signature, along with the slice it's at
+pub type Posting = (SongId, TimeStamp);
+/// Slices between the start of a song and the start of a sample of it
 pub type Offset = i32;
 
-#[derive(Clone, Copy, Debug, Hash, Serialize)]
+/// Everything affecting a song's signatures, songs are only comparable under the same
+/// config
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct DatabaseConfig {
-	slice_size: std::time::Duration,
-	freq_per_slice: usize,
-	bucket_size: Freq,
-	bucket_count: usize,
-	target_zone_size: (TimeStamp, Freq),
+	constellation: ConstellationConfig,
+	/// Width and height of the target zone, the width is always a whole number of hops
+	target_zone_size: (std::time::Duration, Freq),
+	/// Slices between an anchor and the start of its target zone
+	target_zone_offset: TimeStamp,
+	/// Caps the signatures each anchor pairs into, keeping the nearest targets
+	max_fanout_per_anchor: Option<usize>,
+	/// Peaks are rounded to the nearest multiple of this many Hz, so a peak landing a
+	/// bin over in a noisy sample still produces the same signatures
+	freq_fuzz: Freq,
+	/// A signature repeating within this many slices of its last kept occurrence in a
+	/// song is dropped, 0 only drops exact duplicates
+	dedupe_window: TimeStamp,
+	sample_rate: usize,
+	/// Caps the signatures of every slice at this many per second of audio
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	max_signatures_per_second: Option<usize>,
+}
+impl Hash for DatabaseConfig {
+	/// Hashes like the derived impl did before fields were added, so
+	/// `legacy_cached_dir_name` still finds the caches of configs not using them
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.constellation.hash(state);
+		self.target_zone_size.hash(state);
+		self.target_zone_offset.hash(state);
+		self.max_fanout_per_anchor.hash(state);
+		self.freq_fuzz.hash(state);
+		self.dedupe_window.hash(state);
+		self.sample_rate.hash(state);
+		if let Some(max_signatures_per_second) = self.max_signatures_per_second {
+			max_signatures_per_second.hash(state);
+		}
+	}
 }
 impl DatabaseConfig {
+	/// Decodes the song at `path` and resamples it to the configured rate
+	pub fn load_song(&self, path: &Path) -> Result<encoder::Song, DecodeError> {
+		encoder::Song::from_file(path).map(|song| song.resample(self.sample_rate))
+	}
+	/// Decodes a WAV stream, such as a pipe, and resamples it to the configured rate
+	pub fn load_wav(&self, reader: impl std::io::Read) -> Result<encoder::Song, DecodeError> {
+		encoder::Song::from_wav_reader(reader).map(|song| song.resample(self.sample_rate))
+	}
+	/// Name of this config's cache directory, a stable hash of its JSON form. Fields
+	/// added from now on should skip serializing their default value, so configs that
+	/// don't use them keep their existing cache
 	pub fn cached_dir_name(&self) -> OsString {
+		// Going through `Value` sorts the keys, so reordering fields changes nothing
+		let json = serde_json::to_value(self)
+			.and_then(|value| serde_json::to_vec(&value))
+			.expect("configs always serialize");
+		format!(
+			"{:016x}",
+			fnv1a(&[&CACHE_LAYOUT_VERSION.to_le_bytes(), &json])
+		)
+		.into()
+	}
+	/// `cached_dir_name` from before it was stable across Rust releases, only used to
+	/// find and migrate existing caches
+	pub fn legacy_cached_dir_name(&self) -> OsString {
 		let mut hasher = DefaultHasher::new();
+		CACHE_LAYOUT_VERSION.hash(&mut hasher);
 		self.hash(&mut hasher);
 		format!("{:016x}", hasher.finish()).into()
 	}
+	/// Signatures of `song` as stored in the database, sorted and with repeats within
+	/// the dedupe window dropped. `name` is only used for logging
+	pub fn song_data(&self, name: &OsStr, song: &encoder::Song) -> SongData {
+		let span = tracing::info_span!(
+			"song_data",
+			song = ?name,
+			signatures = tracing::field::Empty,
+			kept = tracing::field::Empty,
+		)
+		.entered();
+		// TODO: set an estimated initial capacity
+		let mut res: Vec<_> = self
+			.signatures(song)
+			.map(|(timestamp, signature)| (signature.pack(), timestamp))
+			.collect();
+		// Repeats of a signature within the window all vote for nearly the same
+		// offset, so only the first of them is kept
+		let signature_count = res.len();
+		res.sort_unstable();
+		let mut last_kept = None;
+		res.retain(|&(signature, timestamp)| match last_kept {
+			Some((kept_signature, kept_timestamp))
+				if kept_signature == signature
+					&& timestamp - kept_timestamp <= self.dedupe_window =>
+			{
+				false
+			}
+			_ => {
+				last_kept = Some((signature, timestamp));
+				true
+			}
+		});
+		info!(
+			"Kept {} of {signature_count} signatures for {name:?} after deduplication",
+			res.len()
+		);
+		span.record("signatures", signature_count);
+		span.record("kept", res.len());
+		SongData(res)
+	}
+	/// Signatures of `song`, none if it's shorter than `min_sample_length`
 	pub fn signatures<'a>(
 		&'a self,
 		song: &'a encoder::Song,
-	) -> impl Iterator<Item = Vec<Signature>> + 'a {
-		let constellation_map = song.constellation_map(
-			self.slice_size,
-			self.freq_per_slice,
-			self.bucket_size,
-			self.bucket_count,
+	) -> impl Iterator<Item = (TimeStamp, Signature)> + 'a {
+		// Zero padding a sample out to a whole slice would make up most of its spectrum
+		let slices = match self.is_too_short(song) {
+			true => 0,
+			false => usize::MAX,
+		};
+		// Peaks are all picked up front, only pairing them is left to the iterator
+		let span = tracing::debug_span!("constellation_map", slices = tracing::field::Empty);
+		let constellation_map = span.in_scope(|| song.constellation_map(self.constellation));
+		span.record("slices", constellation_map.len().min(slices));
+		self.pair_peaks(constellation_map.take(slices))
+	}
+	/// How songs are turned into constellation maps
+	pub fn constellation(&self) -> ConstellationConfig {
+		self.constellation
+	}
+	/// Length of every slice, the resolution of match offsets
+	pub fn slice_size(&self) -> std::time::Duration {
+		self.constellation.slice_size
+	}
+	/// Rate songs are resampled to before fingerprinting
+	pub fn sample_rate(&self) -> usize {
+		self.sample_rate
+	}
+	/// Shortest sample that's fingerprinted at all, a slice. Signatures take a little
+	/// longer still, as a sample's last slice has nothing after it to pair with
+	pub fn min_sample_length(&self) -> std::time::Duration {
+		self.constellation.slice_size
+	}
+	/// Whether `song` is shorter than `min_sample_length`, so has no signatures
+	pub fn is_too_short(&self, song: &encoder::Song) -> bool {
+		let (window, _) = encoder::slice_samples(
+			song.sample_rate,
+			self.constellation.slice_size,
+			self.constellation.hop_size,
 		);
+		song.samples.len() < window
+	}
+	/// Fuzzes then pairs the peaks of consecutive slices, the first being slice 0
+	pub fn pair_peaks<'a>(
+		&'a self,
+		constellation_map: impl Iterator<Item = Vec<Freq>> + 'a,
+	) -> impl Iterator<Item = (TimeStamp, Signature)> + 'a {
+		let fuzz = self.freq_fuzz.max(1);
+		let constellation_map = constellation_map.map(move |mut peaks| {
+			peaks
+				.iter_mut()
+				.for_each(|freq| *freq = freq.saturating_add(fuzz / 2) / fuzz * fuzz);
+			peaks
+		});
 		encoder::Song::signatures(
-			self.target_zone_size.0,
+			self.target_zone_width(),
 			self.target_zone_size.1,
+			self.target_zone_offset,
+			self.max_fanout_per_anchor,
+			self.max_signatures_per_slice(),
 			constellation_map,
 		)
 	}
-	pub fn from_args(
-		Args {
-			ms_timeslice_size: slice_size_ms,
-			freq_per_slice,
-			size_bucket: bucket_size,
-			count_bucket: bucket_count,
-			width_target_zone: target_zone_size_width,
-			target_zone_height: target_zone_size_height,
-			..
-		}: Args,
-	) -> Self {
-		// TODO: input validation, like `assert!(freq_per_slice >= bucket_count)`
+	/// `max_signatures_per_second` over a slice's hop, but at least one
+	fn max_signatures_per_slice(&self) -> Option<usize> {
+		self.max_signatures_per_second.map(|per_second| {
+			((per_second as f64 * self.constellation.hop_size.as_secs_f64()).round() as usize)
+				.max(1)
+		})
+	}
+	/// Slices in the target zone
+	fn target_zone_width(&self) -> TimeStamp {
+		(self.target_zone_size.0.as_millis() / self.constellation.hop_size.as_millis()) as TimeStamp
+	}
+	/// Checks the parameters make sense together, a config failing this fingerprints
+	/// songs poorly if at all
+	pub fn validate(&self) -> Result<(), ConfigError> {
+		let constellation = &self.constellation;
+		if !constellation.fft_size.is_power_of_two() {
+			return Err(ConfigError::FftSizeNotPowerOfTwo(constellation.fft_size));
+		}
+		if constellation.slice_size < MIN_SLICE_SIZE {
+			return Err(ConfigError::SliceTooShort(constellation.slice_size));
+		}
+		let (slice_samples, _) = encoder::slice_samples(
+			self.sample_rate,
+			constellation.slice_size,
+			constellation.hop_size,
+		);
+		if slice_samples > constellation.fft_size {
+			return Err(ConfigError::SliceExceedsFft {
+				slice_samples,
+				fft_size: constellation.fft_size,
+			});
+		}
+		if constellation.hop_size.as_millis() == 0 {
+			return Err(ConfigError::HopTooShort);
+		}
+		if constellation.bucket_size == 0 || constellation.bucket_count == 0 {
+			return Err(ConfigError::NoBuckets);
+		}
+		if constellation.peak_picking == PeakPicking::Bucket
+			&& constellation.freq_per_slice > constellation.bucket_count
+		{
+			return Err(ConfigError::MorePeaksThanBuckets {
+				freq_per_slice: constellation.freq_per_slice,
+				bucket_count: constellation.bucket_count,
+			});
+		}
+		let top_freq = constellation.min_freq as usize
+			+ constellation.bucket_size as usize * constellation.bucket_count;
+		if top_freq > self.sample_rate / 2 {
+			return Err(ConfigError::AboveNyquist {
+				top_freq,
+				sample_rate: self.sample_rate,
+			});
+		}
+		if self.target_zone_width() < 2 {
+			return Err(ConfigError::TargetZoneTooNarrow {
+				width: self.target_zone_size.0,
+				hop_size: constellation.hop_size,
+			});
+		}
+		if self.target_zone_offset == 0 {
+			return Err(ConfigError::TargetZoneOffsetZero);
+		}
+		if self.target_zone_offset >= self.target_zone_width() {
+			return Err(ConfigError::TargetZoneOffsetPastZone {
+				offset: self.target_zone_offset,
+				width: self.target_zone_width(),
+			});
+		}
+		if self.target_zone_size.1 < constellation.bucket_size {
+			return Err(ConfigError::TargetZoneTooLow {
+				height: self.target_zone_size.1,
+				bucket_size: constellation.bucket_size,
+			});
+		}
+		Ok(())
+	}
+	/// Starts from the defaults the CLI uses
+	pub fn builder() -> DatabaseConfigBuilder {
+		DatabaseConfigBuilder::default()
+	}
+}
+
+/// Builds a `DatabaseConfig` from the parameters it's usually described by, see
+/// `DatabaseConfig::builder`. Config files are this serialized, every field being
+/// optional
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfigBuilder {
+	slice_size_ms: u64,
+	fft_size: usize,
+	/// Half a slice when `None`
+	hop_size_ms: Option<u64>,
+	freq_per_slice: usize,
+	bucket_size: Freq,
+	bucket_count: usize,
+	target_zone_width_ms: u64,
+	target_zone_height: Freq,
+	target_zone_offset: TimeStamp,
+	max_fanout_per_anchor: Option<usize>,
+	freq_fuzz: Freq,
+	dedupe_window: TimeStamp,
+	max_signatures_per_second: Option<usize>,
+	sample_rate: usize,
+	min_freq: Freq,
+	window: Window,
+	bucketing: Bucketing,
+	noise_floor: f32,
+	whitening_width: Freq,
+	peak_picking: PeakPicking,
+	peak_time_radius: usize,
+	peak_freq_radius: Freq,
+}
+impl Default for DatabaseConfigBuilder {
+	fn default() -> Self {
+		Self {
+			slice_size_ms: 260,
+			fft_size: 16384,
+			hop_size_ms: None,
+			freq_per_slice: 8,
+			bucket_size: 180,
+			bucket_count: 20,
+			target_zone_width_ms: 1300,
+			target_zone_height: 900,
+			target_zone_offset: 1,
+			max_fanout_per_anchor: None,
+			freq_fuzz: 1,
+			dedupe_window: 0,
+			max_signatures_per_second: None,
+			sample_rate: 44100,
+			min_freq: 100,
+			window: Window::Hann,
+			bucketing: Bucketing::Linear,
+			noise_floor: 2.,
+			whitening_width: 0,
+			peak_picking: PeakPicking::Bucket,
+			peak_time_radius: 2,
+			peak_freq_radius: 30,
+		}
+	}
+}
+impl From<DatabaseConfig> for DatabaseConfigBuilder {
+	/// The builder that builds `config`
+	fn from(config: DatabaseConfig) -> Self {
+		let constellation = config.constellation;
 		Self {
-			slice_size: std::time::Duration::from_millis(slice_size_ms),
+			slice_size_ms: constellation.slice_size.as_millis() as u64,
+			fft_size: constellation.fft_size,
+			hop_size_ms: Some(constellation.hop_size.as_millis() as u64),
+			freq_per_slice: constellation.freq_per_slice,
+			bucket_size: constellation.bucket_size,
+			bucket_count: constellation.bucket_count,
+			target_zone_width_ms: config.target_zone_size.0.as_millis() as u64,
+			target_zone_height: config.target_zone_size.1,
+			target_zone_offset: config.target_zone_offset,
+			max_fanout_per_anchor: config.max_fanout_per_anchor,
+			freq_fuzz: config.freq_fuzz,
+			dedupe_window: config.dedupe_window,
+			max_signatures_per_second: config.max_signatures_per_second,
+			sample_rate: config.sample_rate,
+			min_freq: constellation.min_freq,
+			window: constellation.window,
+			bucketing: constellation.bucketing,
+			noise_floor: constellation.noise_floor.0,
+			whitening_width: constellation.whitening_width,
+			peak_picking: constellation.peak_picking,
+			peak_time_radius: constellation.peak_neighbourhood.0,
+			peak_freq_radius: constellation.peak_neighbourhood.1,
+		}
+	}
+}
+impl DatabaseConfigBuilder {
+	/// Length of every slice, in whole milliseconds
+	pub fn with_slice_size(mut self, slice_size: std::time::Duration) -> Self {
+		self.slice_size_ms = slice_size.as_millis() as u64;
+		self
+	}
+	/// Length of the per slice FFT, a power of two holding a whole slice
+	pub fn with_fft_size(mut self, fft_size: usize) -> Self {
+		self.fft_size = fft_size;
+		self
+	}
+	/// Time between the starts of consecutive slices, half a slice by default
+	pub fn with_hop_size(mut self, hop_size: std::time::Duration) -> Self {
+		self.hop_size_ms = Some(hop_size.as_millis() as u64);
+		self
+	}
+	/// Most peaks kept from every slice
+	pub fn with_freq_per_slice(mut self, freq_per_slice: usize) -> Self {
+		self.freq_per_slice = freq_per_slice;
+		self
+	}
+	/// Width in Hz of the buckets peaks are picked from
+	pub fn with_bucket_size(mut self, bucket_size: Freq) -> Self {
+		self.bucket_size = bucket_size;
+		self
+	}
+	/// Number of buckets the frequency range is split into
+	pub fn with_bucket_count(mut self, bucket_count: usize) -> Self {
+		self.bucket_count = bucket_count;
+		self
+	}
+	/// Time the target zone spans, rounded to whole hops
+	pub fn with_target_zone_width(mut self, width: std::time::Duration) -> Self {
+		self.target_zone_width_ms = width.as_millis() as u64;
+		self
+	}
+	/// Hz the target zone spans, centered on the anchor
+	pub fn with_target_zone_height(mut self, height: Freq) -> Self {
+		self.target_zone_height = height;
+		self
+	}
+	/// Slices between an anchor and the first slice of its target zone
+	pub fn with_target_zone_offset(mut self, target_zone_offset: TimeStamp) -> Self {
+		self.target_zone_offset = target_zone_offset;
+		self
+	}
+	/// Most signatures a single anchor may pair into, `None` for no limit
+	pub fn with_max_fanout_per_anchor(mut self, max_fanout_per_anchor: Option<usize>) -> Self {
+		self.max_fanout_per_anchor = max_fanout_per_anchor;
+		self
+	}
+	/// Rounds peak frequencies to the nearest multiple of this many Hz
+	pub fn with_freq_fuzz(mut self, freq_fuzz: Freq) -> Self {
+		self.freq_fuzz = freq_fuzz;
+		self
+	}
+	/// Drops a song's repeats of a signature within this many slices, 0 only drops
+	/// exact duplicates
+	pub fn with_dedupe_window(mut self, dedupe_window: TimeStamp) -> Self {
+		self.dedupe_window = dedupe_window;
+		self
+	}
+	/// Most signatures kept per second of audio, of songs and samples alike, `None` for
+	/// no limit. It's applied to every slice on its own, keeping at least one
+	pub fn with_max_signatures_per_second(
+		mut self,
+		max_signatures_per_second: Option<usize>,
+	) -> Self {
+		self.max_signatures_per_second = max_signatures_per_second;
+		self
+	}
+	/// Rate every song is resampled to before fingerprinting
+	pub fn with_sample_rate(mut self, sample_rate: usize) -> Self {
+		self.sample_rate = sample_rate;
+		self
+	}
+	/// Lowest frequency considered when picking peaks, in Hz
+	pub fn with_min_freq(mut self, min_freq: Freq) -> Self {
+		self.min_freq = min_freq;
+		self
+	}
+	/// Window applied to every slice before its FFT
+	pub fn with_window(mut self, window: Window) -> Self {
+		self.window = window;
+		self
+	}
+	/// How the buckets split the frequency range
+	pub fn with_bucketing(mut self, bucketing: Bucketing) -> Self {
+		self.bucketing = bucketing;
+		self
+	}
+	/// Drops peaks below this multiple of their slice's mean amplitude, 2 by default,
+	/// which weeds out those barely standing out from the rest of the slice. 0 keeps
+	/// them all
+	pub fn with_noise_floor(mut self, noise_floor: f32) -> Self {
+		self.noise_floor = noise_floor;
+		self
+	}
+	/// Width in Hz of the spectral whitening neighbourhood, 0 disables whitening
+	pub fn with_whitening_width(mut self, whitening_width: Freq) -> Self {
+		self.whitening_width = whitening_width;
+		self
+	}
+	/// How peaks are picked from every slice
+	pub fn with_peak_picking(mut self, peak_picking: PeakPicking) -> Self {
+		self.peak_picking = peak_picking;
+		self
+	}
+	/// Slices either side a local maximum must dominate, for `PeakPicking::LocalMaxima`
+	pub fn with_peak_time_radius(mut self, peak_time_radius: usize) -> Self {
+		self.peak_time_radius = peak_time_radius;
+		self
+	}
+	/// Hz either side a local maximum must dominate, for `PeakPicking::LocalMaxima`
+	pub fn with_peak_freq_radius(mut self, peak_freq_radius: Freq) -> Self {
+		self.peak_freq_radius = peak_freq_radius;
+		self
+	}
+	/// Panics if the config is invalid, see `try_build`
+	pub fn build(self) -> DatabaseConfig {
+		self.try_build()
+			.unwrap_or_else(|err| panic!("Invalid config, {err}"))
+	}
+	/// The config, if it passes `DatabaseConfig::validate`
+	pub fn try_build(self) -> Result<DatabaseConfig, ConfigError> {
+		let Self {
+			slice_size_ms,
+			fft_size,
+			hop_size_ms,
 			freq_per_slice,
 			bucket_size,
 			bucket_count,
-			target_zone_size: (target_zone_size_width, target_zone_size_height),
+			target_zone_width_ms,
+			target_zone_height,
+			target_zone_offset,
+			max_fanout_per_anchor,
+			freq_fuzz,
+			dedupe_window,
+			max_signatures_per_second,
+			sample_rate,
+			min_freq,
+			window,
+			bucketing,
+			noise_floor,
+			whitening_width,
+			peak_picking,
+			peak_time_radius,
+			peak_freq_radius,
+		} = self;
+		// Half overlapping slices unless asked otherwise
+		let hop_ms = hop_size_ms.unwrap_or(slice_size_ms / 2);
+		if hop_ms == 0 {
+			return Err(ConfigError::HopTooShort);
+		}
+		// Rounded to whole hops, so widths giving the same zone share a cache
+		let target_zone_slices = (target_zone_width_ms + hop_ms / 2) / hop_ms;
+		let config = DatabaseConfig {
+			constellation: ConstellationConfig {
+				slice_size: std::time::Duration::from_millis(slice_size_ms),
+				fft_size,
+				hop_size: std::time::Duration::from_millis(hop_ms),
+				freq_per_slice,
+				bucket_size,
+				bucket_count,
+				min_freq,
+				window,
+				bucketing,
+				noise_floor: HashableF32(noise_floor),
+				whitening_width,
+				peak_picking,
+				peak_neighbourhood: (peak_time_radius, peak_freq_radius),
+			},
+			target_zone_size: (
+				std::time::Duration::from_millis(target_zone_slices * hop_ms),
+				target_zone_height,
+			),
+			target_zone_offset,
+			max_fanout_per_anchor,
+			freq_fuzz,
+			dedupe_window,
+			sample_rate,
+			max_signatures_per_second,
+		};
+		config.validate().map(|()| config)
+	}
+}
+
+/// A constraint on its parameters a `DatabaseConfig` violates
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+	/// The FFT size isn't a power of two
+	FftSizeNotPowerOfTwo(usize),
+	/// Slices are shorter than `MIN_SLICE_SIZE`
+	SliceTooShort(std::time::Duration),
+	/// A slice holds more samples than the FFT
+	SliceExceedsFft {
+		/// Samples in a slice
+		slice_samples: usize,
+		/// Points of the FFT
+		fft_size: usize,
+	},
+	/// Slices start less than 1ms apart
+	HopTooShort,
+	/// The buckets are 0Hz wide, or there are none
+	NoBuckets,
+	/// More peaks are kept per slice than there are buckets to pick them from
+	MorePeaksThanBuckets {
+		/// Peaks kept per slice
+		freq_per_slice: usize,
+		/// Buckets, each giving at most one peak
+		bucket_count: usize,
+	},
+	/// The buckets reach above the Nyquist frequency, where there's nothing to pick
+	AboveNyquist {
+		/// Top of the highest bucket in Hz
+		top_freq: usize,
+		/// Rate songs are resampled to
+		sample_rate: usize,
+	},
+	/// The target zone, rounded to whole hops, spans less than two of them
+	TargetZoneTooNarrow {
+		/// Time the rounded target zone spans
+		width: std::time::Duration,
+		/// Time between consecutive slices
+		hop_size: std::time::Duration,
+	},
+	/// The target zone starts at the anchor's own slice, pairing it with itself
+	TargetZoneOffsetZero,
+	/// The target zone starts past its own last slice, so no signature is ever made
+	TargetZoneOffsetPastZone {
+		/// Slices between an anchor and the start of its target zone
+		offset: TimeStamp,
+		/// Slices the target zone spans, the anchor's included
+		width: TimeStamp,
+	},
+	/// The target zone is lower than a bucket, so rarely holds a second peak
+	TargetZoneTooLow {
+		/// Hz the target zone spans
+		height: Freq,
+		/// Width of a bucket in Hz
+		bucket_size: Freq,
+	},
+}
+impl std::fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::FftSizeNotPowerOfTwo(fft_size) => {
+				write!(f, "fft_size {fft_size} isn't a power of two")
+			}
+			Self::SliceTooShort(slice_size) => write!(
+				f,
+				"slice_size_ms {} is below the minimum of {}",
+				slice_size.as_millis(),
+				MIN_SLICE_SIZE.as_millis()
+			),
+			Self::SliceExceedsFft {
+				slice_samples,
+				fft_size,
+			} => write!(
+				f,
+				"a slice of {slice_samples} samples doesn't fit in fft_size {fft_size}, raise \
+				 fft_size or lower slice_size_ms"
+			),
+			Self::HopTooShort => write!(f, "hop_size_ms must be at least 1"),
+			Self::NoBuckets => write!(f, "bucket_size and bucket_count must be at least 1"),
+			Self::MorePeaksThanBuckets {
+				freq_per_slice,
+				bucket_count,
+			} => write!(
+				f,
+				"freq_per_slice {freq_per_slice} is more than bucket_count {bucket_count}, \
+				 which caps the peaks per slice"
+			),
+			Self::AboveNyquist {
+				top_freq,
+				sample_rate,
+			} => write!(
+				f,
+				"min_freq + bucket_size * bucket_count reaches {top_freq}Hz, above the \
+				 {}Hz Nyquist frequency of sample_rate {sample_rate}",
+				sample_rate / 2
+			),
+			Self::TargetZoneTooNarrow { width, hop_size } => write!(
+				f,
+				"target_zone_width_ms rounds to {}ms, less than two {}ms hops",
+				width.as_millis(),
+				hop_size.as_millis()
+			),
+			Self::TargetZoneOffsetZero => write!(
+				f,
+				"target_zone_offset must be at least 1, 0 pairs every anchor with itself"
+			),
+			Self::TargetZoneOffsetPastZone { offset, width } => write!(
+				f,
+				"target_zone_offset {offset} is past the {width} slices of the target zone, \
+				 leaving nothing to pair"
+			),
+			Self::TargetZoneTooLow {
+				height,
+				bucket_size,
+			} => write!(
+				f,
+				"target_zone_height {height} is less than bucket_size {bucket_size}"
+			),
 		}
 	}
 }
+impl std::error::Error for ConfigError {}
+
+/// Writes `contents` to a temporary file next to `path` and renames it into place, so
+/// an interrupted write never leaves a truncated file at `path`
+fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+	let mut tmp_path = path.as_os_str().to_owned();
+	tmp_path.push(".tmp");
+	std::fs::write(&tmp_path, contents)
+		.and_then(|_| std::fs::rename(&tmp_path, path))
+		.inspect_err(|_| {
+			let _ = std::fs::remove_file(&tmp_path);
+		})
+}
 
+/// A song file to be fingerprinted
 #[derive(Debug, Hash)]
 pub struct SongEntry {
+	/// Path relative to the songs directory, the song's name in the database
 	pub name: OsString,
+	/// Where the file is
 	pub path: PathBuf,
 }
 impl SongEntry {
-	fn cached_file_name(&self) -> OsString {
+	/// Name of the song's cache file, unique to its name and path
+	pub fn cached_file_name(&self, extension: &str) -> OsString {
+		let hash = fnv1a(&[
+			self.name.as_encoded_bytes(),
+			// Keeps the boundary between name and path from shifting
+			&[0],
+			self.path.as_os_str().as_encoded_bytes(),
+		]);
+		// Songs in subdirectories have separators in their name, the hash already tells
+		// `a/track.wav` and `a_track.wav` apart
+		let name = self.name.display().to_string().replace(['/', '\\'], "_");
+		format!("{name}-{hash:016x}.{extension}").into()
+	}
+	/// `cached_file_name` from before it was stable across Rust releases
+	pub fn legacy_cached_file_name(&self, extension: &str) -> OsString {
 		let mut hasher = DefaultHasher::new();
 		self.hash(&mut hasher);
-		format!("{}-{:016x}.json", self.name.display(), hasher.finish()).into()
+		format!(
+			"{}-{:016x}.{extension}",
+			self.name.display(),
+			hasher.finish()
+		)
+		.into()
+	}
+}
+
+/// Which files under the songs directory are treated as songs
+#[derive(Debug, Clone)]
+pub struct SongFilter {
+	/// Directories to descend below the songs directory, 0 only looks at its top level
+	pub max_depth: usize,
+	/// Extensions a song may have, compared case insensitively
+	pub extensions: Vec<String>,
+	/// Globs of paths relative to the songs directory to skip
+	pub exclude: Vec<String>,
+}
+impl SongFilter {
+	/// Paths relative to `songs_dir` of every song in it, sorted. Hidden files and
+	/// directories are skipped, and each directory is only visited once however many
+	/// symlinks lead to it, so symlink loops end
+	pub fn find_songs(&self, songs_dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+		let mut visited = HashSet::from([songs_dir.canonicalize()?]);
+		let mut songs = Vec::new();
+		self.scan(songs_dir, Path::new(""), 0, &mut visited, &mut songs)?;
+		songs.sort();
+		Ok(songs)
+	}
+	fn scan(
+		&self,
+		songs_dir: &Path,
+		relative_dir: &Path,
+		depth: usize,
+		visited: &mut HashSet<PathBuf>,
+		songs: &mut Vec<PathBuf>,
+	) -> std::io::Result<()> {
+		let mut entries = Vec::new();
+		for entry in std::fs::read_dir(songs_dir.join(relative_dir))? {
+			match entry {
+				Ok(x) => entries.push(x),
+				Err(err) => error!("{err}"),
+			}
+		}
+		// Which of several symlinks to a directory gets followed should not depend on the
+		// file system's listing order
+		entries.sort_by_key(|entry| entry.file_name());
+		for entry in entries {
+			let path = entry.path();
+			let relative_path = relative_dir.join(entry.file_name());
+			if entry.file_name().as_encoded_bytes().starts_with(b".") {
+				debug!("Skipping hidden {path:?}");
+			} else if path.is_dir() {
+				if depth >= self.max_depth {
+					debug!("Skipping {path:?}, deeper than the max depth");
+					continue;
+				}
+				let canonical = match path.canonicalize() {
+					Ok(x) => x,
+					Err(err) => {
+						error!("Failed to resolve {path:?}, {err}");
+						continue;
+					}
+				};
+				if !visited.insert(canonical) {
+					warn!("Skipping {path:?}, already visited through a symlink");
+				} else if let Err(err) =
+					self.scan(songs_dir, &relative_path, depth + 1, visited, songs)
+				{
+					error!("Failed to read directory {path:?}, {err}");
+				}
+			} else if path.is_file() && self.accepts(&relative_path) {
+				songs.push(relative_path);
+			} else {
+				debug!("Skipping {path:?}");
+			}
+		}
+		Ok(())
+	}
+	fn accepts(&self, relative_path: &Path) -> bool {
+		let extension_allowed = relative_path
+			.extension()
+			.and_then(|ext| ext.to_str())
+			.is_some_and(|ext| {
+				self.extensions
+					.iter()
+					.any(|allowed| allowed.eq_ignore_ascii_case(ext))
+			});
+		let relative_path = relative_path.to_string_lossy();
+		extension_allowed
+			&& !self
+				.exclude
+				.iter()
+				.any(|pattern| glob_matches(pattern, &relative_path))
+	}
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any run of characters
+/// including path separators, and `?` for any single character
+fn glob_matches(pattern: &str, text: &str) -> bool {
+	let pattern: Vec<char> = pattern.chars().collect();
+	let text: Vec<char> = text.chars().collect();
+	let (mut p, mut t) = (0, 0);
+	// Where the last `*` was, and how far into `text` it currently reaches
+	let mut star = None;
+	while t < text.len() {
+		if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+			p += 1;
+			t += 1;
+		} else if p < pattern.len() && pattern[p] == '*' {
+			star = Some((p, t));
+			p += 1;
+		} else if let Some((star_p, star_t)) = star {
+			// Let the `*` swallow one more character and retry from there
+			star = Some((star_p, star_t + 1));
+			p = star_p + 1;
+			t = star_t + 1;
+		} else {
+			return false;
+		}
 	}
+	pattern[p..].iter().all(|&c| c == '*')
 }
 
+/// Signatures of a song with the slice each is at, sorted
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct SongData(Vec<(Signature, TimeStamp)>);
+pub struct SongData(Vec<(PackedSignature, TimeStamp)>);
+impl SongData {
+	/// Fixed width format, still read but no longer written
+	const MAGIC: &'static [u8; 4] = b"SZSD";
+	const VARINT_MAGIC: &'static [u8; 4] = b"SZSV";
+	const ENTRY_SIZE: usize = size_of::<PackedSignature>() + size_of::<TimeStamp>();
+	/// Encodes to the binary cache format: `VARINT_MAGIC`, the entry count, then each
+	/// signature and timestamp as the `delta` from the previous entry's, all varints.
+	/// Entries are sorted, so the deltas are mostly a byte or two
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(Self::VARINT_MAGIC.len() + 8 + self.0.len() * 4);
+		bytes.extend_from_slice(Self::VARINT_MAGIC);
+		write_varint(&mut bytes, self.0.len() as u64);
+		let (mut previous_signature, mut previous_timestamp) = (0, 0);
+		for &(signature, timestamp) in self.0.iter() {
+			write_varint(&mut bytes, delta(signature, previous_signature));
+			write_varint(
+				&mut bytes,
+				delta(timestamp as u64, previous_timestamp as u64),
+			);
+			(previous_signature, previous_timestamp) = (signature, timestamp);
+		}
+		bytes
+	}
+	/// Decodes either binary cache format
+	pub fn from_bytes(bytes: &[u8]) -> Result<SongData, &'static str> {
+		if let Some(mut bytes) = bytes.strip_prefix(Self::VARINT_MAGIC.as_slice()) {
+			let count = read_varint(&mut bytes)?;
+			// Every entry takes at least two bytes
+			if count > bytes.len() as u64 / 2 {
+				return Err("entry count doesn't match the file length");
+			}
+			let (mut signature, mut timestamp) = (0, 0);
+			let entries = (0..count)
+				.map(|_| {
+					signature = undelta(read_varint(&mut bytes)?, signature);
+					timestamp = undelta(read_varint(&mut bytes)?, timestamp as u64) as TimeStamp;
+					Ok((signature, timestamp))
+				})
+				.collect::<Result<_, &'static str>>()?;
+			if !bytes.is_empty() {
+				return Err("trailing bytes after the entries");
+			}
+			return Ok(SongData(entries));
+		}
+		let bytes = bytes
+			.strip_prefix(Self::MAGIC.as_slice())
+			.ok_or("missing magic bytes")?;
+		let (count, entries) = bytes.split_first_chunk::<8>().ok_or("truncated header")?;
+		if (u64::from_le_bytes(*count) as usize).checked_mul(Self::ENTRY_SIZE)
+			!= Some(entries.len())
+		{
+			return Err("entry count doesn't match the file length");
+		}
+		Ok(SongData(
+			entries
+				.chunks_exact(Self::ENTRY_SIZE)
+				.map(|entry| {
+					let (signature, timestamp) = entry.split_at(size_of::<PackedSignature>());
+					(
+						PackedSignature::from_le_bytes(signature.try_into().unwrap()),
+						TimeStamp::from_le_bytes(timestamp.try_into().unwrap()),
+					)
+				})
+				.collect(),
+		))
+	}
+	/// Loads the cached data of `entry`, falling back to the JSON cache files written
+	/// before the binary format. Files named by the legacy hash are renamed once found
+	fn read_cache(cache_dir: &Path, entry: &SongEntry) -> Result<SongData, CacheReadError> {
+		let mut result = Err(CacheReadError::Io(std::io::ErrorKind::NotFound.into()));
+		for extension in [CACHE_EXTENSION, LEGACY_CACHE_EXTENSION] {
+			let path = cache_dir.join(entry.cached_file_name(extension));
+			let legacy_path = cache_dir.join(entry.legacy_cached_file_name(extension));
+			for candidate in [&path, &legacy_path] {
+				result = SongData::from_cache_file(candidate);
+				match &result {
+					Err(CacheReadError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+						continue;
+					}
+					Ok(_) if candidate == &legacy_path => {
+						if let Err(err) = std::fs::rename(&legacy_path, &path) {
+							warn!("Failed to rename legacy cache file {legacy_path:?}, {err}");
+						}
+					}
+					_ => (),
+				}
+				return result;
+			}
+		}
+		result
+	}
+	/// Decodes a single cache file, as JSON if it has the legacy extension and as the
+	/// binary format, compressed or not, otherwise
+	pub fn from_cache_file(path: &Path) -> Result<SongData, CacheReadError> {
+		let bytes = std::fs::read(path)?;
+		if path.extension() == Some(LEGACY_CACHE_EXTENSION.as_ref()) {
+			return serde_json::from_slice(&bytes).map_err(CacheReadError::Json);
+		}
+		let bytes = if bytes.starts_with(&ZSTD_MAGIC) {
+			zstd::decode_all(bytes.as_slice()).map_err(CacheReadError::Compression)?
+		} else {
+			bytes
+		};
+		SongData::from_bytes(&bytes).map_err(CacheReadError::Corrupt)
+	}
+}
+
+/// Why a cache file couldn't be read
+#[derive(Debug)]
+pub enum CacheReadError {
+	/// The file couldn't be read
+	Io(std::io::Error),
+	/// A binary cache file that doesn't decode
+	Corrupt(&'static str),
+	/// A compressed cache file that doesn't decompress
+	Compression(std::io::Error),
+	/// A legacy JSON cache file that doesn't decode
+	Json(serde_json::Error),
+}
+impl std::fmt::Display for CacheReadError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Io(err) => write!(f, "io error, {err}"),
+			Self::Corrupt(reason) => write!(f, "corrupt file, {reason}"),
+			Self::Compression(err) => write!(f, "corrupt compressed file, {err}"),
+			Self::Json(err) => write!(f, "corrupt legacy JSON file, {err}"),
+		}
+	}
+}
+impl std::error::Error for CacheReadError {}
+impl From<std::io::Error> for CacheReadError {
+	fn from(err: std::io::Error) -> Self {
+		Self::Io(err)
+	}
+}
+
+/// A song added to the builder that didn't make it into the built database
+#[derive(Debug)]
+pub struct BuildError {
+	/// The song's file, its name in the builder joined to the songs directory
+	pub path: PathBuf,
+	/// Why it didn't decode
+	pub reason: DecodeError,
+}
+impl std::fmt::Display for BuildError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{:?}, {}", self.path, self.reason)
+	}
+}
+impl std::error::Error for BuildError {}
+
+/// Why `DatabaseBuilder::add_song` rejected a song
+#[derive(Debug)]
+pub enum AddSongError {
+	/// The file doesn't exist
+	NotFound,
+	/// The path is a directory or some other non regular file
+	NotAFile,
+	/// None of the decoders handle files with this extension
+	UnsupportedExtension,
+	/// The file couldn't be inspected
+	Io(std::io::Error),
+}
+impl std::fmt::Display for AddSongError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::NotFound => write!(f, "no such file"),
+			Self::NotAFile => write!(f, "not a file"),
+			Self::UnsupportedExtension => write!(f, "unsupported extension"),
+			Self::Io(err) => write!(f, "io error, {err}"),
+		}
+	}
+}
+impl std::error::Error for AddSongError {}
 
+/// A song added to a `DatabaseBuilder`
 #[derive(Debug)]
 pub enum BuilderEntry {
+	/// Signatures read from the song's cache file
 	CachedData(OsString, SongData),
+	/// A song still to be fingerprinted when building
 	Entry(SongEntry),
 }
 
-#[derive(Debug, Clone, Copy)]
+/// How `DatabaseBuilder::add_song` handled a song
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CacheStatus {
+	/// The song's signatures were cached
 	Hit,
+	/// The song will be fingerprinted when building
 	Miss,
+	/// The file was already added, possibly under another name, so it was skipped
+	Duplicate,
 }
 
+/// Collects the songs of a database, reading their cached signatures where possible
 #[derive(Debug)]
 pub struct DatabaseBuilder {
 	data: Vec<BuilderEntry>,
 	config: DatabaseConfig,
 	songs_dir: PathBuf,
 	cache_dir: Option<PathBuf>,
+	/// Whether new cache files are zstd compressed, reading detects it either way
+	compress_cache: bool,
+	/// Metadata to attach to songs by name, see `set_song_meta`
+	meta: HashMap<OsString, SongMeta>,
+	/// Name each added file was first added under, by canonical path
+	added: HashMap<PathBuf, OsString>,
+	allow_duplicates: bool,
+	/// Runs the build, and the built database's matching, instead of rayon's global pool
+	thread_pool: Option<Arc<rayon::ThreadPool>>,
 }
 impl DatabaseBuilder {
+	/// Songs are named by their path relative to `songs_dir`, and their signatures are
+	/// cached below `cache_dir` in a directory of their config's
 	pub fn new<T: Into<PathBuf> + std::fmt::Debug + Copy>(
 		config: DatabaseConfig,
 		songs_dir: T,
@@ -117,9 +1149,14 @@ impl DatabaseBuilder {
 					config,
 					songs_dir: songs_dir.into(),
 					cache_dir: None,
+					compress_cache: false,
+					meta: HashMap::new(),
+					added: HashMap::new(),
+					allow_duplicates: false,
+					thread_pool: None,
 				}
 			}
-			Some(x) => PathBuf::from(x.into()),
+			Some(x) => x.into(),
 		};
 		let cache_dir = {
 			let db_cache_dir_name = &config.cached_dir_name();
@@ -131,6 +1168,23 @@ impl DatabaseBuilder {
 							.map(|i| i.path())
 							.filter(|i| i.is_dir())
 					})
+					.or_else(|| {
+						// Caches named by the legacy hash are moved over the first time
+						let legacy_path = cache_dir_path.join(config.legacy_cached_dir_name());
+						let path = cache_dir_path.join(db_cache_dir_name);
+						if !legacy_path.is_dir() {
+							return None;
+						}
+						std::fs::rename(&legacy_path, &path)
+							.inspect_err(|err| {
+								error!("Failed to migrate legacy cache {legacy_path:?}, {err}")
+							})
+							.ok()
+							.map(|_| {
+								info!("Migrated legacy cache {legacy_path:?} to {path:?}");
+								path
+							})
+					})
 					.or_else(|| {
 						cache_dir_path.push(db_cache_dir_name);
 						std::fs::create_dir(&cache_dir_path)
@@ -160,160 +1214,2509 @@ impl DatabaseBuilder {
 			config,
 			songs_dir: songs_dir.into(),
 			cache_dir,
+			compress_cache: false,
+			meta: HashMap::new(),
+			added: HashMap::new(),
+			allow_duplicates: false,
+			thread_pool: None,
 		}
 	}
-	pub fn add_song<T: Into<OsString> + Copy + std::fmt::Debug>(
-		&mut self,
-		file_path: T,
-	) -> Option<CacheStatus> {
-		let mut path = self.songs_dir.clone();
-		path.push(file_path.into());
-		if !path.clone().exists() {
-			return None;
+	/// Whether cache files written from now on are zstd compressed
+	pub fn with_cache_compression(mut self, compress_cache: bool) -> Self {
+		self.compress_cache = compress_cache;
+		self
+	}
+	/// Whether a file added more than once, say through a symlink, gets a song for each
+	/// time. By default only the first is kept, as copies split the matches between them
+	pub fn with_duplicates_allowed(mut self, allow_duplicates: bool) -> Self {
+		self.allow_duplicates = allow_duplicates;
+		self
+	}
+	/// Fingerprints the songs in `thread_pool` rather than rayon's global pool, and has
+	/// the built database match in it too, see `Database::set_thread_pool`
+	pub fn with_thread_pool(mut self, thread_pool: Option<Arc<rayon::ThreadPool>>) -> Self {
+		self.thread_pool = thread_pool;
+		self
+	}
+	/// Attaches `meta` to the song at `file_path`, relative to the songs directory,
+	/// once it's built
+	pub fn set_song_meta(&mut self, file_path: impl AsRef<Path>, meta: SongMeta) {
+		self.meta
+			.insert(file_path.as_ref().as_os_str().to_owned(), meta);
+	}
+	/// Adds the song at `file_path`, relative to the songs directory
+	pub fn add_song(&mut self, file_path: impl AsRef<Path>) -> Result<CacheStatus, AddSongError> {
+		let file_path = file_path.as_ref();
+		let path = self.songs_dir.join(file_path);
+		match std::fs::metadata(&path) {
+			Ok(metadata) if !metadata.is_file() => return Err(AddSongError::NotAFile),
+			Ok(_) => {}
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+				return Err(AddSongError::NotFound)
+			}
+			Err(err) => return Err(AddSongError::Io(err)),
+		}
+		let extension_supported = file_path
+			.extension()
+			.and_then(|ext| ext.to_str())
+			.is_some_and(|ext| {
+				encoder::Song::SUPPORTED_EXTENSIONS
+					.iter()
+					.any(|supported| supported.eq_ignore_ascii_case(ext))
+			});
+		if !extension_supported {
+			return Err(AddSongError::UnsupportedExtension);
+		}
+		if !self.allow_duplicates {
+			let canonical_path = path.canonicalize().map_err(AddSongError::Io)?;
+			if let Some(name) = self.added.get(&canonical_path) {
+				warn!("{file_path:?} is the same file as {name:?}, skipping it");
+				return Ok(CacheStatus::Duplicate);
+			}
+			self.added
+				.insert(canonical_path, file_path.as_os_str().to_owned());
 		}
 		let entry = SongEntry {
-			name: file_path.clone().into(),
+			name: file_path.into(),
 			path,
 		};
-		if let Some(mut cached_file) = self.cache_dir.clone() {
-			cached_file.push(entry.cached_file_name());
-			match std::fs::read(cached_file) {
-				Ok(cached_data) => match serde_json::from_slice(&cached_data) {
-					Ok(x) => {
-						self.data
-							.push(BuilderEntry::CachedData(file_path.into(), x));
-						return Some(CacheStatus::Hit);
-					}
-					Err(err) => {
-						warn!("Failed to deserialize cache file for {file_path:?}, {err:?}")
-					}
-				},
-				Err(err) => warn!("Failed to read cache file for {file_path:?}, {err:?}"),
+		if let Some(cache_dir) = &self.cache_dir {
+			match SongData::read_cache(cache_dir, &entry) {
+				Ok(x) => {
+					self.data.push(BuilderEntry::CachedData(entry.name, x));
+					return Ok(CacheStatus::Hit);
+				}
+				Err(err @ CacheReadError::Io(_)) => {
+					warn!("Failed to read cache file for {file_path:?}, {err}")
+				}
+				Err(err) => warn!("Failed to deserialize cache file for {file_path:?}, {err}"),
 			}
 		}
 		self.data.push(BuilderEntry::Entry(entry));
-		Some(CacheStatus::Miss)
+		Ok(CacheStatus::Miss)
 	}
-	pub fn build(self, config: DatabaseConfig) -> Database {
+	/// Songs that fail to decode are left out of the database rather than failing the
+	/// whole build, and returned alongside it
+	pub fn build(self, config: DatabaseConfig) -> (Database, Vec<BuildError>) {
 		let mut db = Database::new(config);
-		let song_signatures = |byte_array| -> SongData {
-			let song = encoder::Song::from_wav(byte_array);
-			let signatures = config.signatures(&song);
-			// TODO: set an estimated initial capacity
-			let mut res = Vec::new();
-			signatures.enumerate().for_each(|(timestamp, signature)| {
-				signature
-					.iter()
-					.copied()
-					.for_each(|i| res.push((i, timestamp as TimeStamp)))
-			});
-			SongData(res)
+		db.set_thread_pool(self.thread_pool.clone());
+		// Without a config file the directory can't be told apart from any other, so
+		// nothing gets cached if writing it fails
+		let cache_dir = self.cache_dir.clone().filter(|cache_dir| {
+			let path = cache_dir.join(CONFIG_FILE_NAME);
+			serde_json::to_vec(&self.config)
+				.map_err(std::io::Error::from)
+				.and_then(|json| write_atomically(&path, &json))
+				.inspect_err(|err| error!("Failed to write {path:?}, not caching songs, {err}"))
+				.is_ok()
+		});
+		let compress_cache = self.compress_cache;
+		let data = self.data;
+		let span = tracing::info_span!(
+			"build",
+			songs = data.len(),
+			signatures = tracing::field::Empty,
+		);
+		let _entered = span.enter();
+		let results: Vec<Result<(OsString, SongData), BuildError>> = db.install(|| {
+			data.into_par_iter()
+				.map(|entry| match entry {
+					BuilderEntry::CachedData(path, data) => Ok((path, data)),
+					BuilderEntry::Entry(entry) => {
+						// Workers don't inherit the span, so it's handed down explicitly
+						let _entered =
+							tracing::info_span!(parent: &span, "song", song = ?entry.name)
+								.entered();
+						let song = config.load_song(&entry.path).map_err(|err| {
+							if let DecodeError::UnsupportedFormat = err {
+								warn!("Skipping {:?}, {err}", entry.path);
+							} else {
+								error!("Failed to decode {:?}, skipping it, {err}", entry.path);
+							}
+							BuildError {
+								path: entry.path.clone(),
+								reason: err,
+							}
+						})?;
+						let data = config.song_data(&entry.name, &song);
+						if let Some(cache_dir) = &cache_dir {
+							let path = cache_dir.join(entry.cached_file_name(CACHE_EXTENSION));
+							let bytes = if compress_cache {
+								zstd::encode_all(
+									data.to_bytes().as_slice(),
+									zstd::DEFAULT_COMPRESSION_LEVEL,
+								)
+							} else {
+								Ok(data.to_bytes())
+							};
+							match bytes.and_then(|bytes| write_atomically(&path, &bytes)) {
+								Ok(()) => info!("Wrote data for {path:?} to Cache"),
+								Err(err) => error!("Failed to write cache file {path:?}, {err}"),
+							}
+						}
+						Ok((entry.name, data))
+					}
+				})
+				.collect()
+		});
+		// Songs keep the ids they had in earlier builds and new ones are appended, ids
+		// of songs that are gone are never handed out again
+		let song_ids_path = self
+			.cache_dir
+			.as_ref()
+			.and_then(|cache_dir| cache_dir.parent())
+			.map(|root| root.join(SONG_IDS_FILE_NAME));
+		let mut song_ids: BTreeMap<String, SongId> = match &song_ids_path {
+			Some(path) => match std::fs::read(path) {
+				Ok(json) => serde_json::from_slice(&json).unwrap_or_else(|err| {
+					error!("Failed to deserialize {path:?}, songs may get new ids, {err}");
+					BTreeMap::new()
+				}),
+				Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+				Err(err) => {
+					error!("Failed to read {path:?}, songs may get new ids, {err}");
+					BTreeMap::new()
+				}
+			},
+			None => BTreeMap::new(),
 		};
-		if let Some(mut path) = self.cache_dir.clone() {
-			path.push("config.json");
-			std::fs::write(path, serde_json::to_string(&self.config).unwrap()).unwrap();
-		}
-		let data: Vec<(OsString, SongData)> = self
-			.data
-			.par_iter()
-			.map(|entry| match entry {
-				// TODO: cloning big chunks of data
-				BuilderEntry::CachedData(path, data) => (path.clone(), data.clone()),
-				BuilderEntry::Entry(entry) => {
-					let data = song_signatures(std::fs::read(&entry.path).unwrap());
-					if let Some(mut path) = self.cache_dir.clone() {
-						path.push(&entry.cached_file_name());
-						std::fs::write(&path, serde_json::to_string(&data).unwrap()).unwrap();
-						info!("Wrote data for {path:?} to Cache");
+		let mut song_meta = self.meta;
+		let mut next_id = song_ids.values().max().map_or(0, |id| id + 1);
+		let mut new_ids = false;
+		let mut errors = Vec::new();
+		let mut signature_count = 0;
+		for result in results {
+			match result {
+				Ok((path, data)) => {
+					signature_count += data.0.len();
+					let mut new_id = || {
+						next_id += 1;
+						next_id - 1
+					};
+					let id = match path.to_str() {
+						Some(name) => *song_ids.entry(name.to_owned()).or_insert_with(|| {
+							new_ids = true;
+							new_id()
+						}),
+						// Can't be written to the JSON manifest
+						None => new_id(),
+					};
+					if let Some(meta) = song_meta.remove(&path) {
+						db.song_meta.insert(id, meta);
 					}
-					(entry.name.clone(), data)
+					db.insert_song(id, path, data);
 				}
-			})
-			.collect();
-		for (path, SongData(data)) in data {
-			data.iter().copied().for_each(|(signature, timestamp)| {
-				let vec = db.data.entry(signature).or_insert(Vec::new());
-				vec.push((db.song_paths.len() as SongId, timestamp));
-			});
-			db.song_paths.push(path);
+				Err(err) => errors.push(err),
+			}
 		}
-		db
+		if let Some(path) = song_ids_path.filter(|_| new_ids) {
+			let json = serde_json::to_vec_pretty(&song_ids).expect("ids always serialize");
+			if let Err(err) = write_atomically(&path, &json) {
+				error!("Failed to write {path:?}, new songs may get other ids next time, {err}");
+			}
+		}
+		info!(
+			"Database holds {signature_count} signatures under {} distinct keys for {} songs",
+			db.data.len(),
+			db.song_paths.iter().flatten().count()
+		);
+		span.record("signatures", signature_count);
+		(db, errors)
 	}
 }
 
-#[allow(unused)]
-#[derive(Clone, Copy, Debug)]
+/// How well a sample lines up with a song, see `Database::match_sample`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 pub struct Match {
+	/// The song matched
 	pub id: SongId,
+	/// Votes for the best offset over the average votes for an offset, higher is more
+	/// certain
 	pub score: f32,
-	pub offset: f32,
+	/// Where in the song the sample begins, samples starting before it do at zero
+	pub offset: std::time::Duration,
+	/// Start and end of the part of the sample lining up with the song, by the first
+	/// and last slice that did, `None` when none did
+	pub matched_span: Option<(std::time::Duration, std::time::Duration)>,
+	/// Signatures lining up with the song at `offset`
 	pub freq: usize,
+	/// Slices of the sample with a signature lining up with the song at `offset`
+	pub slice_freq: usize,
+	/// Offsets the song got votes for
 	pub n: usize,
+	/// Whether matching stopped short of the end of the sample, as a song was far enough
+	/// ahead, see `Database::set_early_exit`
+	pub early_exit: bool,
 }
 
-#[derive(Debug)]
-pub struct Database {
-	data: FxHashMap<Signature, Vec<(SongId, TimeStamp)>>,
-	config: DatabaseConfig,
-	song_paths: Vec<OsString>,
+impl Match {
+	/// The match of a sample that `lead` was trimmed off the start of before it was
+	/// matched, placed by the untrimmed start
+	fn trimmed_by(mut self, lead: std::time::Duration) -> Self {
+		self.offset = self.offset.saturating_sub(lead);
+		self.matched_span = self
+			.matched_span
+			.map(|(start, end)| (start + lead, end + lead));
+		self
+	}
 }
-impl Database {
-	pub fn song_name(&self, id: SongId) -> String {
-		self.song_paths[id as usize].clone().into_string().unwrap()
+
+/// A match along with the votes behind it, see `Database::match_sample_detailed`
+#[derive(Clone, Debug)]
+pub struct DetailedMatch {
+	/// The match, as `Database::match_sample` has it
+	pub found: Match,
+	/// Signatures voting for every offset the song got votes for, by the offset in
+	/// slices into the song that the sample as matched starts at
+	pub histogram: FxHashMap<Offset, usize>,
+	/// How much was trimmed off the start of the sample before matching it, which the
+	/// histogram's offsets don't account for
+	pub lead: std::time::Duration,
+}
+
+/// Where one recording lines up with another, see `align`
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Alignment {
+	/// Seconds into the first recording that the second starts at, negative if it
+	/// starts before the first does
+	pub offset_secs: f64,
+	/// How certain the offset is, as `Match::score`
+	pub score: f32,
+	/// Signatures of the second recording lining up with the first at the offset, give
+	/// or take the offset tolerance, each counted once
+	pub aligned: usize,
+	/// Signatures of the second recording
+	pub signatures: usize,
+}
+
+/// Where `b` lines up with `a`, both at `config`'s sample rate, if they share a
+/// signature. `a` is indexed as the only song of a database kept in memory, which `b`
+/// is matched against. Slices only place `b` to within a hop, so when they overlap it's
+/// also matched with fractions of a hop cut off its start, keeping the cut lining up the
+/// most signatures at exactly one offset
+pub fn align(a: &encoder::Song, b: &encoder::Song, config: DatabaseConfig) -> Option<Alignment> {
+	let _entered = tracing::info_span!("align").entered();
+	let mut db = Database::new(config);
+	let id = db.add_song("a".into(), config.song_data("a".as_ref(), a));
+	db.finalize();
+	let hop_size = config.constellation.hop_size;
+	let steps = match hop_size < config.constellation.slice_size {
+		true => ALIGNMENT_STEPS,
+		false => 1,
+	};
+	(0..steps)
+		.filter_map(|step| {
+			let cut = ((hop_size * step / steps).as_secs_f64() * b.sample_rate as f64) as usize;
+			let cut = cut.min(b.samples.len());
+			let b = encoder::Song {
+				sample_rate: b.sample_rate,
+				samples: b.samples[cut..].to_vec(),
+			};
+			let signatures: Vec<_> = config.signatures(&b).collect();
+			let votes = db.count_votes(signatures.iter().copied());
+			let offset_freq_table = votes.get(&id)?;
+			let (found, offset) = db.score_song(id, offset_freq_table);
+			let alignment = Alignment {
+				offset_secs: offset as f64 * hop_size.as_secs_f64()
+					- cut as f64 / b.sample_rate as f64,
+				score: found.score,
+				aligned: db.aligned_signatures(&signatures, id, offset),
+				signatures: signatures.len(),
+			};
+			Some((offset_freq_table[&offset].hits, alignment))
+		})
+		// Ties go to the smallest cut
+		.min_by_key(|&(hits, _)| std::cmp::Reverse(hits))
+		.map(|(_, alignment)| alignment)
+}
+
+/// Why a sample lines up with a song where it does, see `Database::explain_match`.
+/// Timestamps count slices of the sample as it was matched, from `lead` into it
+#[derive(Clone, Debug, Serialize)]
+pub struct MatchExplanation {
+	/// The song explained
+	pub id: SongId,
+	/// How the song matches the sample, as `Database::match_sample` has it. `None` if
+	/// they share no signature
+	pub found: Option<Match>,
+	/// Offset in slices `found` is at, where the song is when the sample starts
+	pub offset: Offset,
+	/// Signatures of the sample the song holds at `offset`, give or take the offset
+	/// tolerance, in the sample's order
+	pub aligned: Vec<AlignedSignature>,
+	/// Votes for every offset the song got, by offset
+	pub histogram: Vec<OffsetHits>,
+	/// Signatures fingerprinted from the sample
+	pub sample_signatures: usize,
+	/// Slices of the sample, up to the last with a signature
+	pub sample_slices: TimeStamp,
+	/// Those the song holds, at any offset and each counted once however many times it
+	/// does
+	pub shared: usize,
+	/// How much was trimmed off the start of the sample before matching it
+	pub lead: std::time::Duration,
+	/// Time between the starts of consecutive slices
+	pub hop_size: std::time::Duration,
+}
+impl MatchExplanation {
+	/// Slices of the sample with at least one aligned signature. Unlike
+	/// `Match::slice_freq`, slices voting for several offsets within the tolerance
+	/// count once
+	pub fn aligned_slices(&self) -> usize {
+		let mut slices: Vec<_> = self.aligned.iter().map(|a| a.sample_timestamp).collect();
+		slices.dedup();
+		slices.len()
 	}
-	pub fn new(config: DatabaseConfig) -> Self {
+}
+
+/// A signature of a sample that a song holds
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct AlignedSignature {
+	/// Slice of the sample it's from
+	pub sample_timestamp: TimeStamp,
+	/// Slice of the song holding it
+	pub song_timestamp: TimeStamp,
+	/// The signature
+	pub signature: Signature,
+}
+
+/// Votes a song got for one offset, see `MatchExplanation::histogram`
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct OffsetHits {
+	/// Slices into the song the sample would start at
+	pub offset: Offset,
+	/// Signatures voting for it
+	pub hits: usize,
+	/// Slices of the sample they're from
+	pub slices: usize,
+}
+
+/// Two indexed songs lining up with each other, see `Database::find_duplicates`
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Duplicate {
+	/// The song whose signatures were matched
+	pub id: SongId,
+	/// The song they line up with
+	pub other: SongId,
+	/// Signatures of `id` lining up with `other` at `offset`, give or take the offset
+	/// tolerance, over all its signatures
+	pub ratio: f32,
+	/// Slices into `other` that `id` starts at, negative if it starts before `other`
+	/// does
+	pub offset: Offset,
+	/// How `id` matches `other`, as `Database::match_sample` would have it
+	pub found: Match,
+}
+
+/// The votes a song got for one offset while matching, along with the first and last
+/// slice of the sample casting one. Besides every signature, the slices they come from
+/// are counted, each once however many of its signatures line up
+#[derive(Clone, Copy)]
+struct OffsetVotes {
+	/// Signatures voting
+	hits: usize,
+	/// Sum of their weights, in units of `1 / IDF_WEIGHT_SCALE`
+	weight: usize,
+	/// Slices voting
+	slices: usize,
+	/// Sum of the heaviest weight each slice voted with
+	slice_weight: usize,
+	first: TimeStamp,
+	last: TimeStamp,
+	/// Heaviest weight the last slice voted with
+	last_weight: usize,
+}
+impl Default for OffsetVotes {
+	fn default() -> Self {
 		Self {
-			config,
-			data: FxHashMap::default(),
-			song_paths: Vec::new(),
+			hits: 0,
+			weight: 0,
+			slices: 0,
+			slice_weight: 0,
+			first: TimeStamp::MAX,
+			last: TimeStamp::MIN,
+			last_weight: 0,
+		}
+	}
+}
+impl OffsetVotes {
+	/// Slices have to be added in order, and all of one slice's votes before the next
+	fn add(&mut self, sample_timestamp: TimeStamp, weight: usize) {
+		self.hits += 1;
+		self.weight += weight;
+		if self.slices > 0 && sample_timestamp == self.last {
+			if weight > self.last_weight {
+				self.slice_weight += weight - self.last_weight;
+				self.last_weight = weight;
+			}
+			return;
 		}
+		self.slices += 1;
+		self.slice_weight += weight;
+		self.first = self.first.min(sample_timestamp);
+		self.last = sample_timestamp;
+		self.last_weight = weight;
 	}
-	#[allow(unused)]
-	pub fn data(&self) -> &FxHashMap<Signature, Vec<(SongId, TimeStamp)>> {
-		&self.data
+	/// Slices voting in both are counted twice
+	fn merge(&mut self, other: &Self) {
+		if other.last >= self.last {
+			self.last_weight = other.last_weight;
+		}
+		self.hits += other.hits;
+		self.weight += other.weight;
+		self.slices += other.slices;
+		self.slice_weight += other.slice_weight;
+		self.first = self.first.min(other.first);
+		self.last = self.last.max(other.last);
 	}
-	pub fn match_sample(&self, sample: encoder::Song) -> Vec<Match> {
-		let mut song_offsets: FxHashMap<SongId, FxHashMap<Offset, usize>> = FxHashMap::default();
-		self.config
-			.signatures(&sample)
-			.enumerate()
-			.for_each(|(sample_timestamp, signatures)| {
-				signatures.iter().for_each(|i| {
-					if let Some(matches) = self.data.get(i) {
-						matches.iter().for_each(|(song_id, song_timestamp)| {
-							let offset = *song_timestamp as Offset - sample_timestamp as Offset;
-							let freq_table =
-								song_offsets.entry(*song_id).or_insert(FxHashMap::default());
-							let offset_freq = freq_table.entry(offset).or_insert(0);
-							*offset_freq += 1;
-						})
-					}
-				})
-			});
-		song_offsets
-			.iter()
-			.map(|(&song_id, offset_freq_table)| {
-				let mut sum = 0;
-				let mut max_freq = usize::MIN;
-				let mut best_offset = 0;
-				let mut n = 0;
-				for (offset, freq) in offset_freq_table.iter() {
-					if *freq > max_freq {
-						max_freq = *freq;
-						best_offset = *offset;
-					}
-					sum += *freq;
-					n += 1;
-				}
-				let average = sum as f32 / n as f32;
-				Match {
-					id: song_id,
-					offset: best_offset as f32 * self.config.slice_size.as_secs_f32(),
-					freq: max_freq,
-					score: max_freq as f32 / average,
-					n,
-				}
-			})
-			.collect()
+}
+
+/// Inverse document frequency of the signature with `postings`, `ln(1 + songs / songs
+/// holding it)` scaled so that a signature in every song weighs `IDF_WEIGHT_SCALE`.
+/// Songs are inserted whole, so the postings of each are next to each other
+fn idf_weight(postings: &[Posting], song_count: usize) -> usize {
+	let holding = 1 + postings
+		.windows(2)
+		.filter(|pair| pair[0].0 != pair[1].0)
+		.count();
+	let idf = (1. + song_count as f64 / holding as f64).ln() / 2f64.ln();
+	(idf * IDF_WEIGHT_SCALE as f64).round() as usize
+}
+
+/// When the best match for a sample is trusted rather than put down to chance, see
+/// `Database::identify`
+#[derive(Debug, Clone, Copy)]
+pub struct MatchPolicy {
+	/// Fewest signatures that must line up at the best match's offset
+	pub min_aligned: usize,
+	/// How many times the runner up's score the best match's must be at least, a lone
+	/// candidate only has to line up enough signatures
+	pub min_score_ratio: f32,
+}
+impl MatchPolicy {
+	/// The first of `matches`, sorted best first like `Database::best_matches` returns
+	/// them, if it passes the policy
+	pub fn pick(&self, matches: &[Match]) -> Option<Match> {
+		let best = *matches.first()?;
+		let confident = best.freq >= self.min_aligned
+			&& matches
+				.get(1)
+				.is_none_or(|second| best.score >= self.min_score_ratio * second.score);
+		confident.then_some(best)
+	}
+}
+
+/// How silence is trimmed off samples before they're matched, see
+/// `Database::set_silence_trim`
+#[derive(Debug, Clone, Copy)]
+pub struct SilenceTrim {
+	/// Loudness in dBFS that windows have to exceed to not count as silence
+	pub threshold_db: f32,
+	/// Shortest run of loud windows that ends the silence, so clicks don't
+	pub min_hold: std::time::Duration,
+}
+
+/// When matching a sample stops before its end, see `Database::set_early_exit`
+#[derive(Debug, Clone, Copy)]
+pub struct EarlyExit {
+	/// Slices of the sample between checks of whether a song is far enough ahead
+	pub check_every: usize,
+	/// How many times the signatures the runner up lines up the leading song has to line
+	/// up
+	pub min_lead: f32,
+	/// Fewest signatures the leading song has to line up
+	pub min_aligned: usize,
+}
+impl EarlyExit {
+	/// Whether the song lining up the most signatures in `matches` is far enough ahead
+	fn is_ahead(&self, matches: &[Match]) -> bool {
+		let (mut best, mut second) = (0, 0);
+		for found in matches {
+			if found.freq > best {
+				(best, second) = (found.freq, best);
+			} else if found.freq > second {
+				second = found.freq;
+			}
+		}
+		best >= self.min_aligned && best as f32 >= self.min_lead * second as f32
+	}
+}
+
+/// Why a saved database couldn't be loaded
+#[derive(Debug)]
+pub enum DatabaseLoadError {
+	/// The file couldn't be read
+	Io(std::io::Error),
+	/// The file doesn't decode
+	Corrupt(&'static str),
+	/// The stored config doesn't decode
+	Json(serde_json::Error),
+	/// Written with a different signature packing or cache layout
+	LayoutMismatch,
+	/// Built with this config rather than the requested one
+	ConfigMismatch(Box<DatabaseConfig>),
+}
+impl std::fmt::Display for DatabaseLoadError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Io(err) => write!(f, "io error, {err}"),
+			Self::Corrupt(reason) => write!(f, "corrupt file, {reason}"),
+			Self::Json(err) => write!(f, "corrupt config, {err}"),
+			Self::LayoutMismatch => write!(f, "written by an incompatible version"),
+			Self::ConfigMismatch(config) => write!(f, "built with a different config, {config:?}"),
+		}
+	}
+}
+impl std::error::Error for DatabaseLoadError {}
+impl From<std::io::Error> for DatabaseLoadError {
+	fn from(err: std::io::Error) -> Self {
+		Self::Io(err)
+	}
+}
+
+/// Summary of a database's index, see `Database::stats`
+#[derive(Debug, Serialize)]
+pub struct DatabaseStats {
+	/// Songs in the database, not counting removed ones
+	pub song_count: usize,
+	/// Postings across every signature
+	pub signature_count: usize,
+	/// Signatures held by at least one song
+	pub distinct_signatures: usize,
+	/// Distribution of the number of postings per distinct signature
+	pub postings_per_signature: PostingsHistogram,
+	/// Rough heap usage of the index and song names, in bytes
+	pub estimated_memory: usize,
+	/// The signatures with the most postings, most common first
+	pub most_common: Vec<CommonSignature>,
+	/// How densely every song is fingerprinted, by id
+	pub signature_rates: Vec<SignatureRate>,
+}
+
+/// The signatures a song has, over its length
+#[derive(Debug, Serialize)]
+pub struct SignatureRate {
+	/// The song
+	pub id: SongId,
+	/// Its name, see `Database::song_name`
+	pub name: String,
+	/// Postings it has in the index
+	pub signatures: usize,
+	/// Signatures per second, its length taken to run up to its last anchor's slice
+	pub per_second: f32,
+}
+
+/// A signature along with the number of postings it has
+#[derive(Debug, Serialize)]
+pub struct CommonSignature {
+	/// The signature
+	pub signature: Signature,
+	/// Times it occurs across the songs
+	pub postings: usize,
+}
+
+/// Percentiles of the number of postings per signature
+#[derive(Debug, Default, Serialize)]
+pub struct PostingsHistogram {
+	/// Fewest postings of any signature
+	pub min: usize,
+	/// Median postings of a signature
+	pub median: usize,
+	/// 99th percentile of postings per signature
+	pub p99: usize,
+	/// Most postings of any signature
+	pub max: usize,
+}
+
+/// What `Database::merge` does with a song that's in both databases
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+	/// Keep only the copy already in the database being merged into
+	Skip,
+	/// Keep both under separate ids
+	KeepBoth,
+	/// Fail the merge
+	Error,
+}
+
+/// Why `Database::merge` failed
+#[derive(Debug)]
+pub enum MergeError {
+	/// The other database was built with this config rather than the same one
+	ConfigMismatch(Box<DatabaseConfig>),
+	/// A song in both databases, when duplicates are an error
+	DuplicateSong(OsString),
+}
+impl std::fmt::Display for MergeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::ConfigMismatch(config) => write!(f, "built with a different config, {config:?}"),
+			Self::DuplicateSong(name) => write!(f, "{name:?} is in both databases"),
+		}
+	}
+}
+impl std::error::Error for MergeError {}
+
+/// Postings of every signature
+#[derive(Debug)]
+enum Index {
+	/// A list per signature, cheap to insert into
+	Building(FxHashMap<PackedSignature, Vec<Posting>>),
+	/// Every list back to back in one allocation, the postings of `keys[i]` are
+	/// `postings[offsets[i]..offsets[i + 1]]`
+	Finalized {
+		keys: Vec<PackedSignature>,
+		offsets: Vec<usize>,
+		postings: Vec<Posting>,
+	},
+}
+impl Index {
+	fn len(&self) -> usize {
+		match self {
+			Self::Building(map) => map.len(),
+			Self::Finalized { keys, .. } => keys.len(),
+		}
+	}
+	fn get(&self, signature: PackedSignature) -> &[Posting] {
+		match self {
+			Self::Building(map) => map.get(&signature).map_or(&[], Vec::as_slice),
+			Self::Finalized {
+				keys,
+				offsets,
+				postings,
+			} => match keys.binary_search(&signature) {
+				Ok(i) => &postings[offsets[i]..offsets[i + 1]],
+				Err(_) => &[],
+			},
+		}
+	}
+	fn iter(&self) -> Box<dyn Iterator<Item = (PackedSignature, &[Posting])> + '_> {
+		match self {
+			Self::Building(map) => Box::new(
+				map.iter()
+					.map(|(&signature, postings)| (signature, postings.as_slice())),
+			),
+			Self::Finalized {
+				keys,
+				offsets,
+				postings,
+			} => Box::new(
+				keys.iter()
+					.zip(offsets.windows(2))
+					.map(|(&signature, range)| (signature, &postings[range[0]..range[1]])),
+			),
+		}
+	}
+	/// Rough heap usage in bytes
+	fn estimated_size(&self) -> usize {
+		let posting_size = size_of::<Posting>();
+		match self {
+			Self::Building(map) => {
+				// Each bucket holds a key, a postings header and a control byte
+				map.capacity() * (size_of::<PackedSignature>() + size_of::<Vec<Posting>>() + 1)
+					+ map
+						.values()
+						.map(|postings| postings.capacity() * posting_size)
+						.sum::<usize>()
+			}
+			Self::Finalized {
+				keys,
+				offsets,
+				postings,
+			} => {
+				keys.capacity() * size_of::<PackedSignature>()
+					+ offsets.capacity() * size_of::<usize>()
+					+ postings.capacity() * posting_size
+			}
+		}
+	}
+	/// Packs every list into one allocation, sorted by signature
+	fn finalize(&mut self) {
+		let Self::Building(map) = self else {
+			return;
+		};
+		let mut entries: Vec<_> = std::mem::take(map).into_iter().collect();
+		entries.sort_unstable_by_key(|&(signature, _)| signature);
+		let posting_count = entries.iter().map(|(_, postings)| postings.len()).sum();
+		let mut keys = Vec::with_capacity(entries.len());
+		let mut offsets = Vec::with_capacity(entries.len() + 1);
+		let mut postings = Vec::with_capacity(posting_count);
+		offsets.push(0);
+		for (signature, list) in entries {
+			keys.push(signature);
+			postings.extend(list);
+			offsets.push(postings.len());
+		}
+		*self = Self::Finalized {
+			keys,
+			offsets,
+			postings,
+		};
+	}
+	/// The lists as a map to insert into or remove from, undoing `finalize`
+	fn map_mut(&mut self) -> &mut FxHashMap<PackedSignature, Vec<Posting>> {
+		if let Self::Finalized { .. } = self {
+			let map = self
+				.iter()
+				.map(|(signature, postings)| (signature, postings.to_vec()))
+				.collect();
+			*self = Self::Building(map);
+		}
+		match self {
+			Self::Building(map) => map,
+			Self::Finalized { .. } => unreachable!(),
+		}
+	}
+}
+
+/// Index from signature to the songs holding it and where, matched against samples
+#[derive(Debug)]
+pub struct Database {
+	data: Index,
+	config: DatabaseConfig,
+	/// Indexed by `SongId`, `None` once the song is removed
+	song_paths: Vec<Option<OsString>>,
+	song_meta: FxHashMap<SongId, SongMeta>,
+	/// Slices either side of an offset whose votes count towards it when matching
+	offset_tolerance: Offset,
+	/// Whether rarer signatures carry more weight when matching, see `idf_weight`
+	idf_weighting: bool,
+	/// Whether every slice of a sample votes once per offset when matching, rather than
+	/// once per signature
+	slice_votes: bool,
+	/// How silence is trimmed off samples before matching them, if at all
+	silence_trim: Option<SilenceTrim>,
+	/// Band samples are filtered to before matching them, if any
+	band_pass: Option<encoder::filters::BandPass>,
+	/// Most of the start of a sample that's matched, all of it if `None`
+	query_duration: Option<std::time::Duration>,
+	/// When matching stops before the end of a sample, never if `None`
+	early_exit: Option<EarlyExit>,
+	/// Matching runs in this rather than rayon's global pool
+	thread_pool: Option<Arc<rayon::ThreadPool>>,
+}
+impl Database {
+	/// Postings stored at a fixed width, still read but no longer written
+	const MAGIC: &'static [u8; 4] = b"SZDB";
+	const VARINT_MAGIC: &'static [u8; 4] = b"SZDV";
+	/// Encodes to the binary database format, all little endian: `VARINT_MAGIC`, the
+	/// cache layout version, the length prefixed JSON config, the song count followed by
+	/// each length prefixed song path, with a length of `u64::MAX` marking a removed
+	/// song, then the signature count followed by each signature in order. Signatures
+	/// are their `delta` from the previous one, followed by their posting count and
+	/// their postings, the song id and timestamp of each being the `delta` from the
+	/// previous posting's, all as varints. Last comes the length prefixed JSON object
+	/// from song id to `SongMeta`
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let config = serde_json::to_vec(&self.config).expect("configs always serialize");
+		let posting_count: usize = self.data.iter().map(|(_, postings)| postings.len()).sum();
+		let mut bytes = Vec::with_capacity(self.data.len() * 4 + posting_count * 3);
+		bytes.extend_from_slice(Self::VARINT_MAGIC);
+		bytes.extend_from_slice(&CACHE_LAYOUT_VERSION.to_le_bytes());
+		bytes.extend_from_slice(&(config.len() as u64).to_le_bytes());
+		bytes.extend_from_slice(&config);
+		bytes.extend_from_slice(&(self.song_paths.len() as u64).to_le_bytes());
+		for path in self.song_paths.iter() {
+			match path {
+				Some(path) => {
+					let path = path.as_encoded_bytes();
+					bytes.extend_from_slice(&(path.len() as u64).to_le_bytes());
+					bytes.extend_from_slice(path);
+				}
+				None => bytes.extend_from_slice(&u64::MAX.to_le_bytes()),
+			}
+		}
+		bytes.extend_from_slice(&(self.data.len() as u64).to_le_bytes());
+		// Sorted, so loading can go straight to the finalized index
+		let mut lists: Vec<_> = self.data.iter().collect();
+		lists.sort_unstable_by_key(|&(signature, _)| signature);
+		let mut previous_signature = 0;
+		for (signature, postings) in lists {
+			write_varint(&mut bytes, delta(signature, previous_signature));
+			write_varint(&mut bytes, postings.len() as u64);
+			let (mut previous_song, mut previous_timestamp) = (0, 0);
+			for &(song_id, timestamp) in postings.iter() {
+				write_varint(&mut bytes, delta(song_id as u64, previous_song as u64));
+				write_varint(
+					&mut bytes,
+					delta(timestamp as u64, previous_timestamp as u64),
+				);
+				(previous_song, previous_timestamp) = (song_id, timestamp);
+			}
+			previous_signature = signature;
+		}
+		let meta: BTreeMap<_, _> = self.song_meta.iter().collect();
+		let meta = serde_json::to_vec(&meta).expect("metadata always serializes");
+		bytes.extend_from_slice(&(meta.len() as u64).to_le_bytes());
+		bytes.extend_from_slice(&meta);
+		bytes
+	}
+	/// Decodes the format written by `to_bytes`
+	pub fn from_bytes(bytes: &[u8]) -> Result<Database, DatabaseLoadError> {
+		fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], DatabaseLoadError> {
+			if bytes.len() < len {
+				return Err(DatabaseLoadError::Corrupt("file is truncated"));
+			}
+			let (head, tail) = bytes.split_at(len);
+			*bytes = tail;
+			Ok(head)
+		}
+		fn take_u64(bytes: &mut &[u8]) -> Result<u64, DatabaseLoadError> {
+			Ok(u64::from_le_bytes(take(bytes, 8)?.try_into().unwrap()))
+		}
+		fn take_u32(bytes: &mut &[u8]) -> Result<u32, DatabaseLoadError> {
+			Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+		}
+		fn take_varint(bytes: &mut &[u8]) -> Result<u64, DatabaseLoadError> {
+			read_varint(bytes).map_err(DatabaseLoadError::Corrupt)
+		}
+		/// Reverses `as_encoded_bytes`, on Unix names are any bytes but elsewhere the
+		/// encoding isn't stable, so only UTF-8 is accepted
+		fn path_from_bytes(bytes: Vec<u8>) -> Result<OsString, DatabaseLoadError> {
+			#[cfg(unix)]
+			{
+				use std::os::unix::ffi::OsStringExt;
+				Ok(OsString::from_vec(bytes))
+			}
+			#[cfg(not(unix))]
+			{
+				String::from_utf8(bytes)
+					.map(OsString::from)
+					.map_err(|_| DatabaseLoadError::Corrupt("song path isn't UTF-8"))
+			}
+		}
+		let (mut bytes, varint) = if let Some(bytes) = bytes.strip_prefix(Self::VARINT_MAGIC) {
+			(bytes, true)
+		} else if let Some(bytes) = bytes.strip_prefix(Self::MAGIC) {
+			(bytes, false)
+		} else {
+			return Err(DatabaseLoadError::Corrupt("missing magic bytes"));
+		};
+		if take_u32(&mut bytes)? != CACHE_LAYOUT_VERSION {
+			return Err(DatabaseLoadError::LayoutMismatch);
+		}
+		let config_len = take_u64(&mut bytes)? as usize;
+		let config = serde_json::from_slice(take(&mut bytes, config_len)?)
+			.map_err(DatabaseLoadError::Json)?;
+		let song_count = take_u64(&mut bytes)?;
+		let song_paths = (0..song_count)
+			.map(|_| {
+				let len = take_u64(&mut bytes)?;
+				if len == u64::MAX {
+					return Ok(None);
+				}
+				path_from_bytes(take(&mut bytes, len as usize)?.to_vec()).map(Some)
+			})
+			.collect::<Result<Vec<Option<OsString>>, DatabaseLoadError>>()?;
+		let signature_count = take_u64(&mut bytes)? as usize;
+		// Varint signatures take at least two bytes and postings at least one
+		let mut keys = Vec::with_capacity(signature_count.min(bytes.len() / 2));
+		let mut offsets = Vec::with_capacity(keys.capacity() + 1);
+		let mut postings = Vec::new();
+		offsets.push(0);
+		let mut signature = 0;
+		for _ in 0..signature_count {
+			if varint {
+				signature = undelta(take_varint(&mut bytes)?, signature);
+				let posting_count = take_varint(&mut bytes)?;
+				// Varint postings take at least two bytes
+				postings.reserve(posting_count.min(bytes.len() as u64 / 2) as usize);
+				let (mut song_id, mut timestamp) = (0, 0);
+				for _ in 0..posting_count {
+					song_id = undelta(take_varint(&mut bytes)?, song_id);
+					timestamp = undelta(take_varint(&mut bytes)?, timestamp);
+					postings.push((song_id as SongId, timestamp as TimeStamp));
+				}
+			} else {
+				signature = take_u64(&mut bytes)?;
+				let posting_count = take_u64(&mut bytes)? as usize;
+				postings.reserve(posting_count.min(bytes.len() / 8));
+				for posting in take(&mut bytes, posting_count.saturating_mul(8))?.chunks_exact(8) {
+					let (song_id, timestamp) = posting.split_at(size_of::<SongId>());
+					postings.push((
+						SongId::from_le_bytes(song_id.try_into().unwrap()),
+						TimeStamp::from_le_bytes(timestamp.try_into().unwrap()),
+					));
+				}
+			}
+			keys.push(signature);
+			offsets.push(postings.len());
+		}
+		if postings
+			.iter()
+			.any(|&(song_id, _timestamp)| song_id as u64 >= song_count)
+		{
+			return Err(DatabaseLoadError::Corrupt(
+				"posting of a song that isn't stored",
+			));
+		}
+		// Files saved before metadata was stored end after the signatures
+		let song_meta: FxHashMap<SongId, SongMeta> = if bytes.is_empty() {
+			FxHashMap::default()
+		} else {
+			let meta_len = take_u64(&mut bytes)? as usize;
+			serde_json::from_slice(take(&mut bytes, meta_len)?).map_err(DatabaseLoadError::Json)?
+		};
+		if !bytes.is_empty() {
+			return Err(DatabaseLoadError::Corrupt(
+				"trailing bytes after the metadata",
+			));
+		}
+		let sorted = keys.windows(2).all(|pair| pair[0] < pair[1]);
+		let mut data = Index::Finalized {
+			keys,
+			offsets,
+			postings,
+		};
+		// Files saved before signatures were written sorted
+		if !sorted {
+			data.map_mut();
+			data.finalize();
+		}
+		Ok(Database {
+			data,
+			config,
+			song_paths,
+			song_meta,
+			offset_tolerance: DEFAULT_OFFSET_TOLERANCE,
+			idf_weighting: true,
+			slice_votes: false,
+			silence_trim: None,
+			band_pass: None,
+			query_duration: None,
+			early_exit: None,
+			thread_pool: None,
+		})
+	}
+	/// Writes the database to `path` so later runs can `load` it instead of building it
+	pub fn save(&self, path: &Path) -> std::io::Result<()> {
+		write_atomically(path, &self.to_bytes())
+	}
+	/// Reads a database written by `save`, refusing it unless it was built with `config`
+	pub fn load(path: &Path, config: &DatabaseConfig) -> Result<Database, DatabaseLoadError> {
+		let db = Self::from_bytes(&std::fs::read(path)?)?;
+		if db.config.cached_dir_name() != config.cached_dir_name() {
+			return Err(DatabaseLoadError::ConfigMismatch(Box::new(db.config)));
+		}
+		Ok(db)
+	}
+	/// Indexes `data` under a new song, returning its id. Ids are handed out in order
+	/// and never reused, so ids of songs already in the database stay valid
+	pub fn add_song(&mut self, name: OsString, data: SongData) -> SongId {
+		let id = self.song_paths.len() as SongId;
+		self.insert_song(id, name, data);
+		id
+	}
+	/// Indexes `data` under the given id, which must not be taken
+	fn insert_song(&mut self, id: SongId, name: OsString, data: SongData) {
+		if self.song_paths.len() <= id as usize {
+			self.song_paths.resize(id as usize + 1, None);
+		}
+		assert!(
+			self.song_paths[id as usize].is_none(),
+			"song id {id} is taken"
+		);
+		let map = self.data.map_mut();
+		for (signature, timestamp) in data.0 {
+			map.entry(signature).or_default().push((id, timestamp));
+		}
+		self.song_paths[id as usize] = Some(name);
+	}
+	/// Drops every posting of song `id`, returning its name unless it was already removed.
+	/// Its id is left as a tombstone rather than reused, so the ids of other songs don't
+	/// change and `match_sample` never returns it again
+	pub fn remove_song(&mut self, id: SongId) -> Option<OsString> {
+		let name = self.song_paths.get_mut(id as usize)?.take()?;
+		self.song_meta.remove(&id);
+		self.data.map_mut().retain(|_signature, postings| {
+			postings.retain(|&(song_id, _timestamp)| song_id != id);
+			!postings.is_empty()
+		});
+		Some(name)
+	}
+	/// Decodes the WAV file in `bytes` and adds it like `add_song`
+	pub fn add_song_from_wav(
+		&mut self,
+		name: OsString,
+		bytes: Vec<u8>,
+	) -> Result<SongId, DecodeError> {
+		let song = encoder::Song::from_wav(bytes)?.resample(self.config.sample_rate);
+		let data = self.config.song_data(&name, &song);
+		Ok(self.add_song(name, data))
+	}
+	/// Combines two databases built with the same config, such as shards of one library
+	/// built on different machines. Songs of `other` get new ids following the ones
+	/// in `self`, and what happens to a song whose name is in both is up to `duplicates`
+	pub fn merge(
+		mut self,
+		other: Database,
+		duplicates: DuplicatePolicy,
+	) -> Result<Database, MergeError> {
+		if self.config.cached_dir_name() != other.config.cached_dir_name() {
+			return Err(MergeError::ConfigMismatch(Box::new(other.config)));
+		}
+		let names: HashSet<&OsString> = self.song_paths.iter().flatten().collect();
+		let is_duplicate: Vec<bool> = other
+			.song_paths
+			.iter()
+			.map(|path| path.as_ref().is_some_and(|path| names.contains(path)))
+			.collect();
+		for (path, _) in other
+			.song_paths
+			.iter()
+			.zip(&is_duplicate)
+			.filter(|(_path, &is_duplicate)| is_duplicate)
+		{
+			let path = path.as_ref().unwrap();
+			match duplicates {
+				DuplicatePolicy::Skip => warn!("Skipping {path:?}, it's already in the database"),
+				DuplicatePolicy::KeepBoth => warn!("Keeping both copies of {path:?}"),
+				DuplicatePolicy::Error => return Err(MergeError::DuplicateSong(path.clone())),
+			}
+		}
+		// Skipped songs are left as tombstones, so every id shifts by the same amount
+		let skipped = |id: SongId| duplicates == DuplicatePolicy::Skip && is_duplicate[id as usize];
+		let offset = self.song_paths.len() as SongId;
+		let map = self.data.map_mut();
+		for (signature, postings) in other.data.iter() {
+			let postings: Vec<_> = postings
+				.iter()
+				.copied()
+				.filter(|&(id, _timestamp)| !skipped(id))
+				.map(|(id, timestamp)| (id + offset, timestamp))
+				.collect();
+			if !postings.is_empty() {
+				map.entry(signature).or_default().extend(postings);
+			}
+		}
+		self.song_meta.extend(
+			other
+				.song_meta
+				.into_iter()
+				.filter(|&(id, _)| !skipped(id))
+				.map(|(id, meta)| (id + offset, meta)),
+		);
+		self.song_paths.extend(
+			other
+				.song_paths
+				.into_iter()
+				.enumerate()
+				.map(|(id, path)| path.filter(|_| !skipped(id as SongId))),
+		);
+		Ok(self)
+	}
+	/// Size of the index and the `top_n` most common signatures, handy for seeing what
+	/// a config does to the index and for spotting degenerate signatures
+	pub fn stats(&self, top_n: usize) -> DatabaseStats {
+		let mut lengths: Vec<usize> = self
+			.data
+			.iter()
+			.map(|(_, postings)| postings.len())
+			.collect();
+		lengths.sort_unstable();
+		let percentile = |p: usize| lengths[(lengths.len() - 1) * p / 100];
+		let postings_per_signature = if lengths.is_empty() {
+			PostingsHistogram::default()
+		} else {
+			PostingsHistogram {
+				min: lengths[0],
+				median: percentile(50),
+				p99: percentile(99),
+				max: lengths[lengths.len() - 1],
+			}
+		};
+		let names_size: usize = self
+			.song_paths
+			.iter()
+			.map(|path| size_of::<Option<OsString>>() + path.as_ref().map_or(0, |path| path.len()))
+			.sum();
+		let mut most_common: Vec<_> = self
+			.data
+			.iter()
+			.map(|(signature, postings)| (postings.len(), signature))
+			.collect();
+		let top_n = top_n.min(most_common.len());
+		if top_n > 0 {
+			most_common.select_nth_unstable_by(top_n - 1, |a, b| b.cmp(a));
+		}
+		most_common.truncate(top_n);
+		most_common.sort_unstable_by(|a, b| b.cmp(a));
+		// Signatures and slices of every song
+		let mut songs: BTreeMap<SongId, (usize, TimeStamp)> = BTreeMap::new();
+		for (_, postings) in self.data.iter() {
+			for &(song_id, timestamp) in postings {
+				let (signatures, slices) = songs.entry(song_id).or_default();
+				*signatures += 1;
+				*slices = (*slices).max(timestamp + 1);
+			}
+		}
+		let hop_size = self.config.constellation.hop_size;
+		DatabaseStats {
+			song_count: self.song_paths.iter().flatten().count(),
+			signature_count: lengths.iter().sum(),
+			distinct_signatures: self.data.len(),
+			postings_per_signature,
+			estimated_memory: self.data.estimated_size() + names_size,
+			most_common: most_common
+				.into_iter()
+				.map(|(postings, signature)| CommonSignature {
+					signature: Signature::unpack(signature),
+					postings,
+				})
+				.collect(),
+			signature_rates: songs
+				.into_iter()
+				.map(|(id, (signatures, slices))| SignatureRate {
+					id,
+					name: self.song_name(id).unwrap_or_default().into_owned(),
+					signatures,
+					per_second: signatures as f32 / (hop_size * slices).as_secs_f32(),
+				})
+				.collect(),
+		}
+	}
+	/// Metadata attached to song `id`, if any
+	pub fn song_meta(&self, id: SongId) -> Option<&SongMeta> {
+		self.song_meta.get(&id)
+	}
+	/// Replaces the metadata of song `id`
+	pub fn set_song_meta(&mut self, id: SongId, meta: SongMeta) {
+		self.song_meta.insert(id, meta);
+	}
+	/// "Artist - Title" from the song's metadata, or its name without them. `None` for
+	/// ids of unknown or removed songs
+	pub fn song_display_name(&self, id: SongId) -> Option<String> {
+		let name = self.song_name(id)?;
+		Some(
+			self.song_meta(id)
+				.and_then(SongMeta::display_name)
+				.unwrap_or_else(|| name.into_owned()),
+		)
+	}
+	/// Id of the song named `name`, its path relative to the songs directory
+	pub fn song_id(&self, name: &str) -> Option<SongId> {
+		self.song_paths
+			.iter()
+			.position(|path| path.as_deref() == Some(OsStr::new(name)))
+			.map(|id| id as SongId)
+	}
+	/// Name of song `id` for display, with any non UTF-8 parts replaced. `None` for ids
+	/// of unknown or removed songs
+	pub fn song_name(&self, id: SongId) -> Option<Cow<'_, str>> {
+		self.song_path(id).map(OsStr::to_string_lossy)
+	}
+	/// Ids of every song in the database, in ascending order
+	pub fn song_ids(&self) -> impl Iterator<Item = SongId> + '_ {
+		self.song_paths
+			.iter()
+			.enumerate()
+			.filter(|(_, path)| path.is_some())
+			.map(|(id, _)| id as SongId)
+	}
+	/// Exact name of song `id`, its path relative to the songs directory
+	pub fn song_path(&self, id: SongId) -> Option<&OsStr> {
+		self.song_paths.get(id as usize)?.as_deref()
+	}
+	/// An empty database of songs fingerprinted with `config`
+	pub fn new(config: DatabaseConfig) -> Self {
+		Self {
+			config,
+			data: Index::Building(FxHashMap::default()),
+			song_paths: Vec::new(),
+			song_meta: FxHashMap::default(),
+			offset_tolerance: DEFAULT_OFFSET_TOLERANCE,
+			idf_weighting: true,
+			slice_votes: false,
+			silence_trim: None,
+			band_pass: None,
+			query_duration: None,
+			early_exit: None,
+			thread_pool: None,
+		}
+	}
+	/// Config the songs were fingerprinted with
+	pub fn config(&self) -> &DatabaseConfig {
+		&self.config
+	}
+	/// Packs the index into a form that takes less memory and is as quick to match
+	/// against. Adding, removing or merging songs afterwards unpacks it again, so call
+	/// this once they're done
+	pub fn finalize(&mut self) {
+		self.data.finalize();
+	}
+	/// Every song holding `signature`, with the slice it's at
+	pub fn postings(&self, signature: PackedSignature) -> &[Posting] {
+		self.data.get(signature)
+	}
+	/// Weights every signature a sample shares with a song by how rare it is across
+	/// songs when matching, instead of counting them all alike
+	pub fn set_idf_weighting(&mut self, idf_weighting: bool) {
+		self.idf_weighting = idf_weighting;
+	}
+	/// Lets every slice of a sample vote once for an offset when matching, however many
+	/// of its signatures line up there. Keeps repetitive songs, holding a signature many
+	/// times over, from piling up votes, at the cost of the evidence of slices that line
+	/// up well
+	pub fn set_slice_votes(&mut self, slice_votes: bool) {
+		self.slice_votes = slice_votes;
+	}
+	/// Trims leading and trailing silence off samples before matching them, `None`
+	/// matches them whole. Offsets and matched spans still count from the start of the
+	/// untrimmed sample. Songs are never trimmed, so offsets into them keep their meaning
+	pub fn set_silence_trim(&mut self, silence_trim: Option<SilenceTrim>) {
+		self.silence_trim = silence_trim;
+	}
+	/// How samples are trimmed before matching, see `set_silence_trim`
+	pub fn silence_trim(&self) -> Option<SilenceTrim> {
+		self.silence_trim
+	}
+	/// Filters samples to `band_pass` before matching them, and before trimming their
+	/// silence if that's set too. `None` matches them unfiltered, songs are never
+	/// filtered
+	pub fn set_band_pass(&mut self, band_pass: Option<encoder::filters::BandPass>) {
+		self.band_pass = band_pass;
+	}
+	/// Band samples are filtered to before matching, see `set_band_pass`
+	pub fn band_pass(&self) -> Option<encoder::filters::BandPass> {
+		self.band_pass
+	}
+	/// Matches only the first `query_duration` of samples, `None` matches them whole.
+	/// Long recordings are then fingerprinted no further than needed, and the rest of
+	/// them doesn't add votes for chance offsets. It's taken once they're trimmed of
+	/// silence, if that's set too
+	pub fn set_query_duration(&mut self, query_duration: Option<std::time::Duration>) {
+		self.query_duration = query_duration;
+	}
+	/// How much of samples is matched, see `set_query_duration`
+	pub fn query_duration(&self) -> Option<std::time::Duration> {
+		self.query_duration
+	}
+	/// `sample` as `match_sample` fingerprints it, band-passed, trimmed of silence and
+	/// cut to the query duration if set to, along with how much of its start was
+	/// trimmed off
+	pub fn prepare_sample<'a>(
+		&self,
+		sample: &'a encoder::Song,
+	) -> (Cow<'a, encoder::Song>, std::time::Duration) {
+		self.prepare_sample_within(sample, self.query_duration)
+	}
+	/// `prepare_sample` with the sample cut to `max` instead of the query duration
+	fn prepare_sample_within<'a>(
+		&self,
+		sample: &'a encoder::Song,
+		max: Option<std::time::Duration>,
+	) -> (Cow<'a, encoder::Song>, std::time::Duration) {
+		// Filtering only looks back, so without trimming the sample can be cut first,
+		// sparing the filter the rest of it
+		let mut prepared = match (max, self.silence_trim) {
+			(Some(max), None) => Cow::Owned(sample.slice(std::time::Duration::ZERO, max)),
+			_ => Cow::Borrowed(sample),
+		};
+		if let Some(band_pass) = self.band_pass {
+			prepared = Cow::Owned(encoder::filters::band_pass(&prepared, band_pass));
+		}
+		let Some(SilenceTrim {
+			threshold_db,
+			min_hold,
+		}) = self.silence_trim
+		else {
+			return (prepared, std::time::Duration::ZERO);
+		};
+		let audible = prepared.audible_range(threshold_db, min_hold);
+		let lead =
+			std::time::Duration::from_secs_f64(audible.start as f64 / sample.sample_rate as f64);
+		let trimmed = encoder::Song {
+			sample_rate: sample.sample_rate,
+			samples: prepared.samples[audible].to_vec(),
+		};
+		let trimmed = match max {
+			Some(max) => trimmed.slice(std::time::Duration::ZERO, max),
+			None => trimmed,
+		};
+		(Cow::Owned(trimmed), lead)
+	}
+	/// Matches in `thread_pool` rather than rayon's global pool, `None` goes back to it
+	pub fn set_thread_pool(&mut self, thread_pool: Option<Arc<rayon::ThreadPool>>) {
+		self.thread_pool = thread_pool;
+	}
+	/// Runs `op` in the pool set with `set_thread_pool`, so its rayon work shares the
+	/// database's threads
+	pub fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+		match &self.thread_pool {
+			Some(thread_pool) => thread_pool.install(op),
+			None => op(),
+		}
+	}
+	/// Counts the votes for offsets up to `offset_tolerance` slices either side of each
+	/// one towards it when matching, 0 only counts exact offsets
+	pub fn set_offset_tolerance(&mut self, offset_tolerance: Offset) {
+		self.offset_tolerance = offset_tolerance;
+	}
+	/// Counts the votes of samples `early_exit.check_every` slices at a time, in order,
+	/// stopping as soon as a song is far enough ahead of the rest. Its matches are those
+	/// so far, marked as `Match::early_exit`. `None` always matches samples whole
+	pub fn set_early_exit(&mut self, early_exit: Option<EarlyExit>) {
+		self.early_exit = early_exit;
+	}
+	/// Up to `k` songs scoring at least `min_score` against `sample`, best first
+	pub fn best_matches(&self, sample: &encoder::Song, k: usize, min_score: f32) -> Vec<Match> {
+		rank_matches(self.match_sample(sample), k, min_score)
+	}
+	/// The song scoring highest against `sample`, if any shares a signature with it
+	pub fn best_match(&self, sample: &encoder::Song) -> Option<Match> {
+		self.best_matches(sample, 1, f32::NEG_INFINITY).pop()
+	}
+	/// The best match for `sample` if `policy` trusts it, `None` when it's most likely
+	/// not in the database at all
+	pub fn identify(&self, sample: &encoder::Song, policy: MatchPolicy) -> Option<Match> {
+		policy.pick(&self.best_matches(sample, 2, f32::NEG_INFINITY))
+	}
+	/// Every song sharing a signature with `sample`, in no particular order
+	pub fn match_sample(&self, sample: &encoder::Song) -> Vec<Match> {
+		self.match_sample_within(sample, self.query_duration)
+	}
+	/// Same as `match_sample` matching only the first `max` of `sample`, whatever
+	/// `set_query_duration` says
+	pub fn match_sample_bounded(
+		&self,
+		sample: &encoder::Song,
+		max: std::time::Duration,
+	) -> Vec<Match> {
+		self.match_sample_within(sample, Some(max))
+	}
+	fn match_sample_within(
+		&self,
+		sample: &encoder::Song,
+		max: Option<std::time::Duration>,
+	) -> Vec<Match> {
+		self.match_sample_with_votes(sample, max).0
+	}
+	/// Up to `k` of the songs matching `sample` best, as `best_matches` ranks them, each
+	/// with the votes it got for every offset. Meant for looking into how their scores
+	/// came about, as the votes of the rest are dropped
+	pub fn match_sample_detailed(&self, sample: &encoder::Song, k: usize) -> Vec<DetailedMatch> {
+		let (matches, votes, lead) = self.match_sample_with_votes(sample, self.query_duration);
+		rank_matches(matches, k, f32::NEG_INFINITY)
+			.into_iter()
+			.map(|found| DetailedMatch {
+				histogram: votes[&found.id]
+					.iter()
+					.map(|(&offset, votes)| (offset, votes.hits))
+					.collect(),
+				found,
+				lead,
+			})
+			.collect()
+	}
+	/// `match_sample_within` along with every song's votes for each offset, and how much
+	/// of the start of the sample was trimmed off before counting them
+	fn match_sample_with_votes(
+		&self,
+		sample: &encoder::Song,
+		max: Option<std::time::Duration>,
+	) -> (Vec<Match>, Votes, std::time::Duration) {
+		self.install(|| {
+			let _entered =
+				tracing::info_span!("match_sample", length = ?sample.length(), max = ?max)
+					.entered();
+			let (prepared, lead) = self.prepare_sample_within(sample, max);
+			let (matches, votes) =
+				self.match_signatures_with_votes(self.config.signatures(&prepared));
+			let matches = matches
+				.into_iter()
+				.map(|found| found.trimmed_by(lead))
+				.collect();
+			(matches, votes, lead)
+		})
+	}
+	/// Same as `match_sample` for a sample that's already fingerprinted, `signatures`
+	/// pairs the slice of each with it and must come from this database's config
+	pub fn match_signatures(
+		&self,
+		signatures: impl IntoIterator<Item = (TimeStamp, Signature)>,
+	) -> Vec<Match> {
+		self.match_signatures_with_votes(signatures).0
+	}
+	/// `match_signatures` along with the votes the matches are scored by
+	fn match_signatures_with_votes(
+		&self,
+		signatures: impl IntoIterator<Item = (TimeStamp, Signature)>,
+	) -> (Vec<Match>, Votes) {
+		let span = tracing::info_span!("match_signatures", signatures = tracing::field::Empty);
+		let _entered = span.enter();
+		let signatures: Vec<_> =
+			tracing::debug_span!("signatures").in_scope(|| signatures.into_iter().collect());
+		span.record("signatures", signatures.len());
+		self.install(|| {
+			let (votes, early_exit) =
+				tracing::debug_span!(parent: &span, "count_votes").in_scope(|| {
+					match self.early_exit {
+						Some(early_exit) => self.count_votes_until_ahead(signatures, early_exit),
+						None => (self.count_votes(signatures), false),
+					}
+				});
+			let mut matches =
+				tracing::debug_span!(parent: &span, "score_votes", songs = votes.len())
+					.in_scope(|| self.score_votes(&votes));
+			matches
+				.iter_mut()
+				.for_each(|found| found.early_exit = early_exit);
+			(matches, votes)
+		})
+	}
+	/// Why `sample` matches song `id` where it does, counting its votes like
+	/// `match_sample` would and then going over them again for the signatures voting for
+	/// the best offset. Only those are kept, so it takes little more memory than matching
+	pub fn explain_match(&self, sample: &encoder::Song, id: SongId) -> MatchExplanation {
+		let _entered = tracing::info_span!("explain_match", song = id).entered();
+		let (prepared, lead) = self.prepare_sample(sample);
+		let mut signatures: Vec<_> = self.config.signatures(&prepared).collect();
+		// Slices in order, as votes have to be added
+		signatures.sort_by_key(|&(sample_timestamp, _)| sample_timestamp);
+		let song_count = self.song_paths.iter().flatten().count();
+		// Every time song `id` holds a signature of the sample, with the offset it votes for
+		let shared = || {
+			signatures
+				.iter()
+				.enumerate()
+				.flat_map(|(i, &(sample_timestamp, signature))| {
+					let postings = self.data.get(signature.pack());
+					postings.iter().filter(|&&(song_id, _)| song_id == id).map(
+						move |&(_, song_timestamp)| {
+							let offset = song_timestamp as Offset - sample_timestamp as Offset;
+							let aligned = AlignedSignature {
+								sample_timestamp,
+								song_timestamp,
+								signature,
+							};
+							(i, offset, aligned, postings)
+						},
+					)
+				})
+		};
+		let mut offset_freq_table: FxHashMap<Offset, OffsetVotes> = FxHashMap::default();
+		let mut shared_count = 0;
+		let mut last_shared = None;
+		for (i, offset, aligned, postings) in shared() {
+			if last_shared != Some(i) {
+				shared_count += 1;
+				last_shared = Some(i);
+			}
+			offset_freq_table
+				.entry(offset)
+				.or_default()
+				.add(aligned.sample_timestamp, self.weight(postings, song_count));
+		}
+		let (found, offset) = match offset_freq_table.is_empty() {
+			true => (None, 0),
+			false => {
+				let (found, offset) = self.score_song(id, &offset_freq_table);
+				(Some(found.trimmed_by(lead)), offset)
+			}
+		};
+		let mut histogram: Vec<_> = offset_freq_table
+			.iter()
+			.map(|(&offset, votes)| OffsetHits {
+				offset,
+				hits: votes.hits,
+				slices: votes.slices,
+			})
+			.collect();
+		histogram.sort_by_key(|hits| hits.offset);
+		let aligned = shared()
+			.filter(|&(_, shared_offset, ..)| {
+				(shared_offset - offset).abs() <= self.offset_tolerance
+			})
+			.map(|(_, _, aligned, _)| aligned)
+			.collect();
+		MatchExplanation {
+			id,
+			found,
+			offset,
+			aligned,
+			histogram,
+			sample_signatures: signatures.len(),
+			sample_slices: signatures.last().map_or(0, |&(timestamp, _)| timestamp + 1),
+			shared: shared_count,
+			lead,
+			hop_size: self.config.constellation.hop_size,
+		}
+	}
+	/// Pairs of songs of which one's signatures line up with the other's, at least
+	/// `min_ratio` of them at a single offset, such as a song indexed twice under other
+	/// names or re-encoded. Every song is matched against the rest with the signatures
+	/// it's indexed by, in parallel, and a pair found both ways is reported once, by
+	/// the song with more of its signatures lining up. Most lining up first
+	pub fn find_duplicates(&self, min_ratio: f32) -> Vec<Duplicate> {
+		let _entered = tracing::info_span!("find_duplicates").entered();
+		let mut songs = vec![Vec::new(); self.song_paths.len()];
+		for (signature, postings) in self.data.iter() {
+			for &(song_id, timestamp) in postings {
+				songs[song_id as usize].push((timestamp, Signature::unpack(signature)));
+			}
+		}
+		let mut duplicates: Vec<_> = self.install(|| {
+			songs
+				.par_iter()
+				.enumerate()
+				.flat_map_iter(|(id, signatures)| {
+					let id = id as SongId;
+					let mut votes = self.count_votes(signatures.iter().copied());
+					votes.remove(&id);
+					let needed = min_ratio * signatures.len() as f32;
+					votes
+						.into_iter()
+						// Votes at every offset put together are at least those at one
+						.filter(move |(_, table)| {
+							table.values().map(|votes| votes.hits).sum::<usize>() as f32 >= needed
+						})
+						.filter_map(move |(other, table)| {
+							let (found, offset) = self.score_song(other, &table);
+							// Which can count a signature more than once, if `other` holds
+							// it more than once around `offset`
+							if (found.freq as f32) < needed {
+								return None;
+							}
+							let aligned = self.aligned_signatures(signatures, other, offset);
+							let ratio = aligned as f32 / signatures.len() as f32;
+							(ratio >= min_ratio).then_some(Duplicate {
+								id,
+								other,
+								ratio,
+								offset,
+								found,
+							})
+						})
+				})
+				.collect()
+		});
+		duplicates.sort_by(|a, b| {
+			b.ratio
+				.total_cmp(&a.ratio)
+				.then(a.id.cmp(&b.id))
+				.then(a.other.cmp(&b.other))
+		});
+		let mut reported = FxHashSet::default();
+		duplicates.retain(|duplicate| {
+			reported.insert((
+				duplicate.id.min(duplicate.other),
+				duplicate.id.max(duplicate.other),
+			))
+		});
+		duplicates
+	}
+	/// How many of `signatures` song `id` holds at `offset`, give or take the offset
+	/// tolerance. Unlike `Match::freq`, each counts once however many times it does
+	fn aligned_signatures(
+		&self,
+		signatures: &[(TimeStamp, Signature)],
+		id: SongId,
+		offset: Offset,
+	) -> usize {
+		signatures
+			.iter()
+			.filter(|&&(timestamp, signature)| {
+				self.data
+					.get(signature.pack())
+					.iter()
+					.any(|&(song_id, song_timestamp)| {
+						let shift = song_timestamp as Offset - timestamp as Offset - offset;
+						song_id == id && shift.abs() <= self.offset_tolerance
+					})
+			})
+			.count()
+	}
+	/// Every song's votes for each offset from `signatures`. Each slice's signatures
+	/// all have to be passed at once, for its votes to be told apart from another's
+	fn count_votes(&self, signatures: impl IntoIterator<Item = (TimeStamp, Signature)>) -> Votes {
+		let mut signatures: Vec<_> = signatures.into_iter().collect();
+		signatures.sort_by_key(|&(sample_timestamp, _)| sample_timestamp);
+		let slices: Vec<_> = signatures.chunk_by(|a, b| a.0 == b.0).collect();
+		// Every worker counts the offsets of its share of the slices, the tables are then
+		// summed so the result doesn't depend on how the work was split. A slice is never
+		// split between workers, so its votes can be told apart from the next one's
+		let song_count = self.song_paths.iter().flatten().count();
+		slices
+			.par_iter()
+			.with_min_len(MIN_QUERY_SLICES_PER_JOB)
+			.fold(Votes::default, |mut song_offsets, slice| {
+				for (sample_timestamp, signature) in slice.iter() {
+					let matches = self.data.get(signature.pack());
+					let weight = self.weight(matches, song_count);
+					matches.iter().for_each(|(song_id, song_timestamp)| {
+						let offset = *song_timestamp as Offset - *sample_timestamp as Offset;
+						let freq_table = song_offsets.entry(*song_id).or_default();
+						freq_table
+							.entry(offset)
+							.or_default()
+							.add(*sample_timestamp, weight);
+					});
+				}
+				song_offsets
+			})
+			.reduce(Votes::default, merge_votes)
+	}
+	/// `count_votes`, `early_exit.check_every` slices of the sample at a time, along
+	/// with whether it stopped before the last slice because a song was far enough ahead
+	fn count_votes_until_ahead(
+		&self,
+		mut signatures: Vec<(TimeStamp, Signature)>,
+		early_exit: EarlyExit,
+	) -> (Votes, bool) {
+		signatures.sort_by_key(|&(sample_timestamp, _)| sample_timestamp);
+		let mut votes = Votes::default();
+		let mut start = 0;
+		while start < signatures.len() {
+			let end_timestamp = signatures[start].0 + early_exit.check_every.max(1) as TimeStamp;
+			let end = start
+				+ signatures[start..]
+					.partition_point(|&(sample_timestamp, _)| sample_timestamp < end_timestamp);
+			let counted = self.count_votes(signatures[start..end].iter().copied());
+			votes = merge_votes(votes, counted);
+			start = end;
+			if start < signatures.len() && early_exit.is_ahead(&self.score_votes(&votes)) {
+				return (votes, true);
+			}
+		}
+		(votes, false)
+	}
+	/// Weight of a signature with `postings` among `song_count` songs, see
+	/// `set_idf_weighting`
+	fn weight(&self, postings: &[Posting], song_count: usize) -> usize {
+		match self.idf_weighting {
+			true => idf_weight(postings, song_count),
+			false => IDF_WEIGHT_SCALE,
+		}
+	}
+	fn score_votes(&self, song_offsets: &Votes) -> Vec<Match> {
+		song_offsets
+			.par_iter()
+			.map(|(&song_id, offset_freq_table)| self.score_song(song_id, offset_freq_table).0)
+			.collect()
+	}
+	/// How song `song_id` matches with the votes for each offset in
+	/// `offset_freq_table`, along with the offset it does best at
+	fn score_song(
+		&self,
+		song_id: SongId,
+		offset_freq_table: &FxHashMap<Offset, OffsetVotes>,
+	) -> (Match, Offset) {
+		let mut sum = 0;
+		let mut max_weight = usize::MIN;
+		let mut best_offset = 0;
+		let mut n = 0;
+		// A sample rarely starts on a slice boundary, so the votes for its true offset
+		// are split with the neighbouring ones
+		let window = |offset: Offset| {
+			(offset - self.offset_tolerance..=offset + self.offset_tolerance)
+				.filter_map(|neighbour| offset_freq_table.get(&neighbour))
+		};
+		let weight = |votes: &OffsetVotes| match self.slice_votes {
+			true => votes.slice_weight,
+			false => votes.weight,
+		};
+		for (&offset, votes) in offset_freq_table.iter() {
+			let smoothed_weight = window(offset).map(weight).sum::<usize>();
+			// Ties go to the earliest offset, as the table's order isn't fixed
+			if smoothed_weight > max_weight
+				|| (smoothed_weight == max_weight && offset < best_offset)
+			{
+				max_weight = smoothed_weight;
+				best_offset = offset;
+			}
+			sum += weight(votes);
+			n += 1;
+		}
+		let average = sum as f32 / n as f32;
+		let best_votes = window(best_offset).fold(OffsetVotes::default(), |mut acc, votes| {
+			acc.merge(votes);
+			acc
+		});
+		let hop_size = self.config.constellation.hop_size;
+		let found = Match {
+			id: song_id,
+			offset: hop_size * best_offset.max(0) as u32,
+			matched_span: (best_votes.slices > 0)
+				.then(|| (hop_size * best_votes.first, hop_size * best_votes.last)),
+			freq: best_votes.hits,
+			slice_freq: best_votes.slices,
+			score: max_weight as f32 / average,
+			n,
+			early_exit: false,
+		};
+		(found, best_offset)
+	}
+}
+
+/// Matches a sample as it's recorded, fed to it a chunk at a time. Only the slices
+/// completed by each chunk are fingerprinted, and the sample ends up matched exactly
+/// as `Database::match_sample` would once `finish` is called, unless that band-passes
+/// or trims samples or exits early, which sessions don't. Samples pushed past the
+/// database's query duration are dropped, as `match_sample` would cut them off too
+pub struct MatchSession<'a> {
+	db: &'a Database,
+	/// Samples in a slice and between the starts of consecutive slices
+	window: usize,
+	hop: usize,
+	/// Slices either side of a slice needed to pick its peaks
+	peak_context: usize,
+	/// Samples not yet fingerprinted, the first being sample `samples_start` of the sample
+	samples: Vec<i16>,
+	samples_start: usize,
+	/// First slice whose peaks aren't picked yet
+	next_peak_slice: usize,
+	/// Peaks of the slices from `peaks_slice` on, whose signatures aren't counted yet
+	peaks: VecDeque<Vec<Freq>>,
+	peaks_slice: usize,
+	votes: Votes,
+}
+impl<'a> MatchSession<'a> {
+	/// Starts matching a new sample against `db`
+	pub fn new(db: &'a Database) -> Self {
+		let constellation = db.config.constellation;
+		let (window, hop) = encoder::slice_samples(
+			db.config.sample_rate,
+			constellation.slice_size,
+			constellation.hop_size,
+		);
+		Self {
+			db,
+			window,
+			hop,
+			peak_context: constellation.peak_time_radius(),
+			samples: Vec::new(),
+			samples_start: 0,
+			next_peak_slice: 0,
+			peaks: VecDeque::new(),
+			peaks_slice: 0,
+			votes: Votes::default(),
+		}
+	}
+	/// Rate `push_samples` expects its samples at, the database's
+	pub fn sample_rate(&self) -> usize {
+		self.db.config.sample_rate
+	}
+	/// Adds the next mono samples of the sample, in the ±1.0 range. Slices left
+	/// incomplete are kept for the next push
+	pub fn push_samples(&mut self, samples: &[f32]) {
+		let db = self.db;
+		db.install(|| self.push_samples_inner(samples))
+	}
+	fn push_samples_inner(&mut self, samples: &[f32]) {
+		let pushed = self.samples_start + self.samples.len();
+		let samples = match self.db.query_duration {
+			// As many samples as `encoder::Song::slice` keeps
+			Some(max) => {
+				let limit = (max.as_secs_f64() * self.sample_rate() as f64) as usize;
+				&samples[..samples.len().min(limit.saturating_sub(pushed))]
+			}
+			None => samples,
+		};
+		self.samples
+			.extend(encoder::Song::from_pcm_f32(samples.to_vec(), self.sample_rate()).samples);
+		let len = self.samples_start + self.samples.len();
+		let complete_slices = match len.checked_sub(self.window) {
+			Some(len) => len / self.hop + 1,
+			None => 0,
+		};
+		// Picking a slice's peaks needs the spectra of the slices around it
+		self.pick_peaks(complete_slices.saturating_sub(self.peak_context));
+		// An anchor's signatures need its whole target zone, and the last slice of the
+		// sample has none, so they also need the slice after it
+		let zone = (self.db.config.target_zone_width() as usize).max(2);
+		self.count_signatures((self.next_peak_slice + 1).saturating_sub(zone));
+	}
+	/// Matches the whole sample, taking its last samples as the end of it
+	pub fn finish(mut self, k: usize) -> Vec<Match> {
+		let db = self.db;
+		db.install(|| self.finish_inner(k))
+	}
+	fn finish_inner(&mut self, k: usize) -> Vec<Match> {
+		let len = self.samples_start + self.samples.len();
+		// Like `encoder::Song::constellation_map`, the last slice is zero padded
+		let slice_count = match len {
+			0 => 0,
+			len => len.saturating_sub(self.window).div_ceil(self.hop) + 1,
+		};
+		self.pick_peaks(slice_count);
+		self.count_signatures(slice_count);
+		self.current_best(k)
+	}
+	/// Up to `k` songs scoring highest against the sample so far, best first
+	pub fn current_best(&self, k: usize) -> Vec<Match> {
+		let matches = self.db.install(|| self.db.score_votes(&self.votes));
+		rank_matches(matches, k, f32::NEG_INFINITY)
+	}
+	/// Picks the peaks of the slices up to `end`, dropping the samples no longer needed
+	fn pick_peaks(&mut self, end: usize) {
+		if end <= self.next_peak_slice {
+			return;
+		}
+		// The song starts and ends with the context of the slices picked, which is cut
+		// short where the sample is
+		let first = self.next_peak_slice.saturating_sub(self.peak_context);
+		let start = first * self.hop - self.samples_start;
+		let stop = ((end + self.peak_context - 1) * self.hop + self.window - self.samples_start)
+			.min(self.samples.len());
+		let song = encoder::Song {
+			sample_rate: self.sample_rate(),
+			samples: self.samples[start..stop].to_vec(),
+		};
+		self.peaks.extend(
+			song.constellation_map(self.db.config.constellation)
+				.skip(self.next_peak_slice - first)
+				.take(end - self.next_peak_slice),
+		);
+		self.next_peak_slice = end;
+		let done = (end.saturating_sub(self.peak_context) * self.hop - self.samples_start)
+			.min(self.samples.len());
+		self.samples.drain(..done);
+		self.samples_start += done;
+	}
+	/// Counts the signatures of the anchors up to `end`, dropping their peaks
+	fn count_signatures(&mut self, end: usize) {
+		if end <= self.peaks_slice {
+			return;
+		}
+		let ready = (end - self.peaks_slice) as TimeStamp;
+		let peaks_slice = self.peaks_slice as TimeStamp;
+		let signatures = self
+			.db
+			.config
+			.pair_peaks(self.peaks.iter().cloned())
+			.take_while(|&(timestamp, _)| timestamp < ready)
+			.map(|(timestamp, signature)| (peaks_slice + timestamp, signature));
+		let votes = self.db.count_votes(signatures);
+		self.votes = merge_votes(std::mem::take(&mut self.votes), votes);
+		self.peaks.drain(..(ready as usize).min(self.peaks.len()));
+		self.peaks_slice = end;
+	}
+}
+
+/// Every song's votes for each offset of a sample
+type Votes = FxHashMap<SongId, FxHashMap<Offset, OffsetVotes>>;
+
+/// Sums the votes of `a` and `b`, which have to come from different slices
+fn merge_votes(mut a: Votes, mut b: Votes) -> Votes {
+	if a.len() < b.len() {
+		std::mem::swap(&mut a, &mut b);
+	}
+	for (song_id, offsets) in b {
+		let freq_table = a.entry(song_id).or_default();
+		for (offset, votes) in offsets {
+			freq_table.entry(offset).or_default().merge(&votes);
+		}
+	}
+	a
+}
+
+/// Drops the matches scoring below `min_score` and sorts the rest best first, keeping
+/// up to `k`
+fn rank_matches(mut matches: Vec<Match>, k: usize, min_score: f32) -> Vec<Match> {
+	matches.retain(|m| m.score >= min_score);
+	// Ties go to the lower id so the order doesn't depend on hashing
+	matches.sort_unstable_by(|a, b| b.score.total_cmp(&a.score).then(a.id.cmp(&b.id)));
+	matches.truncate(k);
+	matches
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+
+	#[test]
+	fn varints_round_trip() {
+		let values = [0, 1, 127, 128, 16383, 16384, u32::MAX as u64, u64::MAX];
+		let mut bytes = Vec::new();
+		values
+			.iter()
+			.for_each(|&value| write_varint(&mut bytes, value));
+		let mut rest = bytes.as_slice();
+		for value in values {
+			assert_eq!(read_varint(&mut rest), Ok(value));
+		}
+		assert!(rest.is_empty());
+		let mut truncated = &bytes[..bytes.len() - 1];
+		assert!((0..values.len()).any(|_| read_varint(&mut truncated).is_err()));
+	}
+
+	#[test]
+	fn deltas_round_trip() {
+		let mut next = encoder::xorshift(0);
+		let edges = [(0, u64::MAX), (u64::MAX, 0), (5, 7), (7, 5)];
+		let pairs = (0..1000).map(|_| (next(), next())).chain(edges);
+		for (value, previous) in pairs {
+			assert_eq!(undelta(delta(value, previous), previous), value);
+		}
+		// Small steps either way stay a byte
+		assert!(delta(100, 40) < 0x80 && delta(40, 100) < 0x80);
+	}
+
+	#[test]
+	fn song_data_round_trips_random_entries() {
+		for seed in 0..20 {
+			let mut next = encoder::xorshift(seed);
+			let mut entries: Vec<_> = (0..next() % 2000)
+				.map(|_| (next() % (1 << 48), (next() % 5000) as TimeStamp))
+				.collect();
+			// Unsorted first, then sorted by signature as `song_data` makes them
+			for _ in 0..2 {
+				let data = SongData(entries.clone());
+				let decoded = SongData::from_bytes(&data.to_bytes()).unwrap();
+				assert_eq!(decoded.0, entries);
+				entries.sort_unstable();
+			}
+			let sorted_size = SongData(entries.clone()).to_bytes().len();
+			// Sorted signatures are close together, so take well under their full width
+			assert!(sorted_size < 16 + entries.len() * SongData::ENTRY_SIZE * 3 / 4);
+		}
+	}
+
+	#[test]
+	fn leading_silence_has_no_signatures() {
+		let config = DatabaseConfig::builder().build();
+		let mut samples = vec![0; 2 * 44100];
+		samples.extend(encoder::Song::white_noise(Duration::from_secs(3), 44100, 0).samples);
+		let song = encoder::Song::from_pcm_i16(&samples, 44100);
+		let (window, hop) = encoder::slice_samples(
+			44100,
+			config.constellation().slice_size,
+			config.constellation().hop_size,
+		);
+		// Slices ending within the silence
+		let silent_slices = ((2 * 44100 - window) / hop + 1) as TimeStamp;
+		let first = config
+			.signatures(&song)
+			.map(|(timestamp, _)| timestamp)
+			.min();
+		assert_eq!(first, Some(silent_slices));
+	}
+
+	/// A database of three ten second songs of white noise, the seed of each its id
+	fn noise_database(config: DatabaseConfig) -> (Database, Vec<encoder::Song>) {
+		let mut db = Database::new(config);
+		let songs: Vec<_> = (0..3)
+			.map(|seed| encoder::Song::white_noise(Duration::from_secs(10), 44100, seed))
+			.collect();
+		for (seed, song) in songs.iter().enumerate() {
+			let name = OsString::from(format!("noise_{seed}.wav"));
+			let data = config.song_data(&name, song);
+			db.add_song(name, data);
+		}
+		db.finalize();
+		(db, songs)
+	}
+
+	/// A path in the temporary directory unique to this process and `name`
+	fn temp_path(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("shazoom-{}-{name}", std::process::id()))
+	}
+
+	#[test]
+	fn saved_database_matches_the_same() {
+		let config = DatabaseConfig::builder().build();
+		let (db, songs) = noise_database(config);
+		let path = temp_path("round-trip.db");
+		db.save(&path).unwrap();
+		let loaded = Database::load(&path, &config);
+		let _ = std::fs::remove_file(&path);
+		let loaded = loaded.unwrap_or_else(|err| panic!("{err}"));
+		let sample = songs[1].slice(Duration::from_secs(3), Duration::from_secs(5));
+		let matches = db.match_sample(&sample);
+		assert_eq!(rank_matches(matches.clone(), 1, 0.)[0].id, 1);
+		assert_eq!(loaded.match_sample(&sample), matches);
+	}
+
+	#[test]
+	fn samples_recorded_at_16_khz_match_their_source() {
+		let config = DatabaseConfig::builder().build();
+		let (db, songs) = noise_database(config);
+		let sample = songs[1]
+			.slice(Duration::from_secs(3), Duration::from_secs(5))
+			.resample(16000);
+		let wav = encoder::Song::to_wav(sample, encoder::WavOptions::default());
+		let decoded = config.load_wav(wav.as_slice()).unwrap();
+		assert_eq!(decoded.sample_rate, 44100);
+		let best = rank_matches(db.match_sample(&decoded), 1, 0.)[0];
+		assert_eq!(best.id, 1);
+		// Samples rarely start on a slice boundary, so offsets are right give or take one
+		assert!(
+			best.offset.abs_diff(Duration::from_secs(3)) <= config.slice_size(),
+			"{best:?}"
+		);
+	}
+
+	#[test]
+	fn trimmed_samples_match_at_their_untrimmed_offset() {
+		let (mut db, songs) = noise_database(DatabaseConfig::builder().build());
+		db.set_silence_trim(Some(SilenceTrim {
+			threshold_db: -40.,
+			min_hold: Duration::from_millis(300),
+		}));
+		// Two seconds of dead air, then from three seconds into song 1
+		let mut samples = vec![0; 2 * 44100];
+		samples.extend(
+			songs[1]
+				.slice(Duration::from_secs(3), Duration::from_secs(5))
+				.samples,
+		);
+		samples.extend(vec![0; 44100]);
+		let sample = encoder::Song::from_pcm_i16(&samples, 44100);
+		let best = rank_matches(db.match_sample(&sample), 1, 0.)[0];
+		assert_eq!(best.id, 1);
+		let hop_size = db.config().constellation().hop_size;
+		assert!(
+			best.offset.abs_diff(Duration::from_secs(1)) <= hop_size,
+			"{best:?}"
+		);
+		let silence = encoder::Song::from_pcm_i16(&[0; 3 * 44100], 44100);
+		assert!(db.match_sample(&silence).is_empty());
+	}
+
+	/// Ten seconds of the same half second of white noise over and over, like a loop
+	fn repetitive_song() -> encoder::Song {
+		let beat = encoder::Song::white_noise(Duration::from_millis(500), 44100, 9);
+		encoder::Song::from_pcm_i16(&beat.samples.repeat(20), 44100)
+	}
+
+	#[test]
+	fn repetitive_songs_get_many_hits_per_slice() {
+		let config = DatabaseConfig::builder().build();
+		let best = |song: &encoder::Song| {
+			let mut db = Database::new(config);
+			db.add_song(
+				"song.wav".into(),
+				config.song_data("song.wav".as_ref(), song),
+			);
+			db.finalize();
+			let sample = song.slice(Duration::from_millis(3100), Duration::from_secs(4));
+			rank_matches(db.match_sample(&sample), 1, 0.)[0]
+		};
+		let noise = encoder::Song::white_noise(Duration::from_secs(10), 44100, 0);
+		let (looped, noise) = (best(&repetitive_song()), best(&noise));
+		// Every slice of the sample lines up in both, but the loop's hit over and over
+		assert_eq!(looped.slice_freq, noise.slice_freq);
+		assert!(looped.freq > 2 * noise.freq, "{looped:?} against {noise:?}");
+		assert!(looped.freq > 10 * looped.slice_freq, "{looped:?}");
+	}
+
+	#[test]
+	fn noisy_mixes_are_placed_within_a_slice() {
+		let config = DatabaseConfig::builder().build();
+		let (db, songs) = noise_database(config);
+		let noise = encoder::Song::pink_noise(Duration::from_secs(4), 44100, 7);
+		let duration = Duration::from_secs(4);
+		for offset in [0, 1_500, 3_250, 5_900].map(Duration::from_millis) {
+			let sample = encoder::Song::mix(
+				&songs[2],
+				&noise,
+				10.,
+				offset,
+				duration,
+				encoder::ShortNoise::Reject,
+			)
+			.unwrap();
+			let best = rank_matches(db.match_sample(&sample), 1, 0.)[0];
+			assert_eq!(best.id, 2);
+			assert!(
+				best.offset.abs_diff(offset) <= config.slice_size(),
+				"{best:?} for {offset:?}"
+			);
+			let (start, end) = best.matched_span.unwrap();
+			assert!(start <= end && end <= duration, "{best:?}");
+		}
+	}
+
+	#[test]
+	fn sessions_fed_in_chunks_match_like_the_whole_sample() {
+		let config = DatabaseConfig::builder().build();
+		let (db, songs) = noise_database(config);
+		let noise = encoder::Song::pink_noise(Duration::from_secs(5), 44100, 5);
+		let sample = encoder::Song::mix(
+			&songs[1],
+			&noise,
+			5.,
+			Duration::from_millis(3300),
+			Duration::from_secs(5),
+			encoder::ShortNoise::Reject,
+		)
+		.unwrap();
+		let expected = db.best_matches(&sample, 3, f32::NEG_INFINITY);
+		assert_eq!(expected[0].id, 1);
+		let (window, hop) =
+			encoder::slice_samples(44100, config.slice_size(), config.constellation().hop_size);
+		let pcm = sample.to_pcm_f32();
+		for chunk_size in [1, 1000, hop - 1, hop, hop + 1, window + 7, pcm.len()] {
+			let mut session = MatchSession::new(&db);
+			for chunk in pcm.chunks(chunk_size) {
+				session.push_samples(chunk);
+			}
+			assert_eq!(session.finish(3), expected, "in chunks of {chunk_size}");
+		}
+	}
+
+	#[test]
+	fn samples_up_to_a_slice_long_have_no_matches() {
+		let config = DatabaseConfig::builder().build();
+		let (db, songs) = noise_database(config);
+		let (window, _) =
+			encoder::slice_samples(44100, config.slice_size(), config.constellation().hop_size);
+		for len in [0, window - 1, window] {
+			let sample = encoder::Song::from_pcm_i16(&songs[0].samples[..len], 44100);
+			assert_eq!(config.is_too_short(&sample), len < window, "{len} samples");
+			// A single slice is fingerprinted, but has no other to pair its peaks with
+			assert_eq!(config.signatures(&sample).count(), 0, "{len} samples");
+			assert!(db.match_sample(&sample).is_empty(), "{len} samples");
+		}
+	}
+
+	#[test]
+	fn load_rejects_another_config() {
+		let (db, _) = noise_database(DatabaseConfig::builder().build());
+		let path = temp_path("other-config.db");
+		db.save(&path).unwrap();
+		let other = DatabaseConfig::builder().with_freq_per_slice(4).build();
+		let loaded = Database::load(&path, &other);
+		let _ = std::fs::remove_file(&path);
+		assert!(matches!(loaded, Err(DatabaseLoadError::ConfigMismatch(_))));
+	}
+
+	#[test]
+	fn load_rejects_truncated_files() {
+		let config = DatabaseConfig::builder().build();
+		let (db, _) = noise_database(config);
+		let bytes = db.to_bytes();
+		let path = temp_path("truncated.db");
+		for len in [0, 8, bytes.len() / 2, bytes.len() - 1] {
+			std::fs::write(&path, &bytes[..len]).unwrap();
+			assert!(
+				Database::load(&path, &config).is_err(),
+				"{len} of {} bytes loaded",
+				bytes.len()
+			);
+		}
+		let _ = std::fs::remove_file(&path);
+	}
+
+	/// The song `sample` is best matched with and where
+	fn best(db: &Database, sample: &encoder::Song) -> (SongId, Duration) {
+		let best = rank_matches(db.match_sample(sample), 1, 0.)[0];
+		(best.id, best.offset)
+	}
+
+	#[test]
+	fn songs_added_after_building_match_like_the_rest() {
+		let config = DatabaseConfig::builder().build();
+		let (mut db, mut songs) = noise_database(config);
+		db.remove_song(2);
+		let song = encoder::Song::white_noise(Duration::from_secs(10), 44100, 3);
+		let wav = encoder::Song::to_wav(song.clone(), encoder::WavOptions::default());
+		let id = db.add_song_from_wav("noise_3.wav".into(), wav).unwrap();
+		assert_eq!(id, 3);
+		songs[2] = song;
+		for (song, id) in songs.iter().zip([0, 1, 3]) {
+			let sample = song.slice(Duration::from_secs(4), Duration::from_secs(5));
+			assert_eq!(best(&db, &sample).0, id);
+		}
+		db.finalize();
+		let sample = songs[2].slice(Duration::from_secs(4), Duration::from_secs(5));
+		assert_eq!(best(&db, &sample).0, 3);
+	}
+
+	#[test]
+	fn removed_songs_never_match_again() {
+		let config = DatabaseConfig::builder().build();
+		let (mut db, songs) = noise_database(config);
+		// A noisy copy of song 1, close behind it
+		let noise = encoder::Song::white_noise(Duration::from_secs(10), 44100, 4);
+		let copy = encoder::Song::mix(
+			&songs[1],
+			&noise,
+			0.,
+			Duration::ZERO,
+			Duration::from_secs(10),
+			encoder::ShortNoise::Reject,
+		)
+		.unwrap();
+		let copy_id = db.add_song(
+			"copy.wav".into(),
+			config.song_data("copy.wav".as_ref(), &copy),
+		);
+		let sample = songs[1].slice(Duration::from_secs(3), Duration::from_secs(5));
+		let ranked: Vec<_> = rank_matches(db.match_sample(&sample), 2, 0.)
+			.iter()
+			.map(|found| found.id)
+			.collect();
+		ranked
+			.iter()
+			.for_each(|id| assert!([1, copy_id].contains(id)));
+		let (first, runner_up) = (ranked[0], ranked[1]);
+		assert!(db.remove_song(first).is_some());
+		assert_eq!(db.remove_song(first), None);
+		assert_eq!(best(&db, &sample).0, runner_up);
+		assert!(db
+			.match_sample(&sample)
+			.iter()
+			.all(|found| found.id != first));
+		// Other ids are left as they were
+		assert_eq!(db.song_id("noise_2.wav"), Some(2));
+		let loaded = Database::from_bytes(&db.to_bytes()).unwrap();
+		assert!(loaded
+			.match_sample(&sample)
+			.iter()
+			.all(|found| found.id != first));
+	}
+
+	#[test]
+	fn merged_databases_match_the_songs_of_both() {
+		let config = DatabaseConfig::builder().build();
+		let shard = |seed| {
+			let mut db = Database::new(config);
+			let song = encoder::Song::white_noise(Duration::from_secs(10), 44100, seed);
+			let name = OsString::from(format!("noise_{seed}.wav"));
+			db.add_song(name.clone(), config.song_data(&name, &song));
+			db.finalize();
+			(db, song)
+		};
+		let ((a, a_song), (b, b_song)) = (shard(0), shard(1));
+		let merged = a.merge(b, DuplicatePolicy::Error).unwrap();
+		for (song, id) in [(a_song, 0), (b_song, 1)] {
+			let sample = song.slice(Duration::from_secs(2), Duration::from_secs(5));
+			let (found, offset) = best(&merged, &sample);
+			assert_eq!(found, id);
+			assert!(offset.abs_diff(Duration::from_secs(2)) <= config.slice_size());
+		}
+		assert_eq!(merged.song_id("noise_1.wav"), Some(1));
+		let twice = shard(0).0.merge(shard(0).0, DuplicatePolicy::Error);
+		assert!(matches!(twice, Err(MergeError::DuplicateSong(name)) if name == "noise_0.wav"));
+	}
+
+	#[test]
+	fn merge_rejects_another_config() {
+		let db = Database::new(DatabaseConfig::builder().build());
+		let other = Database::new(DatabaseConfig::builder().with_freq_per_slice(4).build());
+		assert!(matches!(
+			db.merge(other, DuplicatePolicy::KeepBoth),
+			Err(MergeError::ConfigMismatch(config)) if config.constellation().freq_per_slice == 4
+		));
+	}
+
+	#[test]
+	fn builder_skips_the_same_file_added_twice() {
+		let config = DatabaseConfig::builder().build();
+		let songs_dir = temp_path("duplicates");
+		std::fs::create_dir_all(&songs_dir).unwrap();
+		let song = encoder::Song::white_noise(Duration::from_secs(2), 44100, 0);
+		std::fs::write(
+			songs_dir.join("a.wav"),
+			encoder::Song::to_wav(song, encoder::WavOptions::default()),
+		)
+		.unwrap();
+		let add_twice = |builder: &mut DatabaseBuilder| {
+			[builder.add_song("a.wav"), builder.add_song("./a.wav")]
+				.map(|status| status.expect("the song was just written"))
+		};
+		let mut builder = DatabaseBuilder::new(config, songs_dir.as_path(), None::<&Path>);
+		let statuses = add_twice(&mut builder);
+		let (db, errors) = builder.build(config);
+		let mut allowing = DatabaseBuilder::new(config, songs_dir.as_path(), None::<&Path>)
+			.with_duplicates_allowed(true);
+		let allowed_statuses = add_twice(&mut allowing);
+		let (allowing_db, _) = allowing.build(config);
+		let _ = std::fs::remove_dir_all(&songs_dir);
+		assert_eq!(statuses, [CacheStatus::Miss, CacheStatus::Duplicate]);
+		assert!(errors.is_empty());
+		assert_eq!(db.song_paths.len(), 1);
+		assert_eq!(allowed_statuses, [CacheStatus::Miss; 2]);
+		assert_eq!(allowing_db.song_paths.len(), 2);
+	}
+
+	#[test]
+	fn builder_rejects_directories_and_leaves_out_unreadable_songs() {
+		let config = DatabaseConfig::builder().build();
+		let songs_dir = temp_path("unreadable");
+		std::fs::create_dir_all(songs_dir.join("album.wav")).unwrap();
+		let song = encoder::Song::white_noise(Duration::from_secs(2), 44100, 0);
+		let wav = encoder::Song::to_wav(song, encoder::WavOptions::default());
+		for name in ["good.wav", "gone.wav"] {
+			std::fs::write(songs_dir.join(name), &wav).unwrap();
+		}
+		std::fs::write(songs_dir.join("garbage.wav"), b"RIFF and nothing else").unwrap();
+		let mut builder = DatabaseBuilder::new(config, songs_dir.as_path(), None::<&Path>);
+		let directory = builder.add_song("album.wav");
+		let missing = builder.add_song("missing.wav");
+		let statuses = ["good.wav", "gone.wav", "garbage.wav"].map(|name| builder.add_song(name));
+		// Deleted after it was added, so reading it fails while building
+		std::fs::remove_file(songs_dir.join("gone.wav")).unwrap();
+		let (db, errors) = builder.build(config);
+		let _ = std::fs::remove_dir_all(&songs_dir);
+		assert!(matches!(directory, Err(AddSongError::NotAFile)));
+		assert!(matches!(missing, Err(AddSongError::NotFound)));
+		assert!(statuses
+			.iter()
+			.all(|status| matches!(status, Ok(CacheStatus::Miss))));
+		let mut failed: Vec<_> = errors
+			.iter()
+			.map(|err| (err.path.strip_prefix(&songs_dir).unwrap(), &err.reason))
+			.collect();
+		failed.sort_by_key(|&(path, _)| path);
+		assert!(
+			matches!(
+				failed[..],
+				[
+					(garbage, DecodeError::Corrupt(_)),
+					(gone, DecodeError::Io(_)),
+				] if garbage == Path::new("garbage.wav") && gone == Path::new("gone.wav")
+			),
+			"{failed:?}"
+		);
+		let names: Vec<_> = db.song_ids().map(|id| db.song_name(id).unwrap()).collect();
+		assert_eq!(names, ["good.wav"]);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn song_names_of_unknown_ids_and_non_utf8_paths() {
+		use std::os::unix::ffi::OsStringExt;
+		let mut db = Database::new(DatabaseConfig::builder().build());
+		let name = OsString::from_vec(b"caf\xe9.wav".to_vec());
+		let id = db.add_song(name.clone(), SongData(Vec::new()));
+		let other = db.add_song("other.wav".into(), SongData(Vec::new()));
+		db.finalize();
+		assert_eq!(db.song_name(id).as_deref(), Some("caf\u{fffd}.wav"));
+		assert_eq!(db.song_path(id), Some(name.as_os_str()));
+		assert_eq!(db.song_name(other + 1), None);
+		assert_eq!(db.song_path(other + 1), None);
+		// The exact name survives saving
+		let loaded = Database::from_bytes(&db.to_bytes()).unwrap();
+		assert_eq!(loaded.song_path(id), Some(name.as_os_str()));
+		db.remove_song(id);
+		assert_eq!(db.song_name(id), None);
+	}
+
+	/// The error building the default config changed by `change` fails with
+	fn config_error(
+		change: impl FnOnce(DatabaseConfigBuilder) -> DatabaseConfigBuilder,
+	) -> ConfigError {
+		change(DatabaseConfig::builder())
+			.try_build()
+			.expect_err("the config is invalid")
+	}
+
+	#[test]
+	fn default_config_is_valid() {
+		assert!(DatabaseConfig::builder().try_build().is_ok());
+	}
+
+	#[test]
+	fn rejects_fft_size_not_power_of_two() {
+		assert_eq!(
+			config_error(|builder| builder.with_fft_size(10000)),
+			ConfigError::FftSizeNotPowerOfTwo(10000)
+		);
+	}
+
+	#[test]
+	fn rejects_slice_too_short() {
+		assert_eq!(
+			config_error(|builder| builder.with_slice_size(Duration::from_millis(5))),
+			ConfigError::SliceTooShort(Duration::from_millis(5))
+		);
+	}
+
+	#[test]
+	fn rejects_slice_exceeding_fft() {
+		assert_eq!(
+			config_error(|builder| builder.with_fft_size(8192)),
+			ConfigError::SliceExceedsFft {
+				slice_samples: 11466,
+				fft_size: 8192,
+			}
+		);
+	}
+
+	#[test]
+	fn rejects_hop_too_short() {
+		assert_eq!(
+			config_error(|builder| builder.with_hop_size(Duration::ZERO)),
+			ConfigError::HopTooShort
+		);
+	}
+
+	#[test]
+	fn rejects_no_buckets() {
+		assert_eq!(
+			config_error(|builder| builder.with_bucket_count(0)),
+			ConfigError::NoBuckets
+		);
+	}
+
+	#[test]
+	fn rejects_more_peaks_than_buckets() {
+		assert_eq!(
+			config_error(|builder| builder.with_bucket_count(4)),
+			ConfigError::MorePeaksThanBuckets {
+				freq_per_slice: 8,
+				bucket_count: 4,
+			}
+		);
+	}
+
+	#[test]
+	fn rejects_buckets_above_nyquist() {
+		assert_eq!(
+			config_error(|builder| builder.with_bucket_count(200)),
+			ConfigError::AboveNyquist {
+				top_freq: 36100,
+				sample_rate: 44100,
+			}
+		);
+	}
+
+	#[test]
+	fn rejects_target_zone_too_narrow() {
+		assert_eq!(
+			config_error(|builder| builder.with_target_zone_width(Duration::from_millis(130))),
+			ConfigError::TargetZoneTooNarrow {
+				width: Duration::from_millis(130),
+				hop_size: Duration::from_millis(130),
+			}
+		);
+	}
+
+	#[test]
+	fn rejects_target_zone_offset_zero() {
+		assert_eq!(
+			config_error(|builder| builder.with_target_zone_offset(0)),
+			ConfigError::TargetZoneOffsetZero
+		);
+	}
+
+	#[test]
+	fn rejects_target_zone_offset_past_zone() {
+		assert_eq!(
+			config_error(|builder| builder.with_target_zone_offset(10)),
+			ConfigError::TargetZoneOffsetPastZone {
+				offset: 10,
+				width: 10,
+			}
+		);
+		assert!(DatabaseConfig::builder()
+			.with_target_zone_offset(9)
+			.try_build()
+			.is_ok());
+	}
+
+	#[test]
+	fn rejects_target_zone_too_low() {
+		assert_eq!(
+			config_error(|builder| builder.with_target_zone_height(100)),
+			ConfigError::TargetZoneTooLow {
+				height: 100,
+				bucket_size: 180,
+			}
+		);
 	}
 }
@@ -1,169 +1,2059 @@
-//! Handles conversion of a WAV file on disk into a vector of Frequency signatures
+//! Handles conversion of an audio file on disk into a vector of Frequency signatures
 
-use std::hash::Hash;
+use std::{
+	cell::RefCell,
+	collections::VecDeque,
+	hash::Hash,
+	io::{BufRead, Read},
+	path::Path,
+};
 
-use easyfft::prelude::*;
+use rayon::prelude::*;
+use realfft::{num_complex::Complex, RealFftPlanner};
 use serde::{Deserialize, Serialize};
+use symphonia::core::{
+	audio::SampleBuffer,
+	codecs::DecoderOptions,
+	errors::Error as SymphoniaError,
+	formats::{FormatOptions, FormatReader, Packet},
+	io::MediaSourceStream,
+	meta::MetadataOptions,
+	probe::Hint,
+};
 
+pub mod filters;
+
+thread_local! {
+	/// Plans are cached per thread, so twiddle factors are computed once per FFT length
+	static FFT_PLANNER: RefCell<RealFftPlanner<f32>> = RefCell::new(RealFftPlanner::new());
+}
+
+/// Input, output and scratch buffers for one real FFT
+type FftBuffers = (Vec<f32>, Vec<Complex<f32>>, Vec<Complex<f32>>);
+
+/// Slices are fingerprinted in parallel in runs of at least this many, so short
+/// samples stay on the calling thread instead of paying for the hand off
+const MIN_SLICES_PER_JOB: usize = 32;
+
+/// Frequency in Hz
 pub type Freq = u16;
+/// Index of a slice, counting from the start of the song
 pub type TimeStamp = u32;
 
+/// A pair of peaks, the anchor's and the target's frequency along with the slices between
+/// them
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Signature((Freq, Freq), TimeStamp);
 
+/// A `Signature` packed into a single integer, see `Signature::pack`
+pub type PackedSignature = u64;
+
+impl Signature {
+	/// Packs the target frequency, anchor frequency and time delta from the lowest bits
+	/// up, 16, 16 and 32 bits wide. That fits every `Freq` and `TimeStamp`, so nothing is
+	/// quantized and `unpack` gives back the same signature.
+	///
+	/// The frequencies go in the low bits as `FxHasher` only mixes bits upwards, and the
+	/// handful of distinct time deltas would otherwise pick the hash map bucket on
+	/// their own
+	pub fn pack(self) -> PackedSignature {
+		let Signature((anchor_freq, target_freq), time_delta) = self;
+		(time_delta as u64) << 32 | (anchor_freq as u64) << 16 | target_freq as u64
+	}
+	/// Reverses `pack`
+	pub fn unpack(packed: PackedSignature) -> Signature {
+		Signature(
+			((packed >> 16) as Freq, packed as Freq),
+			(packed >> 32) as TimeStamp,
+		)
+	}
+}
+
+/// Why a song couldn't be decoded
+#[derive(Debug)]
+pub enum DecodeError {
+	/// The file could not be read
+	Io(std::io::Error),
+	/// The file isn't in any of the audio formats we know about
+	UnsupportedFormat,
+	/// The input ended before a declared chunk or field was complete
+	Truncated,
+	/// The input is malformed
+	Corrupt(&'static str),
+	/// The input is well formed but uses an encoding we can't decode
+	Unsupported(String),
+}
+impl std::fmt::Display for DecodeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Io(err) => write!(f, "io error, {err}"),
+			Self::UnsupportedFormat => write!(f, "not a recognized audio file"),
+			Self::Truncated => write!(f, "file is truncated"),
+			Self::Corrupt(reason) => write!(f, "corrupt file, {reason}"),
+			Self::Unsupported(format) => write!(f, "unsupported encoding, {format}"),
+		}
+	}
+}
+impl std::error::Error for DecodeError {}
+impl From<std::io::Error> for DecodeError {
+	fn from(err: std::io::Error) -> Self {
+		Self::Io(err)
+	}
+}
+impl From<SymphoniaError> for DecodeError {
+	fn from(err: SymphoniaError) -> Self {
+		match err {
+			// Input is always fully in memory, so running out of bytes means truncation
+			SymphoniaError::IoError(_) => Self::Truncated,
+			SymphoniaError::DecodeError(reason) => Self::Corrupt(reason),
+			SymphoniaError::Unsupported(feature) => Self::Unsupported(feature.to_string()),
+			SymphoniaError::LimitError(limit) => Self::Unsupported(limit.to_string()),
+			SymphoniaError::SeekError(_) | SymphoniaError::ResetRequired => {
+				Self::Corrupt("stream changed parameters mid-way")
+			}
+		}
+	}
+}
+
+/// Why two songs couldn't be mixed at a signal to noise ratio
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MixError {
+	/// The segment of the signal is silent, so no amount of noise gives the ratio
+	SilentSignal,
+	/// The noise is silent, so it can't be scaled to the ratio
+	SilentNoise,
+	/// The noise is shorter than the segment it's mixed into, and `ShortNoise::Reject`
+	/// was asked for
+	NoiseTooShort {
+		/// Length of the noise
+		noise: std::time::Duration,
+		/// Length of the segment
+		segment: std::time::Duration,
+	},
+}
+impl std::fmt::Display for MixError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::SilentSignal => write!(f, "the signal is silent"),
+			Self::SilentNoise => write!(f, "the noise is silent"),
+			Self::NoiseTooShort { noise, segment } => {
+				write!(
+					f,
+					"the noise is only {noise:?} long, the segment is {segment:?}"
+				)
+			}
+		}
+	}
+}
+impl std::error::Error for MixError {}
+
+/// What `Song::mix` does with noise shorter than the segment it's mixed into
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ShortNoise {
+	/// Repeat the noise from its start for as long as the segment lasts
+	#[default]
+	Loop,
+	/// Fail with `MixError::NoiseTooShort`
+	Reject,
+}
+
+/// Stored amplitude of 1.0 on the ±1.0 scale, that of 16-bit PCM. Songs keep their
+/// samples as i16 rather than as floats in ±1.0 to halve their memory, so the floats
+/// decoders, `from_pcm_f32` and `to_pcm_f32` deal in are scaled by this at the edges
+const I16_SCALE: f32 = 32768.;
+
+/// Length of the windows `Song::audible_range` measures loudness over
+const SILENCE_WINDOW: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Length of the `data` chunk of a WAV file written to a pipe, which runs to the end
+/// of the stream instead
+const STREAMED_DATA_SIZE: usize = u32::MAX as usize;
+
+/// Format tag of a `fmt ` chunk whose extension names the actual format in a GUID
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// The sub-format GUIDs of `WAVE_FORMAT_EXTENSIBLE` are the plain format tag followed
+/// by these bytes
+const EXTENSIBLE_GUID_SUFFIX: [u8; 14] = [
+	0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+
+/// Contents of the `fmt ` chunk of a WAV file
+#[derive(Debug, Clone, Copy)]
+struct WavFormat {
+	format_tag: u16,
+	channel_count: u16,
+	sample_rate: u32,
+	bits_per_sample: u16,
+}
+
+/// Reads exactly `buffer.len()` bytes, reporting a premature EOF as truncation
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), DecodeError> {
+	reader.read_exact(buffer).map_err(|err| match err.kind() {
+		std::io::ErrorKind::UnexpectedEof => DecodeError::Truncated,
+		_ => DecodeError::Io(err),
+	})
+}
+
+/// Reads until `buffer` is full or the reader runs out, returning the bytes read
+fn read_to_fill<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize, DecodeError> {
+	let mut read = 0;
+	while read < buffer.len() {
+		match reader.read(&mut buffer[read..]) {
+			Ok(0) => break,
+			Ok(n) => read += n,
+			Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+			Err(err) => return Err(DecodeError::Io(err)),
+		}
+	}
+	Ok(read)
+}
+
+/// Walks the RIFF chunk structure up to the start of the `data` chunk, returning
+/// the parsed `fmt ` chunk and the declared length of the `data` chunk.
+/// Unknown chunks are skipped.
+fn parse_riff_header<R: Read>(reader: &mut R) -> Result<(WavFormat, usize), DecodeError> {
+	let mut header = [0u8; 12];
+	read_exact(reader, &mut header)?;
+	if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+		return Err(DecodeError::Corrupt("missing RIFF/WAVE header"));
+	}
+	let mut format = None;
+	loop {
+		let mut chunk_header = Vec::with_capacity(8);
+		match reader.by_ref().take(8).read_to_end(&mut chunk_header)? {
+			0 => return Err(DecodeError::Corrupt("missing `data` chunk")),
+			8 => {}
+			_ => return Err(DecodeError::Truncated),
+		}
+		let size = u32::from_le_bytes([
+			chunk_header[4],
+			chunk_header[5],
+			chunk_header[6],
+			chunk_header[7],
+		]) as usize;
+		match &chunk_header[0..4] {
+			b"fmt " => {
+				if size < 16 {
+					return Err(DecodeError::Corrupt("`fmt ` chunk is too short"));
+				}
+				let mut body = [0u8; 16];
+				read_exact(reader, &mut body)?;
+				let word = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+				let mut parsed = WavFormat {
+					format_tag: word(0),
+					channel_count: word(2),
+					sample_rate: u32::from_le_bytes([body[4], body[5], body[6], body[7]]),
+					bits_per_sample: word(14),
+				};
+				if parsed.channel_count == 0 {
+					return Err(DecodeError::Corrupt("`fmt ` chunk declares zero channels"));
+				}
+				if parsed.sample_rate == 0 {
+					return Err(DecodeError::Corrupt(
+						"`fmt ` chunk declares a zero sample rate",
+					));
+				}
+				let mut read = 16;
+				if parsed.format_tag == WAVE_FORMAT_EXTENSIBLE {
+					// The extension's size, valid bits per sample and speaker mask come
+					// before the sub-format, the container size still decides the layout
+					if size < 40 {
+						return Err(DecodeError::Corrupt(
+							"`fmt ` chunk is too short for WAVE_FORMAT_EXTENSIBLE",
+						));
+					}
+					let mut extension = [0u8; 24];
+					read_exact(reader, &mut extension)?;
+					read += extension.len();
+					let guid = &extension[8..];
+					if guid[2..] != EXTENSIBLE_GUID_SUFFIX {
+						return Err(DecodeError::Unsupported(format!(
+							"WAVE_FORMAT_EXTENSIBLE sub-format {guid:02x?}"
+						)));
+					}
+					parsed.format_tag = u16::from_le_bytes([guid[0], guid[1]]);
+				}
+				format = Some(parsed);
+				skip_chunk(reader, size - read, size)?;
+			}
+			b"data" => {
+				let format = format.ok_or(DecodeError::Corrupt("`data` chunk before `fmt `"))?;
+				return Ok((format, size));
+			}
+			_ => skip_chunk(reader, size, size)?,
+		}
+	}
+}
+
+/// Discards the `remaining` bytes of a chunk of `size` bytes, along with the padding
+/// byte that keeps chunks at an even length
+fn skip_chunk<R: Read>(reader: &mut R, remaining: usize, size: usize) -> Result<(), DecodeError> {
+	let padded = (remaining + size % 2) as u64;
+	let skipped = std::io::copy(&mut reader.by_ref().take(padded), &mut std::io::sink())?;
+	// A missing padding byte at the very end of the file is harmless
+	if skipped < remaining as u64 {
+		return Err(DecodeError::Truncated);
+	}
+	Ok(())
+}
+
+/// Returns the next packet belonging to `track_id`, or `None` at the end of the stream
+fn next_packet(
+	format: &mut dyn FormatReader,
+	track_id: u32,
+) -> Result<Option<Packet>, DecodeError> {
+	loop {
+		match format.next_packet() {
+			Ok(packet) if packet.track_id() == track_id => return Ok(Some(packet)),
+			Ok(_) => {}
+			// End of stream is reported as an EOF io error
+			Err(SymphoniaError::IoError(err))
+				if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+			{
+				return Ok(None)
+			}
+			Err(err) => return Err(err.into()),
+		}
+	}
+}
+
+/// Decodes a compressed audio stream with symphonia, `extension` is
+/// a hint for picking the container format
+fn decode_compressed(byte_array: Vec<u8>, extension: &str) -> Result<Song, DecodeError> {
+	let source = MediaSourceStream::new(
+		Box::new(std::io::Cursor::new(byte_array)),
+		Default::default(),
+	);
+	let mut hint = Hint::new();
+	hint.with_extension(extension);
+	let mut format = symphonia::default::get_probe()
+		.format(
+			&hint,
+			source,
+			&FormatOptions::default(),
+			&MetadataOptions::default(),
+		)?
+		.format;
+	let track = format
+		.default_track()
+		.ok_or(DecodeError::Corrupt("no audio track"))?;
+	let track_id = track.id;
+	#[cfg(feature = "opus")]
+	if track.codec_params.codec == symphonia::core::codecs::CODEC_TYPE_OPUS {
+		// The pre-skip count lives in the `OpusHead` identification header
+		let pre_skip = match track.codec_params.extra_data.as_deref() {
+			Some([b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', _, _, lsb, msb, ..]) => {
+				u16::from_le_bytes([*lsb, *msb]) as usize
+			}
+			_ => return Err(DecodeError::Corrupt("missing `OpusHead` header")),
+		};
+		return decode_opus(&mut *format, track_id, pre_skip);
+	}
+	let sample_rate = track
+		.codec_params
+		.sample_rate
+		.ok_or(DecodeError::Corrupt("unknown sample rate"))?;
+	let mut decoder =
+		symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+	let mut samples = Vec::new();
+	while let Some(packet) = next_packet(&mut *format, track_id)? {
+		let decoded = decoder.decode(&packet)?;
+		let spec = *decoded.spec();
+		let channel_count = spec.channels.count();
+		let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+		buffer.copy_interleaved_ref(decoded);
+		// Interleaved frames are averaged across channels into a single mono stream
+		samples.extend(
+			buffer.samples().chunks_exact(channel_count).map(|frame| {
+				quantize(frame.iter().sum::<f32>() / channel_count as f32 * I16_SCALE)
+			}),
+		);
+	}
+	Ok(Song {
+		sample_rate: sample_rate as usize,
+		samples,
+	})
+}
+
+/// Opus always decodes at 48 kHz, whatever the rate of the original recording was
+#[cfg(feature = "opus")]
+const OPUS_SAMPLE_RATE: u32 = 48000;
+
+/// Symphonia can demux Ogg/Opus but has no Opus decoder, so packets are handed to libopus
+#[cfg(feature = "opus")]
+fn decode_opus(
+	format: &mut dyn FormatReader,
+	track_id: u32,
+	pre_skip: usize,
+) -> Result<Song, DecodeError> {
+	// libopus downmixes to mono itself when asked for a single channel
+	let mut decoder = opus::Decoder::new(OPUS_SAMPLE_RATE, opus::Channels::Mono)
+		.map_err(|err| DecodeError::Unsupported(err.to_string()))?;
+	// 120 ms is the longest frame a single Opus packet can hold
+	let mut buffer = vec![0f32; OPUS_SAMPLE_RATE as usize * 120 / 1000];
+	let mut samples = Vec::new();
+	while let Some(packet) = next_packet(format, track_id)? {
+		let sample_count = decoder
+			.decode_float(packet.buf(), &mut buffer, false)
+			.map_err(|_| DecodeError::Corrupt("invalid Opus packet"))?;
+		samples.extend(
+			buffer[..sample_count]
+				.iter()
+				.map(|i| quantize(i * I16_SCALE)),
+		);
+	}
+	Ok(Song {
+		sample_rate: OPUS_SAMPLE_RATE as usize,
+		samples: samples.split_off(pre_skip.min(samples.len())),
+	})
+}
+
+/// How samples pushed outside the i16 range by normalizing or dithering are written by
+/// `Song::to_wav`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Clipping {
+	/// Clamp to `i16::MIN..=i16::MAX`
+	#[default]
+	Saturate,
+	/// Keep the low 16 bits, which is only useful for reproducing old test files
+	Wrap,
+}
+
+/// How `Song::to_wav` quantizes a song
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WavOptions {
+	/// What samples exceeding 16-bit full scale are turned into
+	pub clipping: Clipping,
+	/// Scale the song so its peak lands at this level in dBFS
+	pub normalize_peak: Option<f32>,
+	/// Add triangular dither before quantizing to i16
+	pub dither: bool,
+}
+
+/// Root mean square of `samples`, NaN if there are none
+fn rms<T: Copy + Into<f64>>(samples: &[T]) -> f64 {
+	let square_sum = samples.iter().map(|&s| s.into() * s.into()).sum::<f64>();
+	(square_sum / samples.len() as f64).sqrt()
+}
+
+/// `sample`, in the range of 16-bit PCM, rounded to the nearest i16 and saturated at
+/// full scale
+fn quantize(sample: f32) -> i16 {
+	// `as` truncates and saturates, which is much cheaper than calling `round` first
+	(sample + 0.5f32.copysign(sample)) as i16
+}
+
+/// Pseudo random numbers, seeded so that every `seed` gives a distinct sequence
+pub(crate) fn xorshift(seed: u64) -> impl FnMut() -> u64 {
+	let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+	move || {
+		state ^= state << 13;
+		state ^= state >> 7;
+		state ^= state << 17;
+		state
+	}
+}
+
+/// Triangular (TPDF) dither of ±1 LSB, driven by a xorshift generator so the output
+/// is reproducible
+struct Dither(u32);
+impl Default for Dither {
+	fn default() -> Self {
+		Self(0x9e37_79b9)
+	}
+}
+impl Dither {
+	fn uniform(&mut self) -> f32 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 17;
+		self.0 ^= self.0 << 5;
+		self.0 as f32 / u32::MAX as f32
+	}
+	fn sample(&mut self) -> f32 {
+		self.uniform() - self.uniform()
+	}
+}
+
+/// Window function applied to each time slice before the FFT
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Window {
+	/// Leaves the slice as is
+	Rectangular,
+	/// Tapers the slice to zero at both ends, reducing spectral leakage between buckets
+	Hann,
+}
+impl Window {
+	fn coefficients(self, len: usize) -> Vec<f32> {
+		match self {
+			Window::Rectangular => vec![1.; len],
+			Window::Hann => (0..len)
+				.map(|i| {
+					let phase = std::f32::consts::TAU * i as f32 / (len.max(2) - 1) as f32;
+					0.5 - 0.5 * phase.cos()
+				})
+				.collect(),
+		}
+	}
+}
+
+/// How the analysed frequency range is divided into buckets, each contributing at
+/// most one peak per slice
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Bucketing {
+	/// Every bucket spans `bucket_size` Hz
+	Linear,
+	/// The first bucket spans `bucket_size` Hz, the rest are spaced exponentially up to
+	/// the top of the range, giving the low end where melodies live more buckets
+	#[cfg_attr(feature = "cli", value(name = "log"))]
+	Logarithmic,
+}
+impl Bucketing {
+	/// Returns the `bucket_count + 1` edges of the buckets, in Hz
+	fn band_edges(self, bucket_size: Freq, bucket_count: usize) -> Vec<usize> {
+		let bucket_size = bucket_size as usize;
+		match self {
+			Bucketing::Linear => (0..=bucket_count).map(|i| i * bucket_size).collect(),
+			Bucketing::Logarithmic => {
+				let (low, high) = (bucket_size as f64, (bucket_size * bucket_count) as f64);
+				let ratio = (high / low).powf(1. / bucket_count.saturating_sub(1).max(1) as f64);
+				let mut edges = vec![0];
+				for i in 0..bucket_count {
+					let edge = (low * ratio.powi(i as i32)).round() as usize;
+					// Every bucket must hold at least one frequency
+					edges.push(edge.max(edges[i] + 1));
+				}
+				edges
+			}
+		}
+	}
+}
+
+/// How the peaks of each slice's spectrum are picked
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum PeakPicking {
+	/// The loudest frequency of every bucket
+	Bucket,
+	/// Frequencies louder than everything around them in both time and frequency,
+	/// which favours short transients over sustained tones
+	LocalMaxima,
+}
+
+/// An `f32` that can be part of a hashed config, compared bit for bit
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HashableF32(pub f32);
+impl Hash for HashableF32 {
+	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+		self.0.to_bits().hash(state)
+	}
+}
+
+/// Parameters controlling how a song is reduced to its constellation map
+#[derive(Debug, Clone, Copy, Hash, Serialize, Deserialize)]
+pub struct ConstellationConfig {
+	/// Length of every slice
+	pub slice_size: std::time::Duration,
+	/// Length of the FFT run on every slice, a power of two no shorter than a slice.
+	/// Slices are zero padded up to it, and each bin spans `sample_rate / fft_size` Hz
+	pub fft_size: usize,
+	/// Time between the starts of consecutive slices, the unit of every `TimeStamp`
+	pub hop_size: std::time::Duration,
+	/// Most peaks kept from every slice
+	pub freq_per_slice: usize,
+	/// Width of every bucket in Hz
+	pub bucket_size: Freq,
+	/// Number of buckets the frequency range is split into
+	pub bucket_count: usize,
+	/// Frequencies below this are ignored, keeping DC offset and rumble out of the peaks
+	pub min_freq: Freq,
+	/// Window applied to every slice before its FFT
+	pub window: Window,
+	/// How the buckets split the frequency range
+	pub bucketing: Bucketing,
+	/// A peak is only kept if its amplitude exceeds this multiple of the slice's mean
+	/// amplitude, so slices may yield fewer than `freq_per_slice` peaks
+	pub noise_floor: HashableF32,
+	/// Width in Hz of the neighbourhood each amplitude is normalized against before
+	/// picking peaks, so quieter but distinctive frequencies aren't drowned out by the
+	/// loudest instrument. 0 disables whitening
+	pub whitening_width: Freq,
+	/// How peaks are picked from every slice
+	pub peak_picking: PeakPicking,
+	/// How many slices and Hz either side a peak must dominate, only used by
+	/// `PeakPicking::LocalMaxima`
+	pub peak_neighbourhood: (usize, Freq),
+}
+
+impl ConstellationConfig {
+	/// Slices either side of a slice whose spectra its peaks depend on
+	pub fn peak_time_radius(&self) -> usize {
+		match self.peak_picking {
+			PeakPicking::Bucket => 0,
+			PeakPicking::LocalMaxima => self.peak_neighbourhood.0,
+		}
+	}
+}
+
+/// Samples in a slice of `slice_size`, and between the starts of slices `hop_size` apart
+pub fn slice_samples(
+	sample_rate: usize,
+	slice_size: std::time::Duration,
+	hop_size: std::time::Duration,
+) -> (usize, usize) {
+	(
+		sample_rate * slice_size.as_millis() as usize / 1000,
+		(sample_rate * hop_size.as_millis() as usize / 1000).max(1),
+	)
+}
+
+/// Divides every amplitude by the mean amplitude of the `width` bins centered on it
+fn whiten(amplitudes: &mut [f32], width: usize) {
+	let mut prefix_sums = Vec::with_capacity(amplitudes.len() + 1);
+	prefix_sums.push(0f64);
+	for ampl in amplitudes.iter() {
+		prefix_sums.push(prefix_sums[prefix_sums.len() - 1] + *ampl as f64);
+	}
+	let half_width = width / 2;
+	let len = amplitudes.len();
+	amplitudes.iter_mut().enumerate().for_each(|(i, ampl)| {
+		let (low, high) = (i.saturating_sub(half_width), len.min(i + half_width + 1));
+		let local_mean = (prefix_sums[high] - prefix_sums[low]) / (high - low) as f64;
+		*ampl /= (local_mean as f32).max(f32::EPSILON);
+	});
+}
+
+/// Magnitude spectra of consecutive, possibly overlapping, slices of a song
+#[derive(Debug, Clone)]
+pub struct Spectrogram {
+	/// Rate of the song the spectrogram is of
+	pub sample_rate: usize,
+	/// Length of every slice's FFT
+	pub fft_size: usize,
+	/// Time between the starts of consecutive slices
+	pub hop_size: std::time::Duration,
+	slice_count: usize,
+	bin_count: usize,
+	/// Row major, `bin_count` magnitudes for each slice
+	magnitudes: Vec<f32>,
+}
+impl Spectrogram {
+	/// Slices in the spectrogram
+	pub fn slice_count(&self) -> usize {
+		self.slice_count
+	}
+	/// Bins kept from every slice, the lowest ones
+	pub fn bin_count(&self) -> usize {
+		self.bin_count
+	}
+	/// Magnitudes of the `t`th slice, indexed by bin
+	pub fn slice(&self, t: usize) -> &[f32] {
+		&self.magnitudes[t * self.bin_count..(t + 1) * self.bin_count]
+	}
+	/// Center frequency of `bin` in Hz
+	pub fn bin_freq(&self, bin: usize) -> Freq {
+		(bin * self.sample_rate / self.fft_size) as Freq
+	}
+	/// One row per slice led by its start time in seconds, with a header row of bin
+	/// frequencies
+	pub fn to_csv(&self) -> String {
+		let header = (0..self.bin_count).map(|bin| format!(",{}", self.bin_freq(bin)));
+		let rows = (0..self.slice_count).map(|t| {
+			let magnitudes = self.slice(t).iter().map(|ampl| format!(",{ampl}"));
+			std::iter::once(format!("\n{}", t as f32 * self.hop_size.as_secs_f32()))
+				.chain(magnitudes)
+				.collect::<String>()
+		});
+		std::iter::once(String::from("time"))
+			.chain(header)
+			.chain(rows)
+			.chain(std::iter::once(String::from("\n")))
+			.collect()
+	}
+}
+
+/// A peak of a slice's spectrum, picked for a constellation map
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Peak {
+	/// Bin of the spectrogram it's in
+	pub bin: usize,
+	/// Frequency of the bin in Hz, see `Spectrogram::bin_freq`
+	pub freq: Freq,
+	/// Magnitude of the bin, whitened if the spectrogram is
+	pub amplitude: f32,
+}
+
+/// Mono audio at a fixed sample rate, the unit everything is fingerprinted from.
+///
+/// Every decoder, generator and `mix` produces samples on the one scale of ±1.0, stored
+/// as 16-bit PCM for memory's sake: 1.0 is `I16_SCALE`, saturating at `i16::MAX`, and
+/// `from_pcm_f32` and `to_pcm_f32` convert between the two. A WAV written by `to_wav`
+/// with the default options decodes back to the same samples whatever it was decoded
+/// from, so amplitude thresholds mean the same wherever a song came from
 #[derive(Debug, Clone)]
 pub struct Song {
+	/// Samples per second
 	pub sample_rate: usize,
-	// TODO: will i16 samples work here??
-	pub samples: Vec<f32>,
+	/// Mono 16-bit PCM samples, whatever the bit depth or encoding of the source. Songs
+	/// are quantized once decoded, saturating anything past full scale
+	pub samples: Vec<i16>,
 }
 impl Song {
-	/* TODO: `offset` should be `std::time::Duration` and should be specified for each song
-		^ same for `duration`
-	*/
-	pub fn mix(a: &Song, b: &Song, snr: f32, offset: usize, duration: usize) -> Song {
+	/// Builds a song from mono float samples in the conventional ±1.0 range
+	pub fn from_pcm_f32(samples: Vec<f32>, sample_rate: usize) -> Song {
+		Song {
+			sample_rate,
+			samples: samples
+				.into_iter()
+				.map(|i| quantize(i * I16_SCALE))
+				.collect(),
+		}
+	}
+	/// Samples in the conventional ±1.0 range, the inverse of `from_pcm_f32`
+	pub fn to_pcm_f32(&self) -> Vec<f32> {
+		self.samples
+			.iter()
+			.map(|&sample| sample as f32 / I16_SCALE)
+			.collect()
+	}
+	/// Builds a song from mono 16-bit samples
+	pub fn from_pcm_i16(samples: &[i16], sample_rate: usize) -> Song {
+		Song {
+			sample_rate,
+			samples: samples.to_vec(),
+		}
+	}
+	/// `duration` of white noise, uniform in ±0.5 and the same for every `seed`
+	pub fn white_noise(duration: std::time::Duration, sample_rate: usize, seed: u64) -> Song {
+		let mut random = xorshift(seed);
+		let sample_count = (duration.as_secs_f64() * sample_rate as f64) as usize;
+		let samples = (0..sample_count)
+			.map(|_| (random() >> 40) as f32 / (1u64 << 24) as f32 - 0.5)
+			.collect();
+		Song::from_pcm_f32(samples, sample_rate)
+	}
+	/// `duration` of pink noise, its power falling 3 dB an octave, the same for every
+	/// `seed`. It has the RMS of `white_noise`, so its rare peaks past ±1.0 are saturated
+	pub fn pink_noise(duration: std::time::Duration, sample_rate: usize, seed: u64) -> Song {
+		let mut white = Song::white_noise(duration, sample_rate, seed);
+		// Paul Kellet's economy filter, a sum of three one pole low passes, accurate to
+		// ±0.5 dB above 9 Hz at 44.1kHz
+		let mut poles = [0f32; 3];
+		let (gains, inputs) = ([0.99765, 0.963, 0.57], [0.099046, 0.2965164, 1.0526913]);
+		let pink: Vec<f32> = white
+			.samples
+			.iter()
+			.map(|&sample| {
+				let sample = sample as f32;
+				for ((pole, gain), input) in poles.iter_mut().zip(gains).zip(inputs) {
+					*pole = gain * *pole + input * sample;
+				}
+				poles.iter().sum::<f32>() + 0.1848 * sample
+			})
+			.collect();
+		let gain = match pink.is_empty() {
+			true => 1.,
+			false => (rms(&white.samples) / rms(&pink)) as f32,
+		};
+		white.samples = pink.iter().map(|&sample| quantize(sample * gain)).collect();
+		white
+	}
+	/// `duration` of a sine at `freq_hz`, at half of full scale
+	pub fn sine(freq_hz: f32, duration: std::time::Duration, sample_rate: usize) -> Song {
+		Song::chirp(freq_hz, freq_hz, duration, sample_rate)
+	}
+	/// `duration` of a sine sweeping linearly from `start_hz` to `end_hz`, at half of full
+	/// scale
+	pub fn chirp(
+		start_hz: f32,
+		end_hz: f32,
+		duration: std::time::Duration,
+		sample_rate: usize,
+	) -> Song {
+		let sample_count = (duration.as_secs_f64() * sample_rate as f64) as usize;
+		let (start_hz, sweep_rate) = (
+			start_hz as f64,
+			(end_hz - start_hz) as f64 / duration.as_secs_f64(),
+		);
+		let samples = (0..sample_count)
+			.map(|i| {
+				let t = i as f64 / sample_rate as f64;
+				// Phase is the integral of the frequency, `start_hz + sweep_rate * t`
+				let phase = std::f64::consts::TAU * (start_hz * t + sweep_rate * t * t / 2.);
+				(phase.sin() / 2.) as f32
+			})
+			.collect();
+		Song::from_pcm_f32(samples, sample_rate)
+	}
+	/// Mixes `duration` of `a` starting at `offset`, or as much as there is, with the
+	/// start of `b`, scaled so the RMS of `a` over the segment is `snr_db` dB above that
+	/// of `b`. `a` keeps its level, so loud mixes saturate at full scale. The mix is as
+	/// long as the segment, `short_noise` says how `b` is stretched to it if it's shorter
+	pub fn mix(
+		a: &Song,
+		b: &Song,
+		snr_db: f32,
+		offset: std::time::Duration,
+		duration: std::time::Duration,
+		short_noise: ShortNoise,
+	) -> Result<Song, MixError> {
 		assert_eq!(
 			a.sample_rate, b.sample_rate,
 			"Mixing samples of unequal sample rate"
 		);
-		// Take duration of 15 seconds
-		Song {
+		let signal = a.slice(offset, duration);
+		if short_noise == ShortNoise::Reject && b.samples.len() < signal.samples.len() {
+			return Err(MixError::NoiseTooShort {
+				noise: b.length(),
+				segment: signal.length(),
+			});
+		}
+		let signal = signal.samples;
+		let noise: Vec<i16> = b
+			.samples
+			.iter()
+			.copied()
+			.cycle()
+			.take(signal.len())
+			.collect();
+		let (signal_rms, noise_rms) = (rms(&signal), rms(&noise));
+		// Empty segments have a NaN RMS
+		if signal.is_empty() || signal_rms == 0. {
+			return Err(MixError::SilentSignal);
+		}
+		if noise.is_empty() || noise_rms == 0. {
+			return Err(MixError::SilentNoise);
+		}
+		let noise_gain = (signal_rms / noise_rms / 10f64.powf(snr_db as f64 / 20.)) as f32;
+		Ok(Song {
 			sample_rate: a.sample_rate,
-			samples: a
-				.samples
+			samples: signal
 				.iter()
-				.skip(offset * a.sample_rate)
-				.take(duration * a.sample_rate)
-				.zip(b.samples.iter())
-				.map(|(a_sample, b_sample)| a_sample * snr + (1. - snr) * b_sample)
+				.zip(noise)
+				.map(|(&a_sample, b_sample)| {
+					quantize(a_sample as f32 + noise_gain * b_sample as f32)
+				})
 				.collect(),
+		})
+	}
+	/// Indices of the samples between the leading and trailing silence. That is
+	/// everything from the first to the last `min_hold` of consecutive 10 ms windows
+	/// whose RMS is above `threshold_db` dBFS. Empty if the song is silent throughout, and
+	/// all of it if it's too short to hold for `min_hold` but has a loud window
+	pub fn audible_range(
+		&self,
+		threshold_db: f32,
+		min_hold: std::time::Duration,
+	) -> std::ops::Range<usize> {
+		let window = ((SILENCE_WINDOW.as_secs_f64() * self.sample_rate as f64) as usize).max(1);
+		let hold = min_hold
+			.as_nanos()
+			.div_ceil(SILENCE_WINDOW.as_nanos())
+			.max(1) as usize;
+		let threshold = 10f64.powf(threshold_db as f64 / 20.) * I16_SCALE as f64;
+		let loud: Vec<bool> = self
+			.samples
+			.chunks(window)
+			.map(|window| rms(window) > threshold)
+			.collect();
+		// Starts of the runs of `hold` loud windows
+		let mut held = loud
+			.windows(hold)
+			.enumerate()
+			.filter(|(_, run)| run.iter().all(|&loud| loud))
+			.map(|(start, _)| start);
+		let Some(first) = held.next() else {
+			return match loud.len() < hold && loud.contains(&true) {
+				true => 0..self.samples.len(),
+				false => 0..0,
+			};
+		};
+		let last = held.next_back().unwrap_or(first) + hold;
+		first * window..(last * window).min(self.samples.len())
+	}
+	/// The song without its leading and trailing silence, see `audible_range`. A silent
+	/// song comes out empty
+	pub fn trim_silence(&self, threshold_db: f32, min_hold: std::time::Duration) -> Song {
+		Song {
+			sample_rate: self.sample_rate,
+			samples: self.samples[self.audible_range(threshold_db, min_hold)].to_vec(),
+		}
+	}
+	/// Extracts `len` worth of samples starting at `start`, clamped to the end of the song
+	pub fn slice(&self, start: std::time::Duration, len: std::time::Duration) -> Song {
+		let sample_index = |time: std::time::Duration| {
+			((time.as_secs_f64() * self.sample_rate as f64) as usize).min(self.samples.len())
+		};
+		let start_index = sample_index(start);
+		let end_index = sample_index(start + len);
+		Song {
+			sample_rate: self.sample_rate,
+			samples: self.samples[start_index..end_index].to_vec(),
 		}
 	}
-	pub fn to_wav(song: Song) -> Vec<u8> {
-		let mut byte_array: Vec<u8> = Vec::with_capacity(song.samples.len() * 2);
-		[
-			0x52, 0x49, 0x46, 0x46, 0x1c, 0x30, 0x14, 0x00, 0x57, 0x41, 0x56, 0x45, 0x66, 0x6d,
-			0x74, 0x20, 0x10, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x44, 0xac, 0x00, 0x00,
-			0x88, 0x58, 0x01, 0x00, 0x02, 0x00, 0x10, 0x00, 0x64, 0x61, 0x74, 0x61, 0xf8, 0x2f,
-			0x14, 0x00u8,
-		]
-		.into_iter()
-		.for_each(|t| byte_array.push(t));
-		song.samples.iter().for_each(|i| {
-			let [a, b] = (*i as i16).to_le_bytes();
+	/// Encodes as a mono 16-bit PCM WAV file
+	pub fn to_wav(song: Song, options: WavOptions) -> Vec<u8> {
+		// Mono 16-bit PCM
+		let (channel_count, bits_per_sample) = (1u16, 16u16);
+		let block_align = channel_count * bits_per_sample / 8;
+		let byte_rate = song.sample_rate as u32 * block_align as u32;
+		let data_size = (song.samples.len() * block_align as usize) as u32;
+		let mut byte_array: Vec<u8> = Vec::with_capacity(44 + data_size as usize);
+		byte_array.extend(b"RIFF");
+		// Everything after the RIFF size field: "WAVE", the `fmt ` chunk and the `data` header
+		byte_array.extend((36 + data_size).to_le_bytes());
+		byte_array.extend(b"WAVEfmt ");
+		byte_array.extend(16u32.to_le_bytes());
+		byte_array.extend(1u16.to_le_bytes());
+		byte_array.extend(channel_count.to_le_bytes());
+		byte_array.extend((song.sample_rate as u32).to_le_bytes());
+		byte_array.extend(byte_rate.to_le_bytes());
+		byte_array.extend(block_align.to_le_bytes());
+		byte_array.extend(bits_per_sample.to_le_bytes());
+		byte_array.extend(b"data");
+		byte_array.extend(data_size.to_le_bytes());
+		let gain = match options.normalize_peak {
+			Some(dbfs) => {
+				let peak = song
+					.samples
+					.iter()
+					.fold(0f32, |peak, &i| peak.max((i as f32).abs()));
+				match peak > 0. {
+					true => 10f32.powf(dbfs / 20.) * i16::MAX as f32 / peak,
+					false => 1.,
+				}
+			}
+			None => 1.,
+		};
+		let mut dither = Dither::default();
+		song.samples.iter().for_each(|&i| {
+			let mut sample = i as f32 * gain;
+			if options.dither {
+				sample += dither.sample();
+			}
+			let sample = match options.clipping {
+				Clipping::Saturate => sample.round() as i16,
+				Clipping::Wrap => sample.round() as i32 as i16,
+			};
+			let [a, b] = sample.to_le_bytes();
 			byte_array.push(a);
 			byte_array.push(b);
 		});
 		byte_array
 	}
-	pub fn from_wav(byte_array: Vec<u8>) -> Song {
-		let channel_count = u16::from_le_bytes([byte_array[22], byte_array[23]]);
-		assert_eq!(channel_count, 1, "Only mono channel files are supported!");
-		let sample_rate = u16::from_le_bytes([byte_array[24], byte_array[25]]) as usize;
-		let mut samples = Vec::with_capacity(byte_array.len() / 2);
-		let mut byte_iter = byte_array.iter().skip(44);
-		while let Some(&lsb) = byte_iter.next() {
-			if let Some(&msb) = byte_iter.next() {
-				let word_data = i16::from_le_bytes([lsb, msb]);
-				samples.push(word_data as f32);
+	/// Decodes a WAV file held in memory
+	pub fn from_wav(byte_array: Vec<u8>) -> Result<Song, DecodeError> {
+		Song::from_wav_reader(byte_array.as_slice())
+	}
+	/// Decodes a WAV stream a block at a time, so the raw bytes are never fully resident
+	pub fn from_wav_reader<R: Read>(mut reader: R) -> Result<Song, DecodeError> {
+		let (format, data_size) = parse_riff_header(&mut reader)?;
+		// Every decoder scales its samples into the i16 range
+		let decode_sample: fn(&[u8]) -> f32 = match (format.format_tag, format.bits_per_sample) {
+			(1, 8) => |b| (b[0] as f32 - 128.) * 256.,
+			(1, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f32,
+			// Placing the 3 bytes in the upper bits of an i32 sign extends them
+			(1, 24) => |b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 65536.,
+			(3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) * I16_SCALE,
+			_ => {
+				return Err(DecodeError::Unsupported(format!(
+					"format tag {} with {} bits per sample",
+					format.format_tag, format.bits_per_sample
+				)))
+			}
+		};
+		let bytes_per_sample = format.bits_per_sample as usize / 8;
+		let channel_count = format.channel_count as usize;
+		let frame_size = bytes_per_sample * channel_count;
+		let mut samples = Vec::new();
+		let mut block = vec![0u8; frame_size * 4096];
+		// Tools writing to a pipe can't go back to fill in the length, the samples then
+		// run to the end of the stream
+		let streamed = data_size == STREAMED_DATA_SIZE;
+		let mut remaining = match streamed {
+			true => usize::MAX,
+			false => data_size - data_size % frame_size,
+		};
+		while remaining > 0 {
+			let block = &mut block[..remaining.min(frame_size * 4096)];
+			let read = match streamed {
+				true => read_to_fill(&mut reader, block)?,
+				false => read_exact(&mut reader, block).map(|_| block.len())?,
+			};
+			// Interleaved frames are averaged across channels into a single mono stream
+			samples.extend(block[..read].chunks_exact(frame_size).map(|frame| {
+				quantize(
+					frame
+						.chunks_exact(bytes_per_sample)
+						.map(decode_sample)
+						.sum::<f32>() / channel_count as f32,
+				)
+			}));
+			if read < block.len() {
+				break;
 			}
+			remaining -= block.len();
 		}
+		Ok(Song {
+			sample_rate: format.sample_rate as usize,
+			samples,
+		})
+	}
+	/// Decodes an MP3 file held in memory
+	pub fn from_mp3(byte_array: Vec<u8>) -> Result<Song, DecodeError> {
+		decode_compressed(byte_array, "mp3")
+	}
+	/// Decodes a FLAC file held in memory
+	pub fn from_flac(byte_array: Vec<u8>) -> Result<Song, DecodeError> {
+		decode_compressed(byte_array, "flac")
+	}
+	/// Decodes Ogg Vorbis, and Ogg Opus when built with the `opus` feature
+	pub fn from_ogg(byte_array: Vec<u8>) -> Result<Song, DecodeError> {
+		decode_compressed(byte_array, "ogg")
+	}
+	/// Extensions of the files `from_file` has a decoder for
+	pub const SUPPORTED_EXTENSIONS: &'static [&'static str] = &[
+		"wav",
+		"flac",
+		"mp3",
+		"ogg",
+		#[cfg(feature = "opus")]
+		"opus",
+	];
+	/// Reads a song from disk, picking the decoder from the file's magic bytes
+	pub fn from_file(path: &Path) -> Result<Song, DecodeError> {
+		let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+		// WAV files can be big and uncompressed, so they're streamed instead
+		if reader.fill_buf()?.starts_with(b"RIFF") {
+			return Song::from_wav_reader(reader);
+		}
+		let mut byte_array = Vec::new();
+		reader.read_to_end(&mut byte_array)?;
+		match byte_array.as_slice() {
+			[b'f', b'L', b'a', b'C', ..] => Song::from_flac(byte_array),
+			[b'O', b'g', b'g', b'S', ..] => Song::from_ogg(byte_array),
+			// Either an ID3v2 tag or the sync word of a bare MPEG audio frame
+			[b'I', b'D', b'3', ..] => Song::from_mp3(byte_array),
+			[0xff, second, ..] if second & 0xe0 == 0xe0 => Song::from_mp3(byte_array),
+			_ => Err(DecodeError::UnsupportedFormat),
+		}
+	}
+	/// Converts the song to `sample_rate` by linearly interpolating between samples
+	pub fn resample(self, sample_rate: usize) -> Song {
+		if self.sample_rate == sample_rate || self.samples.is_empty() {
+			return self;
+		}
+		let step = self.sample_rate as f64 / sample_rate as f64;
+		let sample_count = (self.samples.len() as f64 / step) as usize;
+		let samples = (0..sample_count)
+			.map(|i| {
+				let position = i as f64 * step;
+				let index = position as usize;
+				let fraction = (position - index as f64) as f32;
+				let current = self.samples[index] as f32;
+				let next = self
+					.samples
+					.get(index + 1)
+					.map_or(current, |&next| next as f32);
+				quantize(current + (next - current) * fraction)
+			})
+			.collect();
 		Song {
 			sample_rate,
 			samples,
 		}
 	}
-	#[allow(unused)]
+	/// Length in whole milliseconds
 	pub fn length(&self) -> std::time::Duration {
 		std::time::Duration::from_millis((self.samples.len() * 1000 / self.sample_rate) as u64)
 	}
 
-	pub fn signatures<'a, T: ExactSizeIterator<Item = Vec<Freq>>>(
-		// This should be a `std::time::Duration`
+	/// Pairs every peak with the peaks in its target zone, yielding each signature along
+	/// with the index of its anchor's slice. Only the slices of one target zone are held
+	/// at a time, so the constellation map can be consumed as it's produced.
+	///
+	/// Slices with more than `max_signatures_per_slice` keep every anchor's nearest
+	/// target before any anchor's next one, louder anchors first
+	pub fn signatures<T: Iterator<Item = Vec<Freq>>>(
 		target_zone_width: TimeStamp,
 		target_zone_height: Freq,
+		target_zone_offset: TimeStamp,
+		max_fanout_per_anchor: Option<usize>,
+		max_signatures_per_slice: Option<usize>,
 		constellation_map: T,
-	) -> impl Iterator<Item = Vec<Signature>> + 'a {
-		let constellation_map: Vec<_> = constellation_map.collect();
-		(0..constellation_map.len() - 1).map(move |i| {
-			let slice = &constellation_map[i];
-			let target_slices =
-				&constellation_map[i..constellation_map.len().min(i + target_zone_width as usize)];
-			slice
+	) -> impl Iterator<Item = (TimeStamp, Signature)> {
+		let mut constellation_map = constellation_map.peekable();
+		// The anchor's slice followed by the rest of its target zone
+		let mut target_slices = VecDeque::with_capacity(target_zone_width as usize);
+		let mut anchor_timestamp: TimeStamp = 0;
+		std::iter::from_fn(move || {
+			while target_slices.len() < (target_zone_width as usize).max(1) {
+				match constellation_map.next() {
+					Some(slice) => target_slices.push_back(slice),
+					None => break,
+				}
+			}
+			// The last slice has nothing after it to pair with, and samples shorter than
+			// two slices yield no signatures at all
+			if target_slices.len() <= 1 && constellation_map.peek().is_none() {
+				return None;
+			}
+			let mut signatures: Vec<_> = target_slices[0]
 				.iter()
 				.copied()
-				.map(move |anchor_freq| {
+				.flat_map(|anchor_freq| {
 					target_slices
 						.iter()
 						.enumerate()
-						.skip(1)
-						.map(move |(time_offset, target_slice)| {
+						.skip(target_zone_offset as usize)
+						.flat_map(move |(time_offset, target_slice)| {
 							target_slice
 								.iter()
 								.copied()
 								.filter(move |target_freq| {
 									(anchor_freq.saturating_sub(target_zone_height / 2)
-										..anchor_freq + target_zone_height / 2)
+										..anchor_freq.saturating_add(target_zone_height / 2))
 										.contains(target_freq)
 								})
 								.map(move |target_freq| {
 									Signature((anchor_freq, target_freq), time_offset as TimeStamp)
 								})
 						})
-						.flatten()
+						// Targets come nearest slice first, and loudest first within a slice
+						.take(max_fanout_per_anchor.unwrap_or(usize::MAX))
+						.enumerate()
 				})
-				.flatten()
-				.collect()
+				.collect();
+			if let Some(max_signatures) = max_signatures_per_slice {
+				if signatures.len() > max_signatures {
+					// Stable, so anchors of the same rank stay loudest first
+					signatures.sort_by_key(|&(rank, _)| rank);
+					signatures.truncate(max_signatures);
+				}
+			}
+			let timestamp = anchor_timestamp;
+			target_slices.pop_front();
+			anchor_timestamp += 1;
+			Some(
+				signatures
+					.into_iter()
+					.map(move |(_, signature)| (timestamp, signature)),
+			)
 		})
+		.flatten()
+	}
+
+	/// Magnitude spectrogram of the song, slices of `slice_size` start `hop_size` apart
+	/// and are windowed then zero padded to `fft_size`, a power of two no shorter than
+	/// a slice
+	pub fn spectrogram(
+		&self,
+		slice_size: std::time::Duration,
+		hop_size: std::time::Duration,
+		fft_size: usize,
+		window: Window,
+	) -> Spectrogram {
+		self.spectrogram_bins(slice_size, hop_size, fft_size, window, fft_size / 2 + 1)
 	}
 
-	/// For each time slice of duration `slice_size`, compute the frequency with the
-	/// highest amplitude for each frequency bucket.
+	/// Same as `spectrogram` but only keeping the lowest `bin_count` bins
+	fn spectrogram_bins(
+		&self,
+		slice_size: std::time::Duration,
+		hop_size: std::time::Duration,
+		fft_size: usize,
+		window: Window,
+		bin_count: usize,
+	) -> Spectrogram {
+		let (sample_window_size, sample_hop_size) =
+			slice_samples(self.sample_rate, slice_size, hop_size);
+		// Keep adding slices until the end of the song is covered, the last one
+		// is zero padded if it runs past the end
+		let slice_count = match self.samples.len() {
+			0 => 0,
+			len => {
+				len.saturating_sub(sample_window_size)
+					.div_ceil(sample_hop_size)
+					+ 1
+			}
+		};
+		let bin_count = bin_count.min(fft_size / 2 + 1);
+		let window_coefficients = window.coefficients(sample_window_size);
+		let fft = FFT_PLANNER.with_borrow_mut(|planner| planner.plan_fft_forward(fft_size));
+		let mut magnitudes = vec![0f32; slice_count * bin_count];
+		// Every rayon worker brings its own FFT buffers so they are only allocated once
+		// per worker, `max` keeps `par_chunks_mut` happy when no bins are kept
+		magnitudes
+			.par_chunks_mut(bin_count.max(1))
+			.enumerate()
+			.with_min_len(MIN_SLICES_PER_JOB)
+			.for_each_init(
+				|| {
+					(
+						fft.make_input_vec(),
+						fft.make_output_vec(),
+						fft.make_scratch_vec(),
+					)
+				},
+				|buffers: &mut FftBuffers, (i, row)| {
+					let (fft_extended_buffer, fft_output, fft_scratch) = buffers;
+					let start = i * sample_hop_size;
+					let slice =
+						&self.samples[start..self.samples.len().min(start + sample_window_size)];
+					slice
+						.iter()
+						.zip(window_coefficients.iter())
+						.zip(fft_extended_buffer.iter_mut())
+						.for_each(|((&sample, coefficient), buffer)| {
+							*buffer = sample as f32 * coefficient
+						});
+					fft_extended_buffer[slice.len()..].fill(0.);
+					fft.process_with_scratch(fft_extended_buffer, fft_output, fft_scratch)
+						.expect("buffers are sized by the FFT plan");
+					row.iter_mut()
+						.zip(fft_output.iter())
+						.for_each(|(magnitude, bin)| *magnitude = bin.norm());
+				},
+			);
+		Spectrogram {
+			sample_rate: self.sample_rate,
+			fft_size,
+			hop_size,
+			slice_count,
+			bin_count,
+			magnitudes,
+		}
+	}
+
+	/// For each time slice of duration `slice_size`, pick up to `freq_per_slice` peak
+	/// frequencies from the song's spectrogram using `peak_picking`. Consecutive slices
+	/// start `hop_size` apart, so they overlap whenever `hop_size` is shorter than
+	/// `slice_size`.
 	///
-	/// The frequency range spans from 0 to `bucket_size` * `bucket_count`
+	/// The frequency range spans from `min_freq` to `min_freq` + `bucket_size` * `bucket_count`
 	pub fn constellation_map(
 		&self,
-		slice_size: std::time::Duration,
-		freq_per_slice: usize,
-		bucket_size: Freq,
-		bucket_count: usize,
+		config: ConstellationConfig,
 	) -> impl ExactSizeIterator<Item = Vec<Freq>> + '_ {
-		let sample_window_size = self.sample_rate * slice_size.as_millis() as usize / 1000;
-		let mut fft_extended_buffer = vec![0f32; self.sample_rate];
-		self.samples
-			.chunks_exact(sample_window_size)
-			.map(move |slice| {
-				slice
-					.iter()
-					.zip(fft_extended_buffer.iter_mut())
-					.for_each(|(&sample, buffer)| *buffer = sample);
-				let freq_amplitudes: Vec<_> = fft_extended_buffer
-					.real_fft()
-					.iter()
-					.map(|i| i.norm())
-					.take(Into::<usize>::into(bucket_size) * bucket_count)
-					.enumerate()
-					.map(|(freq, ampl)| (freq as Freq, ampl))
-					.collect();
-				let mut bucket_frequencies: Vec<_> = freq_amplitudes
-					.chunks_exact(bucket_size.into())
-					.map(|freq_bucket| {
-						freq_bucket
+		let (_, peaks) = self.constellation_peaks(config);
+		peaks
+			.into_iter()
+			.map(|peaks| peaks.into_iter().map(|peak| peak.freq).collect())
+	}
+	/// The peaks `constellation_map` picks for every slice, loudest first, along with the
+	/// spectrogram they're picked from. It's whitened if `config` says so, and only holds
+	/// the bins up to the top of the highest bucket
+	pub fn constellation_peaks(
+		&self,
+		ConstellationConfig {
+			slice_size,
+			fft_size,
+			hop_size,
+			freq_per_slice,
+			bucket_size,
+			bucket_count,
+			min_freq,
+			window,
+			bucketing,
+			noise_floor,
+			whitening_width,
+			peak_picking,
+			peak_neighbourhood: (time_radius, freq_radius),
+		}: ConstellationConfig,
+	) -> (Spectrogram, Vec<Vec<Peak>>) {
+		// Everything below works on bin indices, peaks are converted back to Hz at the end
+		let hz_to_bin = |hz: usize| hz * fft_size / self.sample_rate;
+		let band_edges: Vec<_> = bucketing
+			.band_edges(bucket_size, bucket_count)
+			.into_iter()
+			.map(|edge| hz_to_bin(edge + min_freq as usize))
+			.collect();
+		let (low_edge, high_edge) = (band_edges[0], band_edges[bucket_count]);
+		let mut spectrogram =
+			self.spectrogram_bins(slice_size, hop_size, fft_size, window, high_edge);
+		let bin_count = spectrogram.bin_count;
+		let low_edge = low_edge.min(bin_count);
+		if whitening_width > 0 && bin_count > 0 {
+			let whitening_width = hz_to_bin(whitening_width as usize).max(1);
+			spectrogram
+				.magnitudes
+				.par_chunks_mut(bin_count)
+				.with_min_len(MIN_SLICES_PER_JOB)
+				.for_each(|row| whiten(&mut row[low_edge..], whitening_width));
+		}
+		let freq_radius = hz_to_bin(freq_radius as usize);
+		let time_radius = match peak_picking {
+			PeakPicking::Bucket => 0,
+			PeakPicking::LocalMaxima => time_radius,
+		};
+		let slice_count = spectrogram.slice_count;
+		let peaks = |i: usize| -> Vec<Peak> {
+			let freq_amplitudes = spectrogram.slice(i);
+			let analysed_bins = &freq_amplitudes[low_edge..];
+			let mean_amplitude =
+				analysed_bins.iter().sum::<f32>() / analysed_bins.len().max(1) as f32;
+			let amplitude_floor = noise_floor.0 * mean_amplitude;
+			let mut bucket_frequencies: Vec<(usize, f32)> = match peak_picking {
+				PeakPicking::Bucket => band_edges
+					.windows(2)
+					.filter_map(|band| {
+						let band_start = band[0].min(bin_count);
+						freq_amplitudes[band_start..band[1].min(bin_count)]
 							.iter()
-							.max_by(|(_freq_1, ampl_1), (_freq_2, ampl_2)| {
+							.enumerate()
+							.max_by(|(_bin_1, ampl_1), (_bin_2, ampl_2)| {
 								ampl_1.partial_cmp(ampl_2).unwrap()
 							})
-							.unwrap()
+							.map(|(bin, ampl)| (band_start + bin, *ampl))
 					})
-					.collect();
-				bucket_frequencies.sort_unstable_by(|(_freq_1, ampl_1), (_freq_2, ampl_2)| {
-					ampl_2.partial_cmp(ampl_1).unwrap()
-				});
-				bucket_frequencies
-					.iter()
-					.map(|(freq, _ampl)| *freq)
-					.take(freq_per_slice)
-					.collect()
+					.collect(),
+				PeakPicking::LocalMaxima => (low_edge..bin_count)
+					.map(|bin| (bin, freq_amplitudes[bin]))
+					.filter(|&(bin, ampl)| {
+						let neighbours = bin.saturating_sub(freq_radius).max(low_edge)
+							..bin_count.min(bin + freq_radius + 1);
+						// Checking the own slice first rules out most bins cheaply
+						std::iter::once(i)
+							.chain(
+								(i.saturating_sub(time_radius)
+									..slice_count.min(i + time_radius + 1))
+									.filter(|&j| j != i),
+							)
+							.all(|j| {
+								neighbours.clone().all(|other_bin| {
+									(j == i && other_bin == bin)
+										|| spectrogram.slice(j)[other_bin] < ampl
+								})
+							})
+					})
+					.collect(),
+			};
+			// Peaks that barely stand out are most likely noise, or a silent slice
+			bucket_frequencies.retain(|(_bin, ampl)| *ampl > amplitude_floor);
+			bucket_frequencies.sort_unstable_by(|(_bin_1, ampl_1), (_bin_2, ampl_2)| {
+				ampl_2.partial_cmp(ampl_1).unwrap()
+			});
+			bucket_frequencies
+				.iter()
+				.map(|&(bin, amplitude)| Peak {
+					bin,
+					freq: spectrogram.bin_freq(bin),
+					amplitude,
+				})
+				.take(freq_per_slice)
+				.collect()
+		};
+		let peaks = (0..slice_count)
+			.into_par_iter()
+			.with_min_len(MIN_SLICES_PER_JOB)
+			.map(peaks)
+			.collect();
+		(spectrogram, peaks)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::database::DatabaseConfig;
+
+	/// The loudest peak of every slice of `song`
+	fn loudest_peaks(song: &Song, config: ConstellationConfig) -> Vec<Freq> {
+		song.constellation_map(config)
+			.map(|peaks| peaks[0])
+			.collect()
+	}
+
+	#[test]
+	fn constellation_map_finds_a_sine_in_its_bucket() {
+		for (slice_ms, fft_size) in [(100, 8192), (260, 16384), (500, 32768)] {
+			let config = DatabaseConfig::builder()
+				.with_slice_size(Duration::from_millis(slice_ms))
+				.with_fft_size(fft_size)
+				.build()
+				.constellation();
+			let sine = Song::sine(1234., Duration::from_secs(2), 44100);
+			let hz_per_bin = (44100 / fft_size) as Freq + 1;
+			for peak in loudest_peaks(&sine, config) {
+				assert_eq!((peak - config.min_freq) / config.bucket_size, 6);
+				assert!(
+					peak.abs_diff(1234) <= hz_per_bin,
+					"{peak}Hz in {slice_ms}ms slices"
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn constellation_map_follows_a_chirp() {
+		let config = DatabaseConfig::builder().build().constellation();
+		let chirp = Song::chirp(500., 3000., Duration::from_secs(3), 44100);
+		let peaks = loudest_peaks(&chirp, config);
+		assert!(peaks.windows(2).all(|pair| pair[0] <= pair[1]), "{peaks:?}");
+		assert!(peaks[0] < 700 && *peaks.last().unwrap() > 2800, "{peaks:?}");
+	}
+
+	#[test]
+	fn constellation_map_ignores_rumble_below_the_cutoff() {
+		let config = DatabaseConfig::builder().build().constellation();
+		let mut clean = Song::white_noise(Duration::from_secs(3), 44100, 0);
+		// Whole slices, as a partial last one would cut the rumble off abruptly
+		let (window, hop) = slice_samples(44100, config.slice_size, config.hop_size);
+		clean.samples.truncate(window + 20 * hop);
+		let rumble = Song::sine(20., clean.length(), 44100);
+		let rumbling = Song {
+			sample_rate: 44100,
+			samples: clean
+				.samples
+				.iter()
+				.zip(&rumble.samples)
+				.map(|(&sample, &rumble)| sample + rumble / 2)
+				.collect(),
+		};
+		let peaks: Vec<_> = clean.constellation_map(config).collect();
+		assert!(peaks
+			.iter()
+			.all(|peaks| peaks.len() == config.freq_per_slice));
+		assert_eq!(
+			rumbling.constellation_map(config).collect::<Vec<_>>(),
+			peaks
+		);
+		// Without the cutoff the rumble is the loudest peak of every slice
+		let uncut = DatabaseConfig::builder()
+			.with_min_freq(0)
+			.build()
+			.constellation();
+		assert!(loudest_peaks(&rumbling, uncut)
+			.iter()
+			.all(|peak| peak.abs_diff(20) <= 3));
+	}
+
+	#[test]
+	fn signatures_pair_two_tones() {
+		let config = DatabaseConfig::builder().with_freq_per_slice(1).build();
+		let mut song = Song::sine(1234., Duration::from_secs(1), 44100);
+		song.samples
+			.extend(Song::sine(1567., Duration::from_secs(1), 44100).samples);
+		let peaks = loudest_peaks(&song, config.constellation());
+		let (low, high) = (peaks[0], *peaks.last().unwrap());
+		assert!(
+			low.abs_diff(1234) <= 3 && high.abs_diff(1567) <= 3,
+			"{peaks:?}"
+		);
+		let pairs: std::collections::BTreeSet<_> = config
+			.signatures(&song)
+			.map(|(_, Signature(pair, _))| pair)
+			.collect();
+		assert_eq!(
+			pairs,
+			[(low, low), (low, high), (high, high)]
+				.into_iter()
+				.collect()
+		);
+		// The first slice of the second tone is the nearest target of the last anchor
+		// of the first
+		let handover = peaks.iter().position(|&peak| peak == high).unwrap() as TimeStamp;
+		assert!(config
+			.signatures(&song)
+			.any(|signature| signature == (handover - 1, Signature((low, high), 1))));
+	}
+
+	/// Every signature `Song::signatures` pairs `constellation_map` into, with a target
+	/// zone of `width` slices and `height` Hz right after the anchor
+	fn pair(
+		width: TimeStamp,
+		height: Freq,
+		constellation_map: Vec<Vec<Freq>>,
+	) -> Vec<(TimeStamp, Signature)> {
+		Song::signatures(width, height, 1, None, None, constellation_map.into_iter()).collect()
+	}
+
+	#[test]
+	fn signatures_of_short_constellation_maps() {
+		assert!(pair(10, 900, vec![]).is_empty());
+		assert!(pair(10, 900, vec![vec![1000, 1200]]).is_empty());
+		assert_eq!(
+			pair(10, 900, vec![vec![1000], vec![1200]]),
+			[(0, Signature((1000, 1200), 1))]
+		);
+	}
+
+	/// Share of `song`'s power in the lowest quarter of the buckets
+	fn low_bucket_power(song: &Song) -> f64 {
+		let config = DatabaseConfig::builder().build().constellation();
+		let spectrogram = song.spectrogram(
+			config.slice_size,
+			config.hop_size,
+			config.fft_size,
+			config.window,
+		);
+		let low = config.min_freq as usize + config.bucket_size as usize * config.bucket_count / 4;
+		let (mut below, mut total) = (0., 0.);
+		for t in 0..spectrogram.slice_count() {
+			for (bin, &magnitude) in spectrogram.slice(t).iter().enumerate() {
+				let power = magnitude as f64 * magnitude as f64;
+				total += power;
+				if (config.min_freq as usize..low).contains(&(spectrogram.bin_freq(bin) as usize)) {
+					below += power;
+				}
+			}
+		}
+		below / total
+	}
+
+	#[test]
+	fn noise_is_as_long_and_loud_as_asked_and_seeded() {
+		let duration = Duration::from_millis(2500);
+		// Uniform in ±0.5 of full scale, so `0.5 / √3` of it
+		let expected_rms = 0.5 / 3f64.sqrt() * I16_SCALE as f64;
+		for noise in [Song::white_noise, Song::pink_noise] {
+			let song = noise(duration, 44100, 3);
+			assert_eq!(song.sample_rate, 44100);
+			assert_eq!(song.samples.len(), 110250);
+			let level = rms(&song.samples);
+			assert!((level / expected_rms - 1.).abs() < 0.02, "RMS of {level}");
+			assert_eq!(noise(duration, 44100, 3).samples, song.samples);
+			assert_ne!(noise(duration, 44100, 4).samples, song.samples);
+			assert!(noise(Duration::ZERO, 44100, 3).samples.is_empty());
+		}
+	}
+
+	#[test]
+	fn pink_noise_loads_the_low_buckets_more_than_white() {
+		let duration = Duration::from_secs(3);
+		let white = low_bucket_power(&Song::white_noise(duration, 44100, 0));
+		let pink = low_bucket_power(&Song::pink_noise(duration, 44100, 0));
+		assert!(
+			pink > 2. * white,
+			"{pink} of pink noise's power low, {white} of white's"
+		);
+	}
+
+	#[test]
+	fn matching_samples_shorter_than_two_slices_finds_nothing() {
+		let config = DatabaseConfig::builder().build();
+		let mut db = crate::database::Database::new(config);
+		let song = Song::white_noise(Duration::from_secs(5), 44100, 0);
+		db.add_song(
+			"noise.wav".into(),
+			config.song_data("noise.wav".as_ref(), &song),
+		);
+		db.finalize();
+		for millis in [0, 100, 260] {
+			let sample = song.slice(Duration::ZERO, Duration::from_millis(millis));
+			assert_eq!(config.signatures(&sample).count(), 0);
+			assert!(
+				db.match_sample(&sample).is_empty(),
+				"{millis}ms sample matched"
+			);
+		}
+	}
+
+	#[test]
+	fn target_zone_saturates_at_the_frequency_range() {
+		// Both bounds of the zone around either anchor are past the range of `Freq`
+		let signatures = pair(
+			2,
+			60000,
+			vec![vec![65500, 100], vec![65530, 35000, 50, 40000]],
+		);
+		let pairs: Vec<_> = signatures
+			.iter()
+			.map(|(_, Signature(pair, _))| *pair)
+			.collect();
+		assert_eq!(pairs, [(65500, 65530), (65500, 40000), (100, 50)]);
+	}
+
+	/// A 44.1kHz WAV file of `data`, its `fmt ` chunk followed by `extension`
+	fn wav(
+		format_tag: u16,
+		channel_count: u16,
+		bits_per_sample: u16,
+		extension: &[u8],
+		data: &[u8],
+	) -> Vec<u8> {
+		let block_align = channel_count * bits_per_sample / 8;
+		let fmt_size = 16 + extension.len() as u32;
+		let mut wav = b"RIFF".to_vec();
+		wav.extend((4 + 8 + fmt_size + 8 + data.len() as u32).to_le_bytes());
+		wav.extend(b"WAVEfmt ");
+		wav.extend(fmt_size.to_le_bytes());
+		wav.extend(format_tag.to_le_bytes());
+		wav.extend(channel_count.to_le_bytes());
+		wav.extend(44100u32.to_le_bytes());
+		wav.extend((44100 * block_align as u32).to_le_bytes());
+		wav.extend(block_align.to_le_bytes());
+		wav.extend(bits_per_sample.to_le_bytes());
+		wav.extend(extension);
+		wav.extend(b"data");
+		wav.extend((data.len() as u32).to_le_bytes());
+		wav.extend(data);
+		wav
+	}
+
+	/// A mono WAV file with a WAVE_FORMAT_EXTENSIBLE `fmt ` chunk of `sub_format`
+	fn extensible_wav(sub_format: u16, bits_per_sample: u16, data: &[u8]) -> Vec<u8> {
+		// Extension size, valid bits per sample, speaker mask and the sub-format GUID
+		let mut extension = 22u16.to_le_bytes().to_vec();
+		extension.extend(bits_per_sample.to_le_bytes());
+		extension.extend(4u32.to_le_bytes());
+		extension.extend(sub_format.to_le_bytes());
+		extension.extend(EXTENSIBLE_GUID_SUFFIX);
+		wav(WAVE_FORMAT_EXTENSIBLE, 1, bits_per_sample, &extension, data)
+	}
+
+	/// Little endian bytes of every one of `samples`
+	fn pcm_bytes(samples: &[i16]) -> Vec<u8> {
+		samples
+			.iter()
+			.flat_map(|sample| sample.to_le_bytes())
+			.collect()
+	}
+
+	/// The loudest `count` peaks of every slice of `song`, in order of frequency
+	fn top_peaks(song: &Song, count: usize) -> Vec<Vec<Freq>> {
+		let config = DatabaseConfig::builder().build().constellation();
+		song.constellation_map(config)
+			.map(|mut peaks| {
+				peaks.truncate(count);
+				peaks.sort_unstable();
+				peaks
+			})
+			.collect()
+	}
+
+	#[test]
+	fn from_wav_downmixes_stereo() {
+		let left = Song::sine(1000., Duration::from_secs(2), 44100);
+		let right = Song::sine(2500., Duration::from_secs(2), 44100);
+		let interleaved: Vec<i16> = left
+			.samples
+			.iter()
+			.zip(&right.samples)
+			.flat_map(|(&left, &right)| [left, right])
+			.collect();
+		let stereo = Song::from_wav(wav(1, 2, 16, &[], &pcm_bytes(&interleaved))).unwrap();
+		let mono = Song::from_pcm_f32(
+			left.samples
+				.iter()
+				.zip(&right.samples)
+				.map(|(&left, &right)| (left as f32 + right as f32) / 2. / I16_SCALE)
+				.collect(),
+			44100,
+		);
+		assert_eq!(stereo.samples.len(), mono.samples.len());
+		assert!(stereo
+			.samples
+			.iter()
+			.zip(&mono.samples)
+			.all(|(stereo, mono)| stereo.abs_diff(*mono) <= 1));
+		assert_eq!(top_peaks(&stereo, 2), top_peaks(&mono, 2));
+	}
+
+	#[test]
+	fn float_wav_fingerprints_like_pcm() {
+		let sine = Song::sine(1234., Duration::from_secs(2), 44100);
+		let floats: Vec<u8> = sine
+			.samples
+			.iter()
+			.flat_map(|&sample| (sample as f32 / I16_SCALE).to_le_bytes())
+			.collect();
+		let float = Song::from_wav(wav(3, 1, 32, &[], &floats)).unwrap();
+		let pcm = Song::from_wav(wav(1, 1, 16, &[], &pcm_bytes(&sine.samples))).unwrap();
+		assert_eq!(float.samples, pcm.samples);
+		let config = DatabaseConfig::builder().build();
+		assert!(config.signatures(&float).eq(config.signatures(&pcm)));
+	}
+
+	#[test]
+	fn to_wav_writes_the_chunk_sizes() {
+		let song = Song::sine(440., Duration::from_millis(1234), 22050);
+		let wav = Song::to_wav(song.clone(), WavOptions::default());
+		let field = |at: usize| u32::from_le_bytes(wav[at..at + 4].try_into().unwrap());
+		assert_eq!(field(4) as usize, wav.len() - 8);
+		assert_eq!(field(24), 22050);
+		assert_eq!(field(28), 22050 * 2);
+		assert_eq!(&wav[36..40], b"data");
+		assert_eq!(field(40) as usize, song.samples.len() * 2);
+		assert_eq!(field(40) as usize, wav.len() - 44);
+		let decoded = Song::from_wav(wav).unwrap();
+		assert_eq!(decoded.sample_rate, 22050);
+		assert_eq!(decoded.samples, song.samples);
+	}
+
+	#[test]
+	fn to_wav_saturates_unless_told_to_wrap() {
+		let song = Song::from_pcm_i16(&[16384, -16384, 1000], 44100);
+		// Normalizing to +6 dBFS takes the peaks to about twice full scale
+		let decoded = |clipping| {
+			let options = WavOptions {
+				clipping,
+				normalize_peak: Some(6.),
+				dither: false,
+			};
+			Song::from_wav(Song::to_wav(song.clone(), options))
+				.unwrap()
+				.samples
+		};
+		assert_eq!(decoded(Clipping::Saturate)[..2], [i16::MAX, i16::MIN]);
+		let wrapped = decoded(Clipping::Wrap);
+		assert!(wrapped[0] < 0 && wrapped[1] > 0, "{wrapped:?}");
+	}
+
+	#[test]
+	fn constellation_map_keeps_the_partial_last_slice() {
+		let config = DatabaseConfig::builder()
+			.with_hop_size(Duration::from_millis(260))
+			.build()
+			.constellation();
+		let window = 44100 * 260 / 1000;
+		// A tone followed by half a slice of silence, which mustn't inherit the tone
+		let mut song = Song::sine(1234., Duration::from_millis(5 * 260), 44100);
+		song.samples.truncate(5 * window);
+		song.samples.extend(vec![0; window / 2]);
+		assert_eq!(song.constellation_map(config).len(), 6);
+		let (spectrogram, _) = song.constellation_peaks(config);
+		assert!(spectrogram
+			.slice(5)
+			.iter()
+			.all(|&magnitude| magnitude == 0.));
+	}
+
+	#[test]
+	fn from_wav_reads_extensible_pcm() {
+		let samples = [0i16, 1000, -1000, i16::MAX, i16::MIN];
+		let data: Vec<u8> = samples
+			.iter()
+			.flat_map(|sample| sample.to_le_bytes())
+			.collect();
+		let song = Song::from_wav(extensible_wav(1, 16, &data)).unwrap();
+		assert_eq!(song.sample_rate, 44100);
+		assert_eq!(song.samples, samples);
+	}
+
+	#[test]
+	fn from_wav_reads_extensible_float() {
+		let data: Vec<u8> = [0f32, 0.5, -0.5]
+			.iter()
+			.flat_map(|sample| sample.to_le_bytes())
+			.collect();
+		let song = Song::from_wav(extensible_wav(3, 32, &data)).unwrap();
+		assert_eq!(song.samples, [0, 16384, -16384]);
+	}
+
+	#[test]
+	fn from_wav_reads_24_bit_pcm() {
+		let samples = [0i16, 1000, -1000, i16::MAX, i16::MIN];
+		// The 16-bit samples in the upper bytes, after a byte that rounds away
+		let data: Vec<u8> = samples
+			.iter()
+			.flat_map(|sample| {
+				let [low, high] = sample.to_le_bytes();
+				[0x40, low, high]
+			})
+			.collect();
+		let song = Song::from_wav(wav(1, 1, 24, &[], &data)).unwrap();
+		assert_eq!(song.samples, samples);
+		let song = Song::from_wav(extensible_wav(1, 24, &data)).unwrap();
+		assert_eq!(song.samples, samples);
+	}
+
+	#[test]
+	fn from_wav_reads_sample_rates_past_16_bits() {
+		let mut wav = wav(1, 1, 16, &[], &pcm_bytes(&vec![0; 96000 / 2]));
+		// Sample and byte rates of the `fmt ` chunk
+		wav[24..28].copy_from_slice(&96000u32.to_le_bytes());
+		wav[28..32].copy_from_slice(&(2 * 96000u32).to_le_bytes());
+		let song = Song::from_wav(wav).unwrap();
+		assert_eq!(song.sample_rate, 96000);
+		assert_eq!(song.length(), Duration::from_millis(500));
+	}
+
+	#[test]
+	fn from_wav_rejects_unknown_extensible_sub_formats() {
+		let mut wav = extensible_wav(1, 16, &[0, 0]);
+		// Last byte of the sub-format GUID
+		wav[12 + 8 + 39] ^= 0xff;
+		assert!(matches!(
+			Song::from_wav(wav),
+			Err(DecodeError::Unsupported(_))
+		));
+	}
+
+	#[test]
+	fn pcm_fingerprints_like_its_wav() {
+		let config = DatabaseConfig::builder().build();
+		let pcm = Song::pink_noise(Duration::from_secs(5), 44100, 0).samples;
+		let from_pcm = Song::from_pcm_i16(&pcm, 44100);
+		let from_wav = Song::from_wav(wav(1, 1, 16, &[], &pcm_bytes(&pcm))).unwrap();
+		assert_eq!(from_pcm.sample_rate, from_wav.sample_rate);
+		assert_eq!(from_pcm.samples, from_wav.samples);
+		let signatures: Vec<_> = config.signatures(&from_pcm).collect();
+		assert!(!signatures.is_empty());
+		assert!(signatures.into_iter().eq(config.signatures(&from_wav)));
+	}
+
+	/// Reads from `bytes`, remembering the most it was asked for at once
+	struct RecordingReader<'a> {
+		bytes: &'a [u8],
+		largest_read: usize,
+	}
+	impl Read for RecordingReader<'_> {
+		fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+			self.largest_read = self.largest_read.max(buffer.len());
+			self.bytes.read(buffer)
+		}
+	}
+
+	#[test]
+	fn streamed_wav_fingerprints_like_a_buffered_one() {
+		let config = DatabaseConfig::builder().build();
+		let song = Song::white_noise(Duration::from_secs(3 * 60), 44100, 0);
+		let wav = Song::to_wav(song, WavOptions::default());
+		let mut reader = RecordingReader {
+			bytes: &wav,
+			largest_read: 0,
+		};
+		let streamed = Song::from_wav_reader(&mut reader).unwrap();
+		// Only a block of samples is ever held besides the decoded song
+		assert!(
+			reader.largest_read <= wav.len() / 1000,
+			"read {} of {} bytes at once",
+			reader.largest_read,
+			wav.len()
+		);
+		let buffered = Song::from_wav(wav).unwrap();
+		assert_eq!(streamed.samples, buffered.samples);
+		assert!(config
+			.signatures(&streamed)
+			.eq(config.signatures(&buffered)));
+	}
+
+	/// CRC of `bytes` with `polynomial` in its low bits, as FLAC frames end with
+	fn flac_crc(bytes: &[u8], polynomial: u16, width: u32) -> u16 {
+		let top = 1 << (width - 1);
+		let mask = ((1u32 << width) - 1) as u16;
+		bytes.iter().fold(0, |mut crc, &byte| {
+			crc ^= (byte as u16) << (width - 8);
+			for _ in 0..8 {
+				crc = match crc & top {
+					0 => crc << 1,
+					_ => (crc << 1) ^ polynomial,
+				} & mask;
+			}
+			crc
+		})
+	}
+
+	/// A mono 16-bit FLAC file of `song`, every frame stored verbatim
+	fn flac(song: &Song) -> Vec<u8> {
+		const BLOCK_SIZE: usize = 4096;
+		let mut flac = b"fLaC".to_vec();
+		// The last and only metadata block, STREAMINFO
+		flac.push(0x80);
+		flac.extend(&34u32.to_be_bytes()[1..]);
+		flac.extend((BLOCK_SIZE as u16).to_be_bytes());
+		flac.extend((BLOCK_SIZE as u16).to_be_bytes());
+		// Frame sizes, unknown
+		flac.extend([0; 6]);
+		// Sample rate, channels - 1, bits per sample - 1 and samples in 20, 3, 5 and 36 bits
+		let info = (song.sample_rate as u64) << 44 | 15 << 36 | song.samples.len() as u64;
+		flac.extend(info.to_be_bytes());
+		// MD5 of the samples, unknown
+		flac.extend([0; 16]);
+		for (number, block) in song.samples.chunks(BLOCK_SIZE).enumerate() {
+			let start = flac.len();
+			// Sync code for fixed size blocks, the size follows the header and the rate is
+			// the STREAMINFO's, then mono at 16 bits
+			flac.extend([0xff, 0xf8, 0x70, 0x08]);
+			let number = char::from_u32(number as u32).unwrap();
+			flac.extend(number.encode_utf8(&mut [0; 4]).as_bytes());
+			flac.extend(((block.len() - 1) as u16).to_be_bytes());
+			flac.push(flac_crc(&flac[start..], 0x07, 8) as u8);
+			// A verbatim subframe without wasted bits
+			flac.push(0x02);
+			flac.extend(block.iter().flat_map(|sample| sample.to_be_bytes()));
+			flac.extend(flac_crc(&flac[start..], 0x8005, 16).to_be_bytes());
+		}
+		flac
+	}
+
+	#[test]
+	fn decoding_what_to_wav_wrote_keeps_the_amplitude() {
+		let noise = Song::white_noise(Duration::from_millis(200), 44100, 0);
+		// Past full scale saturates, everything else is on the one scale of ±1.0
+		let floats = [0f32, 0.25, -0.5, 0.999, -1., 1.5];
+		let float_data: Vec<u8> = floats
+			.iter()
+			.flat_map(|sample| sample.to_le_bytes())
+			.collect();
+		let deep_data: Vec<u8> = noise
+			.samples
+			.iter()
+			.flat_map(|sample| {
+				let [low, high] = sample.to_le_bytes();
+				[0x7f, low, high]
 			})
+			.collect();
+		let stereo = pcm_bytes(&[1000, -1000, i16::MAX, i16::MAX, i16::MIN, -200, 3, 4]);
+		let sources = [
+			(
+				"16-bit",
+				Song::from_wav(wav(1, 1, 16, &[], &pcm_bytes(&noise.samples))),
+			),
+			("24-bit", Song::from_wav(wav(1, 1, 24, &[], &deep_data))),
+			("float", Song::from_wav(extensible_wav(3, 32, &float_data))),
+			("stereo", Song::from_wav(wav(1, 2, 16, &[], &stereo))),
+			("FLAC", Song::from_flac(flac(&noise))),
+		];
+		for (name, decoded) in sources {
+			let decoded = decoded.unwrap();
+			let mut song = decoded.clone();
+			for _ in 0..3 {
+				song = Song::from_wav(Song::to_wav(song, WavOptions::default())).unwrap();
+				assert_eq!(song.samples, decoded.samples, "{name}");
+				assert_eq!(song.sample_rate, decoded.sample_rate, "{name}");
+			}
+			let normalized = Song::from_pcm_f32(decoded.to_pcm_f32(), decoded.sample_rate);
+			assert_eq!(normalized.samples, decoded.samples, "{name}");
+		}
+		let decoded = Song::from_wav(extensible_wav(3, 32, &float_data)).unwrap();
+		for (&source, decoded) in floats.iter().zip(decoded.to_pcm_f32()) {
+			let expected = source.min(i16::MAX as f32 / I16_SCALE);
+			assert!(
+				(decoded - expected).abs() <= 0.5 / I16_SCALE,
+				"{source} came back as {decoded}"
+			);
+		}
+		let rounded = Song::from_wav(wav(1, 1, 24, &[], &deep_data)).unwrap();
+		assert_eq!(rounded.samples, noise.samples);
+	}
+
+	#[test]
+	fn flac_matches_its_wav_source() {
+		let config = DatabaseConfig::builder().build();
+		let mut db = crate::database::Database::new(config);
+		let songs: Vec<_> = (0..2)
+			.map(|seed| Song::white_noise(Duration::from_secs(10), 44100, seed))
+			.collect();
+		for (seed, song) in songs.iter().enumerate() {
+			let name = format!("noise_{seed}.wav");
+			let wav = Song::from_wav(Song::to_wav(song.clone(), WavOptions::default())).unwrap();
+			db.add_song(name.clone().into(), config.song_data(name.as_ref(), &wav));
+		}
+		db.finalize();
+		let sample = songs[1].slice(Duration::from_secs(3), Duration::from_secs(5));
+		let decoded = Song::from_flac(flac(&sample)).unwrap();
+		assert_eq!(decoded.sample_rate, 44100);
+		assert_eq!(decoded.samples, sample.samples);
+		let matches = db.best_matches(&decoded, 2, f32::NEG_INFINITY);
+		assert_eq!(matches[0].id, 1);
+		assert_eq!(matches, db.best_matches(&sample, 2, f32::NEG_INFINITY));
+	}
+
+	/// `song` mixed with `noise_secs` of white noise over its first two seconds at 10 dB,
+	/// along with what the noise added
+	fn mix_noise(noise_secs: f64, short_noise: ShortNoise) -> Result<(Song, Vec<i32>), MixError> {
+		let song = Song::sine(440., Duration::from_secs(2), 44100);
+		let noise = Song::white_noise(Duration::from_secs_f64(noise_secs), 44100, 0);
+		let mix = Song::mix(
+			&song,
+			&noise,
+			10.,
+			Duration::ZERO,
+			Duration::from_secs(2),
+			short_noise,
+		)?;
+		let added = mix
+			.samples
+			.iter()
+			.zip(&song.samples)
+			.map(|(&mixed, &sample)| mixed as i32 - sample as i32)
+			.collect();
+		Ok((mix, added))
+	}
+
+	#[test]
+	fn mix_covers_the_segment_whatever_the_noise_length() {
+		for noise_secs in [0.5, 2., 5.] {
+			let (mix, added) = mix_noise(noise_secs, ShortNoise::Loop).unwrap();
+			assert_eq!(mix.samples.len(), 2 * 44100, "{noise_secs}s of noise");
+			let snr_db = 20. * (0.5 * I16_SCALE as f64 / 2f64.sqrt() / rms(&added)).log10();
+			assert!(
+				(snr_db - 10.).abs() < 0.1,
+				"{noise_secs}s of noise gave {snr_db}dB"
+			);
+		}
+	}
+
+	#[test]
+	fn mix_loops_short_noise() {
+		let (_, added) = mix_noise(0.5, ShortNoise::Loop).unwrap();
+		let period = 44100 / 2;
+		// Rounding either mix may be a sample off
+		assert!(added[period..]
+			.iter()
+			.zip(&added)
+			.all(|(looped, first)| looped.abs_diff(*first) <= 1));
+	}
+
+	#[test]
+	fn mix_rejects_short_noise_if_asked() {
+		assert!(matches!(
+			mix_noise(0.5, ShortNoise::Reject),
+			Err(MixError::NoiseTooShort { .. })
+		));
+		for noise_secs in [2., 5.] {
+			assert!(mix_noise(noise_secs, ShortNoise::Reject).is_ok());
+		}
+	}
+
+	#[test]
+	fn audible_range_keeps_clips_shorter_than_the_hold() {
+		let clip = Song::sine(440., Duration::from_millis(100), 44100);
+		let range = clip.audible_range(-40., Duration::from_millis(300));
+		assert_eq!(range, 0..clip.samples.len());
+	}
+
+	#[test]
+	fn audible_range_trims_leading_and_trailing_silence() {
+		let tone = Song::sine(440., Duration::from_secs(1), 44100);
+		let mut samples = vec![0; 4410];
+		samples.extend(&tone.samples);
+		samples.extend([0; 8820]);
+		let padded = Song::from_pcm_i16(&samples, 44100);
+		let range = padded.audible_range(-40., Duration::from_millis(300));
+		assert_eq!(range, 4410..4410 + tone.samples.len());
+	}
+
+	#[test]
+	fn trim_silence_leaves_the_tone() {
+		let tone = Song::sine(440., Duration::from_secs(1), 44100);
+		let mut samples = vec![0; 44100];
+		samples.extend(&tone.samples);
+		samples.extend([0; 22050]);
+		let padded = Song::from_pcm_i16(&samples, 44100);
+		let trimmed = padded.trim_silence(-40., Duration::from_millis(300));
+		assert_eq!(trimmed.sample_rate, 44100);
+		assert_eq!(trimmed.samples, tone.samples);
+		let silence = Song::from_pcm_i16(&[0; 44100], 44100);
+		assert!(silence
+			.trim_silence(-40., Duration::from_millis(300))
+			.samples
+			.is_empty());
+	}
+
+	#[test]
+	fn audible_range_of_silence_is_empty() {
+		let silence = Song::from_pcm_i16(&[0; 4410], 44100);
+		assert!(silence
+			.audible_range(-40., Duration::from_millis(300))
+			.is_empty());
 	}
 }
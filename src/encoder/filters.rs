@@ -0,0 +1,161 @@
+//! Filters run over songs before they're fingerprinted
+
+use super::{quantize, Song};
+
+/// Qs of the two sections making up a 4th order Butterworth response
+const BUTTERWORTH_QS: [f64; 2] = [0.541_196_1, 1.306_563];
+
+/// The band `band_pass` lets through
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BandPass {
+	/// Cutoff below which frequencies are attenuated, in Hz
+	pub low_hz: f32,
+	/// Cutoff above which frequencies are attenuated, in Hz
+	pub high_hz: f32,
+}
+impl std::fmt::Display for BandPass {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}-{} Hz", self.low_hz, self.high_hz)
+	}
+}
+
+/// A second order IIR section, in transposed direct form II
+#[derive(Debug, Clone, Copy)]
+pub struct Biquad {
+	b: [f64; 3],
+	a: [f64; 2],
+	state: [f64; 2],
+}
+impl Biquad {
+	/// Passes frequencies below `cutoff_hz`, `q` of `FRAC_1_SQRT_2` being Butterworth
+	pub fn low_pass(cutoff_hz: f32, q: f64, sample_rate: usize) -> Self {
+		let (cos, alpha) = Self::prewarp(cutoff_hz, q, sample_rate);
+		let b = (1. - cos) / 2.;
+		Self::new([b, 2. * b, b], [1. + alpha, -2. * cos, 1. - alpha])
+	}
+	/// Passes frequencies above `cutoff_hz`, `q` of `FRAC_1_SQRT_2` being Butterworth
+	pub fn high_pass(cutoff_hz: f32, q: f64, sample_rate: usize) -> Self {
+		let (cos, alpha) = Self::prewarp(cutoff_hz, q, sample_rate);
+		let b = (1. + cos) / 2.;
+		Self::new([b, -2. * b, b], [1. + alpha, -2. * cos, 1. - alpha])
+	}
+	/// Cosine of the cutoff's angular frequency and the bandwidth term of the cookbook
+	/// formulas
+	fn prewarp(cutoff_hz: f32, q: f64, sample_rate: usize) -> (f64, f64) {
+		let omega = std::f64::consts::TAU * cutoff_hz as f64 / sample_rate as f64;
+		(omega.cos(), omega.sin() / (2. * q))
+	}
+	/// Normalizes the coefficients by `a[0]`
+	fn new(b: [f64; 3], [a0, a1, a2]: [f64; 3]) -> Self {
+		Self {
+			b: b.map(|b| b / a0),
+			a: [a1 / a0, a2 / a0],
+			state: [0.; 2],
+		}
+	}
+	/// The next output for the next input `sample`
+	pub fn process(&mut self, sample: f64) -> f64 {
+		let output = self.b[0] * sample + self.state[0];
+		self.state[0] = self.b[1] * sample - self.a[0] * output + self.state[1];
+		self.state[1] = self.b[2] * sample - self.a[1] * output;
+		output
+	}
+}
+
+/// `song` through 4th order Butterworth high and low passes at the edges of `band`.
+/// Either edge is left open if it's at 0 Hz, or at or above the Nyquist frequency
+pub fn band_pass(song: &Song, band: BandPass) -> Song {
+	let nyquist = song.sample_rate as f32 / 2.;
+	let high_passes = BUTTERWORTH_QS
+		.map(|q| Biquad::high_pass(band.low_hz, q, song.sample_rate))
+		.into_iter()
+		.filter(|_| band.low_hz > 0.);
+	let low_passes = BUTTERWORTH_QS
+		.map(|q| Biquad::low_pass(band.high_hz, q, song.sample_rate))
+		.into_iter()
+		.filter(|_| band.high_hz < nyquist);
+	let mut sections: Vec<_> = high_passes.chain(low_passes).collect();
+	Song {
+		sample_rate: song.sample_rate,
+		samples: song
+			.samples
+			.iter()
+			.map(|&sample| {
+				let filtered = sections
+					.iter_mut()
+					.fold(sample as f64, |sample, section| section.process(sample));
+				quantize(filtered as f32)
+			})
+			.collect(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::encoder::rms;
+
+	const BAND: BandPass = BandPass {
+		low_hz: 100.,
+		high_hz: 5000.,
+	};
+
+	/// dB a sine at `freq_hz` loses through `band`, once the filter has settled
+	fn attenuation_db(freq_hz: f32, band: BandPass) -> f64 {
+		let sine = Song::sine(freq_hz, Duration::from_secs(2), 44100);
+		let filtered = band_pass(&sine, band);
+		// Skips the start, where the filter is still responding to the sine starting
+		let settled = |song: &Song| rms(&song.samples[44100 / 2..]);
+		20. * (settled(&sine) / settled(&filtered)).log10()
+	}
+
+	#[test]
+	fn passes_the_band() {
+		for freq_hz in [300., 1000., 3000.] {
+			let attenuation = attenuation_db(freq_hz, BAND);
+			assert!(attenuation.abs() < 0.5, "{freq_hz}Hz lost {attenuation}dB");
+		}
+	}
+
+	#[test]
+	fn attenuates_below_the_band() {
+		// A 4th order Butterworth falls 24 dB an octave past its cutoff
+		for (freq_hz, min_db) in [(50., 20.), (25., 40.)] {
+			let attenuation = attenuation_db(freq_hz, BAND);
+			assert!(attenuation >= min_db, "{freq_hz}Hz lost {attenuation}dB");
+		}
+	}
+
+	#[test]
+	fn attenuates_above_the_band() {
+		for (freq_hz, min_db) in [(10000., 20.), (15000., 35.)] {
+			let attenuation = attenuation_db(freq_hz, BAND);
+			assert!(attenuation >= min_db, "{freq_hz}Hz lost {attenuation}dB");
+		}
+	}
+
+	#[test]
+	fn cutoffs_lose_3_db() {
+		for freq_hz in [BAND.low_hz, BAND.high_hz] {
+			let attenuation = attenuation_db(freq_hz, BAND);
+			assert!(
+				(attenuation - 3.).abs() < 0.5,
+				"{freq_hz}Hz lost {attenuation}dB"
+			);
+		}
+	}
+
+	#[test]
+	fn open_edges_pass_everything() {
+		let open = BandPass {
+			low_hz: 0.,
+			high_hz: 22050.,
+		};
+		for freq_hz in [25., 15000.] {
+			let attenuation = attenuation_db(freq_hz, open);
+			assert!(attenuation.abs() < 0.1, "{freq_hz}Hz lost {attenuation}dB");
+		}
+	}
+}
@@ -0,0 +1,501 @@
+//! Measuring how well query clips with known answers are matched, see `evaluate`
+
+use std::path::{Path, PathBuf};
+
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use shazoom::database::{Database, Match, MatchPolicy};
+use shazoom::encoder::Song;
+
+/// Queries to evaluate a database with, as read from a manifest. Paths in a manifest
+/// are relative to the directory holding it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvalDataset {
+	pub queries: Vec<EvalQuery>,
+	/// Samples of pure noise to match as well, none of which should be identified
+	#[serde(default)]
+	pub noise_queries: usize,
+}
+
+/// A query clip and what it should match
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvalQuery {
+	pub path: PathBuf,
+	/// Name of the song the clip is cut from, `None` for clips of no song in the
+	/// database, which should not be identified
+	pub expected_song: Option<String>,
+	/// Where in the song the clip starts, if known
+	#[serde(default)]
+	pub expected_offset_secs: Option<f64>,
+	/// Signal to noise ratio in dB the clip was mixed at, queries are reported by it
+	#[serde(default)]
+	pub snr: Option<i8>,
+}
+
+impl EvalDataset {
+	/// Reads a JSON manifest, resolving its paths against its directory
+	pub fn from_manifest(path: &Path) -> Result<Self, String> {
+		let json = std::fs::read_to_string(path)
+			.map_err(|err| format!("Failed to read the manifest {path:?}, {err}"))?;
+		let mut dataset: Self = serde_json::from_str(&json)
+			.map_err(|err| format!("Failed to parse the manifest {path:?}, {err}"))?;
+		let dir = path.parent().unwrap_or(Path::new(""));
+		for query in &mut dataset.queries {
+			query.path = dir.join(&query.path);
+		}
+		Ok(dataset)
+	}
+	/// Queries at `<dir>/<song name>/...`, each subdirectory holding clips of the song
+	/// it's named after
+	pub fn from_dir(dir: &Path) -> Result<Self, String> {
+		let read_dir = |dir: &Path| -> Result<Vec<PathBuf>, String> {
+			let mut entries = std::fs::read_dir(dir)
+				.and_then(|entries| {
+					entries
+						.map(|entry| entry.map(|entry| entry.path()))
+						.collect::<std::io::Result<Vec<_>>>()
+				})
+				.map_err(|err| format!("Failed to read {dir:?}, {err}"))?;
+			entries.sort();
+			Ok(entries)
+		};
+		let mut queries = Vec::new();
+		for song_dir in read_dir(dir)?.into_iter().filter(|path| path.is_dir()) {
+			let song = song_dir.file_name().unwrap().to_string_lossy().into_owned();
+			queries.extend(
+				read_dir(&song_dir)?
+					.into_iter()
+					.filter(|path| path.is_file())
+					.map(|path| EvalQuery {
+						path,
+						expected_song: Some(song.clone()),
+						expected_offset_secs: None,
+						snr: None,
+					}),
+			);
+		}
+		Ok(Self {
+			queries,
+			noise_queries: 0,
+		})
+	}
+	/// Clips at `<samples_dir>/<snr>/<offset>.wav`, each cut from `expected_song` at
+	/// `offset` seconds and mixed with noise at `snr` dB, plus as many samples of noise
+	/// as there are offsets
+	pub fn from_snr_layout(
+		samples_dir: &Path,
+		expected_song: &str,
+		snrs: &[i8],
+		offsets: &[u64],
+	) -> Self {
+		let queries = snrs
+			.iter()
+			.flat_map(|&snr| {
+				offsets.iter().map(move |&offset| EvalQuery {
+					path: samples_dir.join(format!("{snr}/{offset}.wav")),
+					expected_song: Some(expected_song.to_owned()),
+					expected_offset_secs: Some(offset as f64),
+					snr: Some(snr),
+				})
+			})
+			.collect();
+		Self {
+			queries,
+			noise_queries: offsets.len(),
+		}
+	}
+}
+
+/// How the queries of one SNR, or those without one, fared
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupReport {
+	pub snr: Option<i8>,
+	pub queries: usize,
+	/// Mean over the queries of the expected song's share of the scores of all matches,
+	/// 0 for queries whose best match is another song or that failed
+	pub accuracy: f32,
+	/// Queries identified as the expected song under the policy
+	pub identified: usize,
+	/// Queries whose best match is the expected song within a slice of the expected
+	/// offset
+	pub positioned: usize,
+	/// Queries with an expected offset, which are the only ones that can be positioned
+	pub with_offset: usize,
+	/// Queries that failed to decode or were too short
+	pub errors: usize,
+	/// The accuracy of every query whose best match is the expected song, or 0 if it's
+	/// another
+	pub raw: Vec<f32>,
+}
+
+/// How one query was matched, kept to tell whether it's identified under other
+/// policies
+#[derive(Debug, Clone)]
+struct QueryOutcome {
+	/// The song the query should be identified as, `None` for negatives
+	expected: Option<String>,
+	/// What the confusion matrix files the query under, the song it's cut from even for
+	/// songs left out of the database
+	label: String,
+	/// The best two matches, all `MatchPolicy::pick` looks at
+	matches: Vec<Match>,
+}
+
+/// Identifications at one score ratio threshold, see `EvalReport::threshold_sweep`
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ThresholdReport {
+	pub min_score_ratio: f32,
+	/// Queries of indexed songs identified as them
+	pub correct: usize,
+	/// Queries identified as any song
+	pub identified: usize,
+	/// Queries of indexed songs
+	pub positives: usize,
+	/// Negatives identified as a song
+	pub false_positives: usize,
+	pub negatives: usize,
+}
+impl ThresholdReport {
+	/// Share of identifications that are right, `None` if there are none
+	pub fn precision(&self) -> Option<f32> {
+		ratio(self.correct, self.identified)
+	}
+	/// Share of queries of indexed songs identified right
+	pub fn recall(&self) -> Option<f32> {
+		ratio(self.correct, self.positives)
+	}
+	/// Share of negatives identified as some song
+	pub fn false_positive_rate(&self) -> Option<f32> {
+		ratio(self.false_positives, self.negatives)
+	}
+}
+
+fn ratio(count: usize, total: usize) -> Option<f32> {
+	(total > 0).then(|| count as f32 / total as f32)
+}
+
+/// Result of `evaluate`
+#[derive(Debug, Clone, Serialize)]
+pub struct EvalReport {
+	pub groups: Vec<GroupReport>,
+	/// Queries of no song in the database, including the noise samples and clips of
+	/// songs left out of it
+	pub negatives: usize,
+	/// Negatives that were identified as a song anyway
+	pub false_positives: usize,
+	#[serde(skip)]
+	outcomes: Vec<QueryOutcome>,
+	/// How long matching every decoded query of an indexed song took, not counting
+	/// decoding it
+	#[serde(skip)]
+	match_times: Vec<std::time::Duration>,
+}
+impl EvalReport {
+	/// Accuracy over the queries of every group, see `GroupReport::accuracy`
+	pub fn accuracy(&self) -> f32 {
+		let queries: usize = self.groups.iter().map(|group| group.queries).sum();
+		let total = self
+			.groups
+			.iter()
+			.fold(0., |sum, group| sum + group.accuracy * group.queries as f32);
+		total / queries.max(1) as f32
+	}
+	/// Average time matching a query took, `None` if none were matched
+	pub fn mean_match_time(&self) -> Option<std::time::Duration> {
+		let total: std::time::Duration = self.match_times.iter().sum();
+		(!self.match_times.is_empty()).then(|| total / self.match_times.len() as u32)
+	}
+	pub fn log(&self) {
+		for group in &self.groups {
+			let snr = match group.snr {
+				Some(snr) => format!("{snr} dB"),
+				None => "none".to_owned(),
+			};
+			info!(
+				"For SNR: {snr}, Accuracy: {}, Identified: {}/{}, Positioned: {}/{}, Errors: {}, \
+				 Raw: {:?}",
+				group.accuracy,
+				group.identified,
+				group.queries,
+				group.positioned,
+				group.with_offset,
+				group.errors,
+				group.raw
+			);
+		}
+		info!(
+			"Negatives, False positives: {}/{}",
+			self.false_positives, self.negatives
+		);
+	}
+	/// Identifications of the queries under `policy` with each of `min_score_ratios`
+	/// instead of its own, leaving out those that failed to decode
+	pub fn threshold_sweep(
+		&self,
+		db: &Database,
+		policy: MatchPolicy,
+		min_score_ratios: &[f32],
+	) -> Vec<ThresholdReport> {
+		min_score_ratios
+			.iter()
+			.map(|&min_score_ratio| {
+				let policy = MatchPolicy {
+					min_score_ratio,
+					..policy
+				};
+				let mut report = ThresholdReport {
+					min_score_ratio,
+					correct: 0,
+					identified: 0,
+					positives: 0,
+					false_positives: 0,
+					negatives: 0,
+				};
+				for outcome in &self.outcomes {
+					let identified = outcome.identified(db, policy);
+					report.identified += identified.is_some() as usize;
+					match &outcome.expected {
+						Some(expected) => {
+							report.positives += 1;
+							report.correct += (identified.as_ref() == Some(expected)) as usize;
+						}
+						None => {
+							report.negatives += 1;
+							report.false_positives += identified.is_some() as usize;
+						}
+					}
+				}
+				report
+			})
+			.collect()
+	}
+	/// Rows of how often queries of the song in their first column were identified as
+	/// the song heading each of the others under `policy`, the first row being the
+	/// headings
+	pub fn confusion_matrix(&self, db: &Database, policy: MatchPolicy) -> Vec<Vec<String>> {
+		const NOTHING: &str = "(nothing)";
+		let identified: Vec<_> = self
+			.outcomes
+			.iter()
+			.map(|outcome| {
+				let identified = outcome.identified(db, policy);
+				(
+					outcome.label.as_str(),
+					identified.unwrap_or(NOTHING.to_owned()),
+				)
+			})
+			.collect();
+		let mut labels: Vec<&str> = self.outcomes.iter().map(|o| o.label.as_str()).collect();
+		labels.sort();
+		labels.dedup();
+		let mut columns: Vec<&str> = identified.iter().map(|(_, song)| song.as_str()).collect();
+		columns.retain(|&column| column != NOTHING);
+		columns.sort();
+		columns.dedup();
+		columns.push(NOTHING);
+		let header = std::iter::once("expected \\ identified")
+			.chain(columns.iter().copied())
+			.map(str::to_owned)
+			.collect();
+		let rows = labels.iter().map(|&label| {
+			let counts = columns.iter().map(|&column| {
+				let count = identified
+					.iter()
+					.filter(|(row, song)| *row == label && song == column)
+					.count();
+				count.to_string()
+			});
+			std::iter::once(label.to_owned()).chain(counts).collect()
+		});
+		std::iter::once(header).chain(rows).collect()
+	}
+}
+impl QueryOutcome {
+	/// Name of the song the query is identified as under `policy`
+	fn identified(&self, db: &Database, policy: MatchPolicy) -> Option<String> {
+		let identified = policy.pick(&self.matches)?;
+		Some(db.song_name(identified.id).unwrap_or_default().into_owned())
+	}
+}
+
+/// Matches every query of `dataset` against `db`, comparing the best match with the
+/// expected song by name. Queries of songs not in `db` are negatives, so leaving songs
+/// out of it tells how often what isn't indexed is identified as something else
+pub fn evaluate(db: &Database, dataset: &EvalDataset, policy: MatchPolicy) -> EvalReport {
+	let db_config = db.config();
+	let mut unindexed: Vec<_> = dataset
+		.queries
+		.iter()
+		.filter_map(|query| query.expected_song.as_deref())
+		.filter(|&song| db.song_id(song).is_none())
+		.collect();
+	unindexed.sort();
+	unindexed.dedup();
+	for song in unindexed {
+		info!("{song:?} isn't in the database, its queries should match nothing");
+	}
+	let mut groups: Vec<GroupReport> = Vec::new();
+	let mut negatives = 0;
+	let mut false_positives = 0;
+	let mut outcomes = Vec::new();
+	let mut match_times = Vec::new();
+	for query in &dataset.queries {
+		let indexed_song = query
+			.expected_song
+			.as_ref()
+			.filter(|&song| db.song_id(song).is_some());
+		let Some(expected_song) = indexed_song else {
+			let label = query
+				.expected_song
+				.clone()
+				.unwrap_or_else(|| "(no song)".to_owned());
+			match db_config.load_song(&query.path) {
+				Ok(sample) => {
+					negatives += 1;
+					let matches = db.best_matches(&sample, 2, f32::NEG_INFINITY);
+					if let Some(m) = policy.pick(&matches) {
+						warn!(
+							"{:?} identified as {:?} though it's of no song in the database",
+							query.path,
+							db.song_path(m.id)
+						);
+						false_positives += 1;
+					}
+					outcomes.push(QueryOutcome {
+						expected: None,
+						label,
+						matches,
+					});
+				}
+				Err(err) => error!("Failed to decode {:?}, {err}", query.path),
+			}
+			continue;
+		};
+		let group = match groups.iter().position(|group| group.snr == query.snr) {
+			Some(i) => &mut groups[i],
+			None => {
+				groups.push(GroupReport {
+					snr: query.snr,
+					queries: 0,
+					accuracy: 0.,
+					identified: 0,
+					positioned: 0,
+					with_offset: 0,
+					errors: 0,
+					raw: Vec::new(),
+				});
+				groups.last_mut().unwrap()
+			}
+		};
+		group.queries += 1;
+		group.with_offset += query.expected_offset_secs.is_some() as usize;
+		let start = std::time::Instant::now();
+		let sample = match db_config.load_song(&query.path) {
+			Ok(sample) if db_config.is_too_short(&sample) => {
+				error!(
+					"Sample {:?} too short: need at least {} ms, got {} ms",
+					query.path,
+					db_config.min_sample_length().as_millis(),
+					sample.length().as_millis()
+				);
+				group.errors += 1;
+				continue;
+			}
+			Ok(sample) => sample,
+			Err(err) => {
+				error!("Failed to decode {:?}, {err}", query.path);
+				group.errors += 1;
+				continue;
+			}
+		};
+		// Accuracy is the best score's share of all of them, so none are cut
+		let match_start = std::time::Instant::now();
+		let matches = db.best_matches(&sample, usize::MAX, 0.);
+		match_times.push(match_start.elapsed());
+		let is_expected = |id| db.song_name(id).as_deref() == Some(expected_song.as_str());
+		outcomes.push(QueryOutcome {
+			expected: Some(expected_song.clone()),
+			label: expected_song.clone(),
+			matches: matches.iter().take(2).copied().collect(),
+		});
+		if policy.pick(&matches).is_some_and(|m| is_expected(m.id)) {
+			group.identified += 1;
+		}
+		if let Some(best_match) = matches.first() {
+			println!(
+				"Match for {:?}, is {}",
+				query.path,
+				db.song_display_name(best_match.id)
+					.unwrap_or_else(|| format!("unknown song {}", best_match.id))
+			);
+			if !is_expected(best_match.id) {
+				error!("Match failed!");
+				group.raw.push(0.);
+				continue;
+			}
+			info!(
+				"Best Match: {:?}, Score: {:.2}, at {:?}, aligned over {:?}",
+				db.song_path(best_match.id),
+				best_match.score,
+				best_match.offset,
+				best_match.matched_span
+			);
+			// Clips rarely start on a slice boundary, so they're placed right give or take
+			// a slice
+			if let Some(expected) = query.expected_offset_secs {
+				let expected = std::time::Duration::from_secs_f64(expected);
+				if best_match.offset.abs_diff(expected) <= db_config.slice_size() {
+					group.positioned += 1;
+				} else {
+					warn!("Placed at {:?} instead of {expected:?}", best_match.offset);
+				}
+			}
+			let mut total_score = 0.;
+			for (i, m) in matches.iter().enumerate() {
+				debug!("{i}: Match: {m:?}");
+				total_score += m.score;
+			}
+			group.raw.push(best_match.score / total_score);
+		}
+		info!("Match Count: {}, in {:?}", matches.len(), start.elapsed());
+	}
+	// Cleanest first, those of no known SNR last
+	groups.sort_by_key(|group| std::cmp::Reverse(group.snr));
+	for group in &mut groups {
+		// `sum` of no floats is -0
+		group.accuracy = group.raw.iter().fold(0., |sum, a| sum + a) / group.queries as f32;
+	}
+	for seed in 0..dataset.noise_queries as u64 {
+		negatives += 1;
+		let sample = Song::white_noise(
+			std::time::Duration::from_secs(15),
+			db_config.sample_rate(),
+			seed,
+		);
+		let matches = db.best_matches(&sample, 2, f32::NEG_INFINITY);
+		if let Some(m) = policy.pick(&matches) {
+			warn!(
+				"Noise sample {seed} identified as {:?}, score {:.2}, {} aligned",
+				db.song_path(m.id),
+				m.score,
+				m.freq
+			);
+			false_positives += 1;
+		}
+		outcomes.push(QueryOutcome {
+			expected: None,
+			label: "(noise)".to_owned(),
+			matches,
+		});
+	}
+	EvalReport {
+		groups,
+		negatives,
+		false_positives,
+		outcomes,
+		match_times,
+	}
+}
@@ -0,0 +1,118 @@
+//! Pictures of a song's spectrogram and the peaks picked from it, as plain PGM or PPM
+//! images so no image library is needed
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use shazoom::database::{DatabaseConfig, DatabaseConfigBuilder};
+use shazoom::encoder::{Freq, Song};
+
+/// Magnitudes this far below the loudest are drawn black
+const DYNAMIC_RANGE_DB: f32 = 80.;
+/// Brightest gray of the spectrogram in PGM images, leaving white to the peaks
+const PGM_MAX_GRAY: f32 = 191.;
+
+/// What the pixels of an image stand for, written next to it as `<image>.json`
+#[derive(Serialize)]
+struct Axes {
+	/// Columns, one per slice from the left
+	slices: usize,
+	/// Seconds between the starts of the slices of consecutive columns
+	hop_secs: f64,
+	/// Seconds every slice spans
+	slice_secs: f64,
+	/// Rows, one per bin of the spectrum from 0 Hz at the bottom
+	bins: usize,
+	/// Hz between the bins of consecutive rows
+	hz_per_bin: f64,
+	/// Frequencies peaks are picked between
+	peak_range_hz: (Freq, Freq),
+	/// Peaks marked
+	peaks: usize,
+	/// Whether the spectrogram is left out, only the peaks drawn
+	peaks_only: bool,
+	/// Gray levels span this many dB below the loudest magnitude
+	dynamic_range_db: f32,
+	config: DatabaseConfigBuilder,
+}
+
+/// Draws the spectrogram `song` is fingerprinted from with `config` and the peaks
+/// picked from it to `out`, a PGM image if its extension is `pgm` and PPM if `ppm`.
+/// Time runs left to right a slice per column and frequency bottom to top a bin per
+/// row, peaks marked white in PGM and red in PPM. The axes are described in
+/// `<out>.json`
+pub fn render(
+	song: &Song,
+	config: &DatabaseConfig,
+	out: &Path,
+	peaks_only: bool,
+) -> Result<(), String> {
+	let color = match out.extension().and_then(|extension| extension.to_str()) {
+		Some("ppm") => true,
+		Some("pgm") => false,
+		_ => return Err(format!("Can't write {out:?}, only .pgm and .ppm images")),
+	};
+	let constellation = config.constellation();
+	let (spectrogram, peaks) = song.constellation_peaks(constellation);
+	let (width, height) = (spectrogram.slice_count(), spectrogram.bin_count());
+	let loudest = (0..width)
+		.flat_map(|t| spectrogram.slice(t).iter().copied())
+		.fold(0f32, f32::max);
+	let max_gray = match color {
+		true => 255.,
+		false => PGM_MAX_GRAY,
+	};
+	// Row major from the top left, so the highest bin first
+	let mut grays = vec![0u8; width * height];
+	if !peaks_only {
+		for t in 0..width {
+			for (bin, &magnitude) in spectrogram.slice(t).iter().enumerate() {
+				let db = 20. * (magnitude / loudest).max(f32::MIN_POSITIVE).log10();
+				let level = (1. + db / DYNAMIC_RANGE_DB).clamp(0., 1.);
+				grays[(height - 1 - bin) * width + t] = (level * max_gray) as u8;
+			}
+		}
+	}
+	let mut marked = vec![false; width * height];
+	for (t, slice_peaks) in peaks.iter().enumerate() {
+		for peak in slice_peaks {
+			marked[(height - 1 - peak.bin) * width + t] = true;
+		}
+	}
+	let pixels = grays.iter().zip(&marked);
+	let mut image = match color {
+		true => format!("P6\n{width} {height}\n255\n").into_bytes(),
+		false => format!("P5\n{width} {height}\n255\n").into_bytes(),
+	};
+	match color {
+		true => image.extend(pixels.flat_map(|(&gray, &marked)| match marked {
+			true => [255, 0, 0],
+			false => [gray; 3],
+		})),
+		false => image.extend(pixels.map(|(&gray, &marked)| match marked {
+			true => 255,
+			false => gray,
+		})),
+	}
+	std::fs::write(out, image).map_err(|err| format!("Failed to write {out:?}, {err}"))?;
+	let axes = Axes {
+		slices: width,
+		hop_secs: constellation.hop_size.as_secs_f64(),
+		slice_secs: constellation.slice_size.as_secs_f64(),
+		bins: height,
+		hz_per_bin: spectrogram.sample_rate as f64 / spectrogram.fft_size as f64,
+		peak_range_hz: (
+			constellation.min_freq,
+			constellation.min_freq + constellation.bucket_size * constellation.bucket_count as Freq,
+		),
+		peaks: peaks.iter().map(Vec::len).sum(),
+		peaks_only,
+		dynamic_range_db: DYNAMIC_RANGE_DB,
+		config: DatabaseConfigBuilder::from(*config),
+	};
+	let mut axes_path = out.as_os_str().to_owned();
+	axes_path.push(".json");
+	let json = serde_json::to_string_pretty(&axes).expect("axes always serialize");
+	std::fs::write(&axes_path, json).map_err(|err| format!("Failed to write {axes_path:?}, {err}"))
+}
@@ -0,0 +1,30 @@
+//! Music recognition by audio fingerprinting. Songs are reduced to signatures pairing
+//! the peaks of their spectrogram, which a `Database` indexes so a short sample of one
+//! can be matched back to it, along with where in it the sample starts
+//!
+//! ```no_run
+//! use shazoom::{DatabaseBuilder, DatabaseConfig};
+//!
+//! let config = DatabaseConfig::builder().build();
+//! let mut builder = DatabaseBuilder::new(config, "songs", Some("cache"));
+//! builder.add_song("songs/track.wav").unwrap();
+//! let (mut db, _failed) = builder.build(config);
+//! db.finalize();
+//! let sample = config.load_song("sample.wav".as_ref()).unwrap();
+//! if let Some(best) = db.best_match(&sample) {
+//!     println!("{:?} at {:?}", db.song_path(best.id), best.offset);
+//! }
+//! ```
+
+#![warn(missing_docs)]
+
+pub mod database;
+pub mod encoder;
+pub mod metadata;
+
+pub use database::{
+	ConfigError, Database, DatabaseBuilder, DatabaseConfig, DatabaseConfigBuilder, Match,
+	MatchPolicy, MatchSession,
+};
+pub use encoder::Song;
+pub use metadata::SongMeta;
@@ -0,0 +1,122 @@
+//! Capture of samples from an input device, such as a laptop's microphone
+
+use std::sync::mpsc;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use log::{info, warn};
+
+use shazoom::encoder::Song;
+
+/// How long capturing may go without the device delivering any samples before it's
+/// given up on
+const STALL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Names of the input devices of the default host
+pub fn device_names() -> Result<Vec<String>, String> {
+	let devices = cpal::default_host()
+		.input_devices()
+		.map_err(|err| format!("Failed to list the input devices, {err}"))?;
+	Ok(devices
+		.map(|device| {
+			device
+				.name()
+				.unwrap_or_else(|err| format!("<unnamed, {err}>"))
+		})
+		.collect())
+}
+
+/// The input device named `name`, or the default one
+fn input_device(name: Option<&str>) -> Result<cpal::Device, String> {
+	let host = cpal::default_host();
+	let Some(name) = name else {
+		return host.default_input_device().ok_or_else(|| {
+			"No default input device, pick one with --device, see --list-devices".to_owned()
+		});
+	};
+	host.input_devices()
+		.map_err(|err| format!("Failed to list the input devices, {err}"))?
+		.find(|device| device.name().is_ok_and(|device_name| device_name == name))
+		.ok_or_else(|| format!("No input device named {name:?}, see --list-devices"))
+}
+
+/// Records `duration` from the input device named `device`, or the default one, mixed
+/// down to mono at the device's native rate
+pub fn capture(device: Option<&str>, duration: std::time::Duration) -> Result<Song, String> {
+	let device = input_device(device)?;
+	let device_name = device.name().unwrap_or_else(|_| "input device".to_owned());
+	let config = device
+		.default_input_config()
+		.map_err(|err| format!("Failed to query {device_name:?}, {err}"))?;
+	let sample_rate = config.sample_rate().0 as usize;
+	let channels = config.channels() as usize;
+	info!("Capturing from {device_name:?} at {sample_rate}Hz, {channels} channels");
+	let (sender, receiver) = mpsc::channel();
+	let stream = match config.sample_format() {
+		cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, sender),
+		cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, sender),
+		cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, sender),
+		cpal::SampleFormat::I32 => build_stream::<i32>(&device, &config, sender),
+		format => {
+			return Err(format!(
+				"{device_name:?} has unsupported sample format {format:?}"
+			))
+		}
+	}
+	.map_err(|err| format!("Failed to open {device_name:?}, {err}"))?;
+	stream
+		.play()
+		.map_err(|err| format!("Failed to start capturing from {device_name:?}, {err}"))?;
+	info!("Listening for {duration:?}...");
+	let wanted = (duration.as_secs_f64() * sample_rate as f64) as usize;
+	let mut samples = Vec::with_capacity(wanted);
+	while samples.len() < wanted {
+		let chunk = match receiver.recv_timeout(STALL_TIMEOUT) {
+			Ok(Ok(chunk)) => chunk,
+			Ok(Err(err)) => return Err(format!("Capturing from {device_name:?} failed, {err}")),
+			Err(_) => {
+				return Err(format!(
+					"{device_name:?} delivered nothing for {STALL_TIMEOUT:?}, check it's not \
+					 muted or in use, and that this program may use it"
+				))
+			}
+		};
+		// Interleaved frames, mixed down like decoded songs are
+		samples.extend(
+			chunk
+				.chunks_exact(channels)
+				.map(|frame| frame.iter().sum::<f32>() / channels as f32),
+		);
+	}
+	drop(stream);
+	samples.truncate(wanted);
+	// Denied access to the microphone often shows as silence rather than an error
+	if samples.iter().all(|&sample| sample == 0.) {
+		warn!("Captured only silence, check the device isn't muted and may be used");
+	}
+	Ok(Song::from_pcm_f32(samples, sample_rate))
+}
+
+/// Opens a stream sending every callback's samples, converted to ±1.0, or the error the
+/// device reports
+fn build_stream<T>(
+	device: &cpal::Device,
+	config: &cpal::SupportedStreamConfig,
+	sender: mpsc::Sender<Result<Vec<f32>, cpal::StreamError>>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+	T: cpal::SizedSample,
+	f32: cpal::FromSample<T>,
+{
+	let error_sender = sender.clone();
+	device.build_input_stream(
+		&config.config(),
+		move |data: &[T], _: &cpal::InputCallbackInfo| {
+			// Fails once capturing is done and the receiver is gone
+			let _ = sender.send(Ok(data.iter().map(|&sample| sample.to_sample()).collect()));
+		},
+		move |err| {
+			let _ = error_sender.send(Err(err));
+		},
+		None,
+	)
+}
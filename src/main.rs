@@ -1,47 +1,1666 @@
-#![feature(stmt_expr_attributes, let_chains, os_str_display)]
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use log::{error, info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
 
-use std::io::Write;
-
-use clap::Parser;
-use log::{debug, error, info};
-
-mod database;
-mod encoder;
+mod cache;
+mod eval;
+mod inspect;
+#[cfg(feature = "mic")]
+mod listen;
+mod repl;
+mod serve;
 mod testing;
+mod tune;
 
-use crate::encoder::{Freq, TimeStamp};
+use shazoom::encoder::{Bucketing, Freq, PeakPicking, TimeStamp, Window};
+use shazoom::{database, encoder, metadata};
+
+#[derive(Subcommand, Clone)]
+pub enum Command {
+	/// Fingerprint the songs directory, caching every song's signatures, and write the
+	/// database to `--db-file` if given
+	Build,
+	/// Print the songs the given samples match best, exiting with a failure unless
+	/// every one of them was identified
+	Match {
+		/// Samples to match, `-` reads a WAV file from stdin
+		#[arg(required_unless_present = "dir")]
+		files: Vec<std::path::PathBuf>,
+		/// Match every audio file in this directory in parallel, then print a summary
+		/// table of them
+		#[arg(long)]
+		dir: Option<std::path::PathBuf>,
+		/// Matches to list for every sample
+		#[arg(long, default_value_t = 5)]
+		top: usize,
+		#[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+		output: OutputFormat,
+		/// Also write the summary table of `--dir` to a file, as `csv=<path>`
+		#[arg(long, requires = "dir", value_parser = parse_report)]
+		report: Option<std::path::PathBuf>,
+		/// Also show how every sample lines up with the song of this name, matched or
+		/// not: where its signatures the song holds fall and which offsets they vote for
+		#[arg(long, conflicts_with = "dir")]
+		explain: Option<String>,
+		/// Write the votes the `--top` matches of every sample got for each offset to
+		/// `<this>/<sample file name>/<rank>-<song>.csv`, as `offset_seconds,count`
+		#[arg(long, conflicts_with = "dir")]
+		dump_histograms: Option<std::path::PathBuf>,
+	},
+	/// Measure how well samples with known answers are matched. Without a manifest or
+	/// queries directory, those are samples cut from a song and mixed with noise
+	Evaluate {
+		/// JSON listing the `queries`, each a `path` and the `expected_song` it's cut from
+		#[arg(long, conflicts_with = "queries_dir")]
+		manifest: Option<std::path::PathBuf>,
+		/// Holds the queries at `<song name>/...`, each cut from the song named
+		#[arg(long)]
+		queries_dir: Option<std::path::PathBuf>,
+		/// Songs to leave out of the database, so their queries should match nothing
+		#[arg(long, value_delimiter = ',')]
+		held_out: Vec<String>,
+		/// Score ratio thresholds to tell precision and recall at, see
+		/// `--min-score-ratio`
+		#[arg(long, value_delimiter = ',', default_values_t = [1., 1.1, 1.25, 1.5, 2., 3., 5.])]
+		thresholds: Vec<f32>,
+		/// Write the precision and recall at every threshold to
+		/// `precision_recall.csv` here, and the confusion matrix to `confusion.csv`
+		#[arg(long)]
+		csv_dir: Option<std::path::PathBuf>,
+		/// Holds the samples at `{snr}/{offset}.wav`
+		#[arg(long, default_value = "test")]
+		samples_dir: std::path::PathBuf,
+		/// Name of the song in the songs directory the samples are cut from
+		#[arg(long, default_value = "song1.wav")]
+		expected_song: String,
+		/// Signal to noise ratios in dB the samples were mixed at
+		#[arg(long, value_delimiter = ',', allow_hyphen_values = true, default_values_t = testing::SNRS)]
+		snrs: Vec<i8>,
+		/// Seconds into the song the samples start at
+		#[arg(long, value_delimiter = ',', default_values_t = testing::OFFSETS)]
+		offsets: Vec<u64>,
+	},
+	/// Build and evaluate every config of a grid, then rank them by accuracy. Each result
+	/// is saved to the output directory, and those already there are reused, so an
+	/// interrupted sweep resumes. Songs are fingerprinted once per config, cached like
+	/// `build` does
+	Tune {
+		/// TOML or JSON listing values to try for fields of the config, named as in
+		/// `--config` files, like `freq_per_slice = [6, 8]`. The tuning flags give the
+		/// rest of it
+		#[arg(long)]
+		grid: std::path::PathBuf,
+		/// JSON manifest of the queries to evaluate every config with, see `evaluate`
+		#[arg(long)]
+		queries: std::path::PathBuf,
+		/// Holds every config's result along with `ranking.csv`, the ranking of them all.
+		/// Sweeps with other queries or matching flags need another one
+		#[arg(long, default_value = "tune")]
+		out: std::path::PathBuf,
+	},
+	/// Write samples of songs mixed with noise for `evaluate`, at
+	/// `<out>/<song>/<snr>/<offset>.wav`, along with a `manifest.json` describing them
+	GenFixtures {
+		/// Songs to cut the samples from, named by their file name like a database would
+		#[arg(long, required = true)]
+		song: Vec<std::path::PathBuf>,
+		/// Recording mixed into the samples from its start, generated noise if not given
+		#[arg(long)]
+		noise: Option<std::path::PathBuf>,
+		/// Kind of noise generated without `--noise`
+		#[arg(long, value_enum, default_value_t = NoiseColor::White, conflicts_with = "noise")]
+		noise_color: NoiseColor,
+		/// Seed of the noise generated without `--noise`, the same one giving the same noise
+		#[arg(long, default_value_t = 0, conflicts_with = "noise")]
+		noise_seed: u64,
+		#[arg(long, default_value = "test")]
+		out: std::path::PathBuf,
+		/// Signal to noise ratios in dB to mix the samples at
+		#[arg(long, value_delimiter = ',', allow_hyphen_values = true, default_values_t = testing::SNRS)]
+		snrs: Vec<i8>,
+		/// Seconds into the songs to start the samples at
+		#[arg(long, value_delimiter = ',', default_values_t = testing::OFFSETS)]
+		offsets: Vec<u64>,
+		/// Seconds every sample lasts
+		#[arg(long, default_value_t = 15)]
+		duration: u64,
+		/// What to do with noise shorter than the samples
+		#[arg(long, value_enum, default_value_t = encoder::ShortNoise::Loop)]
+		short_noise: encoder::ShortNoise,
+	},
+	/// Record a sample from a microphone and print the songs it matches best, exiting
+	/// with a failure unless it was identified. Needs the `mic` feature
+	Listen {
+		/// Seconds to record
+		#[arg(long, default_value_t = 12.)]
+		seconds: f64,
+		/// Name of the input device to record from, the default one if not given
+		#[arg(long)]
+		device: Option<String>,
+		/// Print the names of the input devices instead of recording
+		#[arg(long)]
+		list_devices: bool,
+		/// Matches to list
+		#[arg(long, default_value_t = 5)]
+		top: usize,
+		#[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+		output: OutputFormat,
+	},
+	/// Load the database once, then match samples and adjust settings by commands read
+	/// from stdin, see `help` there
+	Repl {
+		/// Matches to list for every sample, until changed with `top`
+		#[arg(long, default_value_t = 5)]
+		top: usize,
+	},
+	/// Answer identification requests over HTTP, see `serve::serve` for the endpoints
+	Serve {
+		/// Address to listen on
+		#[arg(long, default_value = "127.0.0.1:8080")]
+		bind: String,
+		/// Matches to return for every sample, unless the request asks for another number
+		#[arg(long, default_value_t = 5)]
+		top: usize,
+		/// Largest sample accepted, in bytes
+		#[arg(long, default_value_t = 16 * 1024 * 1024)]
+		max_body_size: usize,
+	},
+	/// Find songs of the database that are the same recording, such as one indexed
+	/// twice under other names, re-encoded or trimmed, by matching every song against
+	/// the rest
+	Dedupe {
+		/// Fewest of a song's signatures, as a fraction of all of them, that have to line
+		/// up with another song for the two to be reported
+		#[arg(long, default_value_t = 0.15)]
+		min_ratio: f32,
+		#[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+		output: OutputFormat,
+	},
+	/// Print how far into one recording another starts, such as two of the same
+	/// concert, exiting with a failure if they don't line up at all
+	Align {
+		a: std::path::PathBuf,
+		/// Recording placed in `a`, starting before it if the offset is negative
+		b: std::path::PathBuf,
+		#[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+		output: OutputFormat,
+	},
+	/// Draw the spectrogram of a song and the peaks picked from it to a PPM or PGM
+	/// image, describing its axes in `<out>.json`
+	Inspect {
+		file: std::path::PathBuf,
+		/// Image to write, PGM if its extension is `pgm` and PPM if `ppm`. Defaults to
+		/// the file's name with a `ppm` extension, in the working directory
+		#[arg(long)]
+		out: Option<std::path::PathBuf>,
+		/// Draw only the peaks, on black
+		#[arg(long)]
+		peaks_only: bool,
+	},
+	/// Inspect or clean up the fingerprint cache
+	Cache {
+		#[command(subcommand)]
+		command: cache::CacheCommand,
+	},
+}
 
 #[derive(Parser, Clone)]
 pub struct Args {
-	#[arg(short, long, default_value_t = 260)]
+	#[command(subcommand)]
+	pub command: Command,
+	/// Read the tuning flags from this TOML or JSON file, like the one written next to
+	/// `--db-file`. Flags given as well override it
+	#[arg(global = true, long)]
+	pub config: Option<std::path::PathBuf>,
+	#[arg(global = true, short, long, default_value_t = 260)]
 	pub ms_timeslice_size: u64,
-	#[arg(short, long, default_value_t = 8)]
+	/// Length of the per slice FFT, must be a power of two holding a whole slice
+	#[arg(global = true, long, default_value_t = 16384)]
+	pub fft_size: usize,
+	/// Milliseconds between the starts of consecutive slices, defaults to half a slice
+	#[arg(global = true, long)]
+	pub hop_ms: Option<u64>,
+	#[arg(global = true, short, long, default_value_t = 8)]
 	pub freq_per_slice: usize,
-	#[arg(short, long, default_value_t = 180)]
+	#[arg(global = true, short, long, default_value_t = 180)]
 	pub size_bucket: Freq,
-	#[arg(short, long, default_value_t = 20)]
+	#[arg(global = true, short, long, default_value_t = 20)]
 	pub count_bucket: usize,
-	#[arg(short, long, default_value_t = 10)]
-	pub width_target_zone: TimeStamp,
-	#[arg(short, long, default_value_t = 900)]
+	/// Milliseconds the target zone spans, rounded to a whole number of hops
+	#[arg(global = true, short, long, default_value_t = 1300)]
+	pub width_target_zone: u64,
+	#[arg(global = true, short, long, default_value_t = 900)]
 	pub target_zone_height: Freq,
-	#[arg(long, default_value_t = String::from("test"))]
+	/// Slices between an anchor and the first slice of its target zone
+	#[arg(global = true, long, default_value_t = 1)]
+	pub target_zone_offset: TimeStamp,
+	/// Most signatures a single anchor may pair into, unlimited by default
+	#[arg(global = true, long)]
+	pub max_fanout_per_anchor: Option<usize>,
+	/// Most signatures kept per second of audio, capping each slice so dense songs
+	/// don't pile up chance votes. Unlimited by default
+	#[arg(global = true, long)]
+	pub max_signatures_per_second: Option<usize>,
+	/// Round peak frequencies to the nearest multiple of this many Hz
+	#[arg(global = true, long, default_value_t = 1)]
+	pub freq_fuzz: Freq,
+	/// Drop a song's repeats of a signature within this many slices, 0 only drops exact
+	/// duplicates
+	#[arg(global = true, long, default_value_t = 0)]
+	pub dedupe_window: TimeStamp,
+	/// Every song is resampled to this rate before fingerprinting
+	#[arg(global = true, long, default_value_t = 44100)]
+	pub sample_rate: usize,
+	/// Lowest frequency considered when picking peaks, in Hz
+	#[arg(global = true, long, default_value_t = 100)]
+	pub min_freq: Freq,
+	#[arg(global = true, long, value_enum, default_value_t = Window::Hann)]
+	pub window: Window,
+	#[arg(global = true, long, value_enum, default_value_t = Bucketing::Linear)]
+	pub bucketing: Bucketing,
+	/// Drop peaks below this multiple of their slice's mean amplitude, 0 keeps them all
+	#[arg(global = true, long, default_value_t = 2.)]
+	pub noise_floor: f32,
+	/// Width in Hz of the spectral whitening neighbourhood, 0 disables whitening
+	#[arg(global = true, long, default_value_t = 0)]
+	pub whitening_width: Freq,
+	#[arg(global = true, long, value_enum, default_value_t = PeakPicking::Bucket)]
+	pub peak_picking: PeakPicking,
+	/// Slices either side a local maximum must dominate
+	#[arg(global = true, long, default_value_t = 2)]
+	pub peak_time_radius: usize,
+	/// Hz either side a local maximum must dominate
+	#[arg(global = true, long, default_value_t = 30)]
+	pub peak_freq_radius: Freq,
+	#[arg(global = true, long, default_value_t = String::from("test"))]
 	pub songs_dir: String,
-	#[arg(long, default_value_t = String::from("cache"))]
+	/// Directories to descend below the songs directory, 0 only adds its top level
+	#[arg(global = true, long, default_value_t = 0)]
+	pub max_depth: usize,
+	/// Extensions of the files in the songs directory treated as songs, all those a
+	/// decoder is built for by default
+	#[arg(global = true, long, value_delimiter = ',', default_values = encoder::Song::SUPPORTED_EXTENSIONS)]
+	pub extensions: Vec<String>,
+	/// Skip songs whose path relative to the songs directory matches this glob, may be
+	/// given more than once
+	#[arg(global = true, long)]
+	pub exclude: Vec<String>,
+	#[arg(global = true, long, default_value_t = String::from("cache"))]
 	pub cache_dir: String,
+	/// Load the built database from this file, building and saving it there if it's
+	/// missing or was built with another config. Remove it to pick up changed songs
+	#[arg(global = true, long)]
+	pub db_file: Option<String>,
+	/// Threads to build and match with, by default as many as there are cores or
+	/// `RAYON_NUM_THREADS` says
+	#[arg(global = true, long)]
+	pub threads: Option<std::num::NonZeroUsize>,
+	/// Print statistics of the database's index as JSON once it's ready
+	#[arg(global = true, long)]
+	pub stats: bool,
+	/// Slices either side of an offset whose matches count towards it, as samples rarely
+	/// start on a slice boundary
+	#[arg(global = true, long, default_value_t = database::DEFAULT_OFFSET_TOLERANCE)]
+	pub offset_tolerance: database::Offset,
+	/// Count every signature a sample shares with a song alike, instead of weighting
+	/// the ones few songs hold higher
+	#[arg(global = true, long)]
+	pub unweighted_scoring: bool,
+	/// Let every slice of a sample vote once for an offset, however many of its
+	/// signatures line up there
+	#[arg(global = true, long)]
+	pub one_vote_per_slice: bool,
+	/// Trim leading and trailing silence quieter than this many dBFS off samples before
+	/// matching them. Songs are never trimmed, so offsets into them keep their meaning
+	#[arg(global = true, long, allow_hyphen_values = true)]
+	pub trim_silence: Option<f32>,
+	/// Milliseconds of sound louder than `--trim-silence` that end the silence, so clicks
+	/// in it don't
+	#[arg(global = true, long, default_value_t = 50)]
+	pub silence_hold_ms: u64,
+	/// Match only the first this many seconds of samples, once trimmed of silence if
+	/// `--trim-silence` is given, sparing long recordings fingerprinting all of them
+	#[arg(global = true, long, value_parser = parse_query_duration)]
+	pub query_duration: Option<std::time::Duration>,
+	/// Stop matching a sample once a song lines up this many times the signatures the
+	/// runner up does, and at least `--early-exit-min-aligned`. Checked every
+	/// `--early-exit-every` slices, matching samples whole if not given
+	#[arg(global = true, long)]
+	pub early_exit: Option<f32>,
+	#[arg(global = true, long, default_value_t = 8)]
+	pub early_exit_every: usize,
+	#[arg(global = true, long, default_value_t = 200)]
+	pub early_exit_min_aligned: usize,
+	/// Band-pass samples to `<low>,<high>` Hz before matching them, cutting hum and hiss
+	/// that only add noise peaks. Given alone it's 100,5000, and `evaluate` then reports
+	/// accuracy both with and without it
+	#[arg(
+		global = true,
+		long,
+		num_args = 0..=1,
+		require_equals = true,
+		default_missing_value = "100,5000",
+		value_parser = parse_band_pass
+	)]
+	pub bandpass: Option<encoder::filters::BandPass>,
+	/// Fewest signatures that must line up with a song for it to be identified
+	#[arg(global = true, long, default_value_t = 50)]
+	pub min_aligned_matches: usize,
+	/// How many times the runner up's score the best match's must be for it to be
+	/// identified
+	#[arg(global = true, long, default_value_t = 2.)]
+	pub min_score_ratio: f32,
+	/// zstd compress newly written cache files
+	#[arg(global = true, long)]
+	pub cache_compression: bool,
+	/// Add a song each time the same file is found, such as through symlinks, instead of
+	/// only the first time
+	#[arg(global = true, long)]
+	pub allow_duplicates: bool,
+	/// Write the spans of building and matching to this file as a Chrome trace, to be
+	/// opened in chrome://tracing or Perfetto
+	#[arg(global = true, long)]
+	pub trace_json: Option<std::path::PathBuf>,
+}
+
+/// Prints logs filtered by `RUST_LOG` to stderr, along with writing every span to
+/// `trace_json` if given. The trace is complete once the returned guard drops
+fn init_tracing(trace_json: Option<&std::path::Path>) -> Option<tracing_chrome::FlushGuard> {
+	use tracing_subscriber::prelude::*;
+	let (chrome_layer, guard) = match trace_json {
+		Some(path) => {
+			let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new()
+				.file(path)
+				.include_args(true)
+				.build();
+			(Some(layer), Some(guard))
+		}
+		None => (None, None),
+	};
+	// Filtering only the printed logs, so the trace has every span whatever the level
+	tracing_subscriber::registry()
+		.with(
+			tracing_subscriber::fmt::layer()
+				.with_writer(std::io::stderr)
+				.with_filter(tracing_subscriber::EnvFilter::from_default_env()),
+		)
+		.with(chrome_layer)
+		.init();
+	guard
+}
+
+/// Exit code when the tuning flags or config file describe an invalid config, telling it
+/// apart from clap's 2 for bad usage and the 1 of a sample without a match
+const INVALID_CONFIG_EXIT_CODE: u8 = 3;
+
+fn main() -> std::process::ExitCode {
+	let matches = Args::command().get_matches();
+	let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+	// Flushes the trace once main returns
+	let _trace_guard = init_tracing(args.trace_json.as_deref());
+	let base = match base_config(&args) {
+		Ok(base) => base,
+		Err(err) => {
+			error!("{err}");
+			return std::process::ExitCode::FAILURE;
+		}
+	};
+	let db_config = match db_config(&args, &matches, base.as_ref()) {
+		Ok(db_config) => db_config,
+		Err(err) => {
+			error!("Invalid config, {err}");
+			return std::process::ExitCode::from(INVALID_CONFIG_EXIT_CODE);
+		}
+	};
+	info!("{db_config:?} at {:?}", db_config.cached_dir_name());
+	let thread_pool = match args.threads {
+		Some(threads) => match rayon::ThreadPoolBuilder::new()
+			.num_threads(threads.get())
+			.build()
+		{
+			Ok(thread_pool) => Some(std::sync::Arc::new(thread_pool)),
+			Err(err) => {
+				error!("Failed to start {threads} threads, {err}");
+				return std::process::ExitCode::FAILURE;
+			}
+		},
+		None => None,
+	};
+	info!(
+		"Using {} threads",
+		thread_pool
+			.as_ref()
+			.map_or_else(rayon::current_num_threads, |thread_pool| {
+				thread_pool.current_num_threads()
+			})
+	);
+	let song_filter = database::SongFilter {
+		max_depth: args.max_depth,
+		extensions: args.extensions.clone(),
+		exclude: args.exclude.clone(),
+	};
+	let policy = database::MatchPolicy {
+		min_aligned: args.min_aligned_matches,
+		min_score_ratio: args.min_score_ratio,
+	};
+	match &args.command {
+		Command::Build => {
+			let db = build_database(&args, &song_filter, db_config, thread_pool.as_ref());
+			if let Some(path) = &args.db_file {
+				save_database(&db, path);
+			}
+			if args.stats {
+				print_stats(&db);
+			}
+		}
+		Command::Match {
+			files,
+			dir,
+			top,
+			output,
+			report,
+			explain,
+			dump_histograms,
+		} => {
+			let db = database(&args, &song_filter, db_config, thread_pool.as_ref());
+			let explain = match explain.as_deref().map(|song| (song, db.song_id(song))) {
+				Some((song, None)) => {
+					error!("No song named {song:?} in the database to explain");
+					return std::process::ExitCode::FAILURE;
+				}
+				Some((_, id)) => id,
+				None => None,
+			};
+			let mut identified = true;
+			for file in files {
+				identified &= match_file(
+					&db,
+					file,
+					*top,
+					policy,
+					*output,
+					explain,
+					dump_histograms.as_deref(),
+				);
+			}
+			if let Some(dir) = dir {
+				identified &= match_dir(&db, dir, *top, policy, *output, report.as_deref());
+			}
+			if !identified {
+				return std::process::ExitCode::FAILURE;
+			}
+		}
+		Command::Evaluate {
+			manifest,
+			queries_dir,
+			samples_dir,
+			expected_song,
+			snrs,
+			offsets,
+			held_out,
+			thresholds,
+			csv_dir,
+		} => {
+			let dataset = match (manifest, queries_dir) {
+				(Some(manifest), _) => eval::EvalDataset::from_manifest(manifest),
+				(None, Some(queries_dir)) => eval::EvalDataset::from_dir(queries_dir),
+				(None, None) => Ok(eval::EvalDataset::from_snr_layout(
+					samples_dir,
+					expected_song,
+					snrs,
+					offsets,
+				)),
+			};
+			let dataset = match dataset {
+				Ok(dataset) => dataset,
+				Err(err) => {
+					error!("{err}");
+					return std::process::ExitCode::FAILURE;
+				}
+			};
+			let mut db = database(&args, &song_filter, db_config, thread_pool.as_ref());
+			for song in held_out {
+				match db.song_id(song) {
+					Some(id) => {
+						db.remove_song(id);
+					}
+					None => warn!("Held out song {song:?} isn't in the database anyway"),
+				}
+			}
+			db.finalize();
+			if let Some(band_pass) = db.band_pass() {
+				db.set_band_pass(None);
+				info!("Without the band-pass:");
+				eval::evaluate(&db, &dataset, policy).log();
+				db.set_band_pass(Some(band_pass));
+				info!("With the {band_pass} band-pass:");
+			}
+			let report = eval::evaluate(&db, &dataset, policy);
+			report.log();
+			let sweep = report.threshold_sweep(&db, policy, thresholds);
+			let fraction =
+				|fraction: Option<f32>| fraction.map_or(String::new(), |f| format!("{f:.4}"));
+			let sweep_rows: Vec<_> = std::iter::once(
+				[
+					"min_score_ratio",
+					"precision",
+					"recall",
+					"false_positive_rate",
+					"correct",
+					"identified",
+					"positives",
+					"false_positives",
+					"negatives",
+				]
+				.map(String::from),
+			)
+			.chain(sweep.iter().map(|threshold| {
+				[
+					threshold.min_score_ratio.to_string(),
+					fraction(threshold.precision()),
+					fraction(threshold.recall()),
+					fraction(threshold.false_positive_rate()),
+					threshold.correct.to_string(),
+					threshold.identified.to_string(),
+					threshold.positives.to_string(),
+					threshold.false_positives.to_string(),
+					threshold.negatives.to_string(),
+				]
+			}))
+			.collect();
+			let confusion = report.confusion_matrix(&db, policy);
+			print_table(&sweep_rows);
+			println!();
+			println!("Identified at a score ratio of {}:", policy.min_score_ratio);
+			print_table(&confusion);
+			if let Some(csv_dir) = csv_dir {
+				for (name, rows) in [
+					("precision_recall.csv", to_csv(&sweep_rows)),
+					("confusion.csv", to_csv(&confusion)),
+				] {
+					let path = csv_dir.join(name);
+					if let Err(err) = std::fs::write(&path, rows) {
+						error!("Failed to write {path:?}, {err}");
+						return std::process::ExitCode::FAILURE;
+					}
+				}
+				info!("Wrote the CSVs to {csv_dir:?}");
+			}
+		}
+		Command::Tune { grid, queries, out } => {
+			let loaded = tune::Grid::read(grid).and_then(|grid| {
+				eval::EvalDataset::from_manifest(queries).map(|dataset| (grid, dataset))
+			});
+			let (grid, dataset) = match loaded {
+				Ok(loaded) => loaded,
+				Err(err) => {
+					error!("{err}");
+					return std::process::ExitCode::FAILURE;
+				}
+			};
+			let base = config_builder(&args, &matches, base.as_ref());
+			let results = tune::sweep(&grid, base, out, |builder, config| {
+				let start = std::time::Instant::now();
+				let mut db = build_database(&args, &song_filter, config, thread_pool.as_ref());
+				let build_time = start.elapsed();
+				apply_match_settings(&mut db, &args, thread_pool.as_ref());
+				let report = eval::evaluate(&db, &dataset, policy);
+				tune::TuneResult::new(builder, &report, &db.stats(0), build_time)
+			});
+			let results = match results {
+				Ok(results) => results,
+				Err(err) => {
+					error!("{err}");
+					return std::process::ExitCode::FAILURE;
+				}
+			};
+			let ranking = tune::ranking(&grid, &results);
+			print_table(&ranking);
+			let path = out.join("ranking.csv");
+			match std::fs::write(&path, to_csv(&ranking)) {
+				Ok(()) => info!("Wrote the ranking of {} configs to {path:?}", results.len()),
+				Err(err) => {
+					error!("Failed to write the ranking to {path:?}, {err}");
+					return std::process::ExitCode::FAILURE;
+				}
+			}
+		}
+		Command::GenFixtures {
+			song,
+			noise,
+			out,
+			snrs,
+			offsets,
+			duration,
+			short_noise,
+			noise_color,
+			noise_seed,
+		} => {
+			let load = |path: &std::path::PathBuf| {
+				db_config
+					.load_song(path)
+					.map_err(|err| error!("Failed to decode {path:?}, {err}"))
+			};
+			let duration = std::time::Duration::from_secs(*duration);
+			let noise = match noise {
+				Some(noise) => match load(noise) {
+					Ok(noise) => noise,
+					Err(()) => return std::process::ExitCode::FAILURE,
+				},
+				None => {
+					let generate = match noise_color {
+						NoiseColor::White => encoder::Song::white_noise,
+						NoiseColor::Pink => encoder::Song::pink_noise,
+					};
+					generate(duration, db_config.sample_rate(), *noise_seed)
+				}
+			};
+			let mut songs = Vec::new();
+			for path in song {
+				let name = path.file_name().unwrap_or_default().to_string_lossy();
+				if songs.iter().any(|(other, _)| other == &name) {
+					error!("Songs {name:?} would share a name, rename one");
+					return std::process::ExitCode::FAILURE;
+				}
+				let Ok(song) = load(path) else {
+					return std::process::ExitCode::FAILURE;
+				};
+				songs.push((name.into_owned(), song));
+			}
+			let dataset = match testing::write_fixtures(
+				&songs,
+				&noise,
+				out,
+				snrs,
+				offsets,
+				duration,
+				*short_noise,
+			) {
+				Ok(dataset) => dataset,
+				Err(err) => {
+					error!("{err}");
+					return std::process::ExitCode::FAILURE;
+				}
+			};
+			let manifest = out.join("manifest.json");
+			let json = serde_json::to_string_pretty(&dataset).expect("datasets always serialize");
+			if let Err(err) = std::fs::write(&manifest, json) {
+				error!("Failed to write {manifest:?}, {err}");
+				return std::process::ExitCode::FAILURE;
+			}
+			info!(
+				"Wrote {} samples, evaluate them with --manifest {manifest:?}",
+				dataset.queries.len()
+			);
+		}
+		#[cfg(feature = "mic")]
+		Command::Listen {
+			seconds,
+			device,
+			list_devices,
+			top,
+			output,
+		} => {
+			if *list_devices {
+				match listen::device_names() {
+					Ok(names) => names.iter().for_each(|name| println!("{name}")),
+					Err(err) => {
+						error!("{err}");
+						return std::process::ExitCode::FAILURE;
+					}
+				}
+				return std::process::ExitCode::SUCCESS;
+			}
+			let Ok(duration) = std::time::Duration::try_from_secs_f64(*seconds) else {
+				error!("Can't record for {seconds} seconds");
+				return std::process::ExitCode::FAILURE;
+			};
+			// Ready before recording, so matching starts as soon as the recording ends
+			let db = database(&args, &song_filter, db_config, thread_pool.as_ref());
+			let sample = listen::capture(device.as_deref(), duration);
+			let start = std::time::Instant::now();
+			let matches = sample
+				.map(|sample| sample.resample(db.config().sample_rate()))
+				.and_then(|sample| match_decoded(&db, &sample, *top));
+			let elapsed = start.elapsed();
+			if !print_matches(&db, "microphone", matches, elapsed, *top, policy, *output) {
+				return std::process::ExitCode::FAILURE;
+			}
+		}
+		#[cfg(not(feature = "mic"))]
+		Command::Listen { .. } => {
+			error!("Built without microphone support, rebuild with `--features mic`");
+			return std::process::ExitCode::FAILURE;
+		}
+		Command::Repl { top } => {
+			let db = database(&args, &song_filter, db_config, thread_pool.as_ref());
+			repl::Session::new(&db, policy, *top).run(std::io::stdin().lock());
+		}
+		Command::Serve {
+			bind,
+			top,
+			max_body_size,
+		} => {
+			let listener = match std::net::TcpListener::bind(bind) {
+				Ok(listener) => listener,
+				Err(err) => {
+					error!("Failed to listen on {bind:?}, {err}");
+					return std::process::ExitCode::FAILURE;
+				}
+			};
+			let db = database(&args, &song_filter, db_config, thread_pool.as_ref());
+			// The port the OS picked when binding port 0
+			match listener.local_addr() {
+				Ok(addr) => info!("Listening on {addr}"),
+				Err(_) => info!("Listening on {bind:?}"),
+			}
+			let options = serve::ServeOptions {
+				top: *top,
+				max_body_size: *max_body_size,
+				policy,
+			};
+			serve::serve(listener, std::sync::Arc::new(db), options);
+		}
+		Command::Dedupe { min_ratio, output } => {
+			let db = database(&args, &song_filter, db_config, thread_pool.as_ref());
+			let start = std::time::Instant::now();
+			let duplicates = db.find_duplicates(*min_ratio);
+			info!(
+				"Found {} duplicates in {:?}",
+				duplicates.len(),
+				start.elapsed()
+			);
+			let hop_secs = db.config().constellation().hop_size.as_secs_f64();
+			let name = |id| {
+				db.song_display_name(id)
+					.unwrap_or_else(|| format!("unknown song {id}"))
+			};
+			for duplicate in duplicates {
+				let offset_secs = duplicate.offset as f64 * hop_secs;
+				match output {
+					OutputFormat::Table => println!(
+						"{} in {} at {offset_secs:+.2}s, {:.1}% of its signatures aligned",
+						name(duplicate.id),
+						name(duplicate.other),
+						duplicate.ratio * 100.
+					),
+					OutputFormat::Json => {
+						let report = DuplicateReport {
+							song: duplicate.id,
+							song_name: db.song_name(duplicate.id).map(|name| name.into_owned()),
+							other: duplicate.other,
+							other_name: db.song_name(duplicate.other).map(|name| name.into_owned()),
+							ratio: duplicate.ratio,
+							score: duplicate.found.score,
+							offset_secs,
+						};
+						println!(
+							"{}",
+							serde_json::to_string(&report).expect("reports always serialize")
+						);
+					}
+				}
+			}
+		}
+		Command::Align { a, b, output } => {
+			let load = |path: &std::path::PathBuf| {
+				db_config
+					.load_song(path)
+					.inspect_err(|err| error!("Failed to decode {path:?}, {err}"))
+			};
+			let (Ok(a), Ok(b)) = (load(a), load(b)) else {
+				return std::process::ExitCode::FAILURE;
+			};
+			let Some(alignment) = database::align(&a, &b, db_config) else {
+				error!("The recordings share no signature");
+				return std::process::ExitCode::FAILURE;
+			};
+			match output {
+				OutputFormat::Table => println!(
+					"{:+.1} ms, score {:.2}, {} of {} signatures aligned",
+					alignment.offset_secs * 1000.,
+					alignment.score,
+					alignment.aligned,
+					alignment.signatures
+				),
+				OutputFormat::Json => println!(
+					"{}",
+					serde_json::to_string(&alignment).expect("alignments always serialize")
+				),
+			}
+		}
+		Command::Inspect {
+			file,
+			out,
+			peaks_only,
+		} => {
+			let song = match db_config.load_song(file) {
+				Ok(song) => song,
+				Err(err) => {
+					error!("Failed to decode {file:?}, {err}");
+					return std::process::ExitCode::FAILURE;
+				}
+			};
+			let out = out.clone().unwrap_or_else(|| {
+				std::path::Path::new(file.file_name().unwrap_or_default()).with_extension("ppm")
+			});
+			if let Err(err) = inspect::render(&song, &db_config, &out, *peaks_only) {
+				error!("{err}");
+				return std::process::ExitCode::FAILURE;
+			}
+			let out = out.display();
+			info!("Wrote {out} and the description of its axes, {out}.json");
+		}
+		Command::Cache { command } => cache::run(
+			command,
+			args.cache_dir.as_ref(),
+			args.songs_dir.as_ref(),
+			&song_filter,
+			&db_config,
+		),
+	}
+	std::process::ExitCode::SUCCESS
+}
+
+/// Loads the database from `--db-file` if it was built with `db_config`, otherwise
+/// builds it and saves it there
+fn database(
+	args: &Args,
+	song_filter: &database::SongFilter,
+	db_config: database::DatabaseConfig,
+	thread_pool: Option<&std::sync::Arc<rayon::ThreadPool>>,
+) -> database::Database {
+	let loaded = args.db_file.as_deref().and_then(|path| {
+		let start = std::time::Instant::now();
+		match database::Database::load(path.as_ref(), &db_config) {
+			Ok(db) => {
+				info!("Loaded the database from {path:?} in {:?}", start.elapsed());
+				Some(db)
+			}
+			Err(database::DatabaseLoadError::Io(err))
+				if err.kind() == std::io::ErrorKind::NotFound =>
+			{
+				info!("No database at {path:?} yet, building it");
+				None
+			}
+			Err(err) => {
+				warn!("Not using the database at {path:?}, rebuilding it, {err}");
+				None
+			}
+		}
+	});
+	let mut db = loaded.unwrap_or_else(|| {
+		let db = build_database(args, song_filter, db_config, thread_pool);
+		if let Some(path) = &args.db_file {
+			save_database(&db, path);
+		}
+		db
+	});
+	apply_match_settings(&mut db, args, thread_pool);
+	if args.stats {
+		print_stats(&db);
+	}
+	db
+}
+
+/// Readies `db` for matching, with the query-time settings of `args`
+fn apply_match_settings(
+	db: &mut database::Database,
+	args: &Args,
+	thread_pool: Option<&std::sync::Arc<rayon::ThreadPool>>,
+) {
+	db.finalize();
+	db.set_offset_tolerance(args.offset_tolerance);
+	db.set_idf_weighting(!args.unweighted_scoring);
+	db.set_slice_votes(args.one_vote_per_slice);
+	db.set_silence_trim(args.trim_silence.map(|threshold_db| database::SilenceTrim {
+		threshold_db,
+		min_hold: std::time::Duration::from_millis(args.silence_hold_ms),
+	}));
+	db.set_band_pass(args.bandpass);
+	db.set_query_duration(args.query_duration);
+	db.set_early_exit(args.early_exit.map(|min_lead| database::EarlyExit {
+		check_every: args.early_exit_every,
+		min_lead,
+		min_aligned: args.early_exit_min_aligned,
+	}));
+	db.set_thread_pool(thread_pool.cloned());
+}
+
+/// Saves `db` to `path`, along with the config it was built with for `--config`
+fn save_database(db: &database::Database, path: &str) {
+	match db.save(path.as_ref()) {
+		Ok(()) => info!("Saved the database to {path:?}"),
+		Err(err) => error!("Failed to save the database to {path:?}, {err}"),
+	}
+	let config_path = stored_config_path(path);
+	let config = database::DatabaseConfigBuilder::from(*db.config());
+	let json = serde_json::to_string_pretty(&config).expect("configs always serialize");
+	match std::fs::write(&config_path, json) {
+		Ok(()) => info!("Saved its config to {config_path:?}"),
+		Err(err) => error!("Failed to save the config to {config_path:?}, {err}"),
+	}
+}
+
+fn print_stats(db: &database::Database) {
+	let stats = db.stats(20);
+	println!(
+		"{}",
+		serde_json::to_string_pretty(&stats).expect("stats always serialize")
+	);
+}
+
+/// Noise `gen-fixtures` generates when not given any
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum NoiseColor {
+	/// Equal power at every frequency
+	White,
+	/// Power falling with frequency, loading the low buckets more like most recordings
+	Pink,
+}
+
+/// How `match` prints its results
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum OutputFormat {
+	/// The identified song, followed by the ranked matches
+	Table,
+	/// One JSON object per sample and line, see `QueryReport`
+	Json,
+}
+
+/// Result of matching a sample, as printed by `--output json`
+#[derive(Serialize)]
+struct QueryReport<'a> {
+	query: &'a str,
+	elapsed_secs: f64,
+	/// The song the sample was identified as, if it's trusted
+	identified: Option<database::SongId>,
+	matches: Vec<MatchReport>,
+}
+
+impl<'a> QueryReport<'a> {
+	/// Reports `matches` of the sample named `query`, ranked best first
+	fn new(
+		db: &database::Database,
+		query: &'a str,
+		elapsed: std::time::Duration,
+		identified: Option<database::Match>,
+		matches: &[database::Match],
+	) -> Self {
+		QueryReport {
+			query,
+			elapsed_secs: elapsed.as_secs_f64(),
+			identified: identified.map(|best| best.id),
+			matches: matches
+				.iter()
+				.map(|m| MatchReport {
+					id: m.id,
+					name: db.song_name(m.id).map(|name| name.into_owned()),
+					score: m.score,
+					aligned: m.freq,
+					offset_secs: m.offset.as_secs_f64(),
+				})
+				.collect(),
+		}
+	}
+}
+
+#[derive(Serialize)]
+struct MatchReport {
+	id: database::SongId,
+	name: Option<String>,
+	score: f32,
+	/// Signatures lining up with the song at `offset_secs`
+	aligned: usize,
+	offset_secs: f64,
+}
+
+/// Two songs found to be the same recording, as `dedupe --output json` prints them
+#[derive(Serialize)]
+struct DuplicateReport {
+	song: database::SongId,
+	song_name: Option<String>,
+	/// The song lining up with `song`
+	other: database::SongId,
+	other_name: Option<String>,
+	/// Signatures of `song` lining up with `other` over all its signatures
+	ratio: f32,
+	/// See `database::Match::score`
+	score: f32,
+	/// Where in `other` `song` starts, negative if before it
+	offset_secs: f64,
+}
+
+/// A sample that couldn't be matched, as printed by `--output json`
+#[derive(Serialize)]
+struct QueryError<'a> {
+	query: &'a str,
+	error: String,
+}
+
+/// Prints the `top` songs matching the sample at `path` best, and which of them
+/// `policy` identifies it as, then how it lines up with song `explain` if given. Their
+/// histograms are written to `dump_histograms` if given too. A path of `-` reads a WAV
+/// file from stdin. Returns whether the sample was identified
+fn match_file(
+	db: &database::Database,
+	path: &std::path::Path,
+	top: usize,
+	policy: database::MatchPolicy,
+	output: OutputFormat,
+	explain: Option<database::SongId>,
+	dump_histograms: Option<&std::path::Path>,
+) -> bool {
+	let start = std::time::Instant::now();
+	let name = match path.as_os_str() == "-" {
+		true => "stdin".into(),
+		false => path.to_string_lossy(),
+	};
+	let sample = load_sample(db, path, &name);
+	let matches = sample.as_ref().map_err(Clone::clone).and_then(|sample| {
+		let Some(dir) = dump_histograms else {
+			return match_decoded(db, sample, top);
+		};
+		check_sample_length(db, sample)?;
+		let detailed = db.match_sample_detailed(sample, top.max(2));
+		let file_name = path.file_name().unwrap_or(std::ffi::OsStr::new("stdin"));
+		write_histograms(
+			db,
+			&dir.join(file_name),
+			&detailed[..top.min(detailed.len())],
+		);
+		Ok(detailed
+			.into_iter()
+			.map(|detailed| detailed.found)
+			.collect())
+	});
+	let identified = print_matches(db, &name, matches, start.elapsed(), top, policy, output);
+	if let (Some(id), Ok(sample)) = (explain, &sample) {
+		print_explanation(db, &name, &db.explain_match(sample, id), output);
+	}
+	identified
 }
 
-fn main() {
-	// testing::test();
-	// std::process::exit(0);
-	env_logger::init();
-	let args = Args::parse();
-	let db_config = database::DatabaseConfig::from_args(args.clone());
-	debug!("{db_config:?} at {:?}", db_config.cached_dir_name());
+/// Writes the histogram of each of `matches`, ranked best first, to
+/// `<dir>/<rank>-<song>.csv`
+fn write_histograms(
+	db: &database::Database,
+	dir: &std::path::Path,
+	matches: &[database::DetailedMatch],
+) {
+	if let Err(err) = std::fs::create_dir_all(dir) {
+		error!("Failed to create {dir:?}, {err}");
+		return;
+	}
+	let hop = db.config().constellation().hop_size.as_secs_f64();
+	for (rank, detailed) in matches.iter().enumerate() {
+		let song = db
+			.song_name(detailed.found.id)
+			.unwrap_or_else(|| detailed.found.id.to_string().into());
+		// Songs in subdirectories are named by their path
+		let path = dir.join(format!(
+			"{}-{}.csv",
+			rank + 1,
+			song.replace(['/', '\\'], "_")
+		));
+		let mut histogram: Vec<_> = detailed.histogram.iter().collect();
+		histogram.sort_unstable();
+		let lead = detailed.lead.as_secs_f64();
+		let rows: Vec<_> = std::iter::once(["offset_seconds".to_owned(), "count".to_owned()])
+			.chain(histogram.into_iter().map(|(&offset, count)| {
+				[
+					format!("{:.3}", offset as f64 * hop - lead),
+					count.to_string(),
+				]
+			}))
+			.collect();
+		if let Err(err) = std::fs::write(&path, to_csv(&rows)) {
+			error!("Failed to write {path:?}, {err}");
+		}
+	}
+	info!(
+		"Wrote the histograms of {} matches to {dir:?}",
+		matches.len()
+	);
+}
+
+/// Most columns the timeline of `print_explanation` takes up
+const TIMELINE_WIDTH: usize = 64;
+
+/// Prints how the sample called `name` lines up with the song `explanation` is of: a
+/// timeline of the sample marking the stretches with signatures aligned, followed by
+/// the offsets the song got the most votes for
+fn print_explanation(
+	db: &database::Database,
+	name: &str,
+	explanation: &database::MatchExplanation,
+	output: OutputFormat,
+) {
+	if let OutputFormat::Json = output {
+		println!(
+			"{}",
+			serde_json::to_string(explanation).expect("explanations always serialize")
+		);
+		return;
+	}
+	let song = db
+		.song_display_name(explanation.id)
+		.unwrap_or_else(|| format!("unknown song {}", explanation.id));
+	let Some(found) = explanation.found else {
+		println!(
+			"{name} shares none of its {} signatures with {song}",
+			explanation.sample_signatures
+		);
+		return;
+	};
+	let hop = explanation.hop_size.as_secs_f64();
+	let secs = |slices: database::Offset| slices as f64 * hop;
+	println!(
+		"{name} against {song}: score {:.2} at {:?}, {} of its {} signatures held by the \
+		 song, {} aligned",
+		found.score,
+		found.offset,
+		explanation.shared,
+		explanation.sample_signatures,
+		explanation.aligned.len()
+	);
+	let slices = explanation.sample_slices as usize;
+	let width = slices.clamp(1, TIMELINE_WIDTH);
+	let mut timeline = vec!['.'; width];
+	for aligned in &explanation.aligned {
+		timeline[aligned.sample_timestamp as usize * width / slices.max(1)] = '#';
+	}
+	let lead = explanation.lead.as_secs_f64();
+	println!(
+		"  {:>7.2}s |{}| {:.2}s",
+		lead,
+		timeline.into_iter().collect::<String>(),
+		lead + secs(slices as database::Offset)
+	);
+	println!(
+		"  aligned in {} of {} slices, {:.2}s of the sample",
+		explanation.aligned_slices(),
+		slices,
+		secs(explanation.aligned_slices() as database::Offset)
+	);
+	let mut histogram = explanation.histogram.clone();
+	histogram.sort_by_key(|hits| std::cmp::Reverse(hits.hits));
+	println!("  most voted for offsets, of {}:", histogram.len());
+	for hits in histogram.iter().take(5) {
+		println!(
+			"  {:>9.2}s  {} signatures from {} slices",
+			secs(hits.offset),
+			hits.hits,
+			hits.slices
+		);
+	}
+}
+
+/// Prints the `top` of `matches` for the sample called `name`, which took `elapsed` to
+/// match, or why there are none. Returns whether `policy` identified the sample
+fn print_matches(
+	db: &database::Database,
+	name: &str,
+	matches: Result<Vec<database::Match>, String>,
+	elapsed: std::time::Duration,
+	top: usize,
+	policy: database::MatchPolicy,
+	output: OutputFormat,
+) -> bool {
+	let matches = matches.inspect_err(|error| match output {
+		OutputFormat::Table => error!("{error}"),
+		OutputFormat::Json => println!(
+			"{}",
+			serde_json::to_string(&QueryError {
+				query: name,
+				error: error.clone(),
+			})
+			.expect("errors always serialize")
+		),
+	});
+	let Ok(matches) = matches else {
+		return false;
+	};
+	info!("Matched {name} in {elapsed:?}");
+	let identified = policy.pick(&matches);
+	let matches = &matches[..top.min(matches.len())];
+	match output {
+		OutputFormat::Table => {
+			match identified {
+				Some(best) => println!(
+					"{name}: {} at {:?}",
+					db.song_display_name(best.id)
+						.unwrap_or_else(|| format!("unknown song {}", best.id)),
+					best.offset
+				),
+				None => println!("{name}: no match"),
+			}
+			for (rank, m) in matches.iter().enumerate() {
+				println!(
+					"  {}. {}, score {:.2}, at {:?}, {} aligned",
+					rank + 1,
+					db.song_display_name(m.id)
+						.unwrap_or_else(|| format!("unknown song {}", m.id)),
+					m.score,
+					m.offset,
+					m.freq
+				);
+			}
+		}
+		OutputFormat::Json => {
+			let report = QueryReport::new(db, name, elapsed, identified, matches);
+			println!(
+				"{}",
+				serde_json::to_string(&report).expect("reports always serialize")
+			);
+		}
+	}
+	identified.is_some()
+}
+
+/// Where `--report` writes the summary table to, given as `csv=<path>`
+fn parse_report(report: &str) -> Result<std::path::PathBuf, String> {
+	match report.split_once('=') {
+		Some(("csv", path)) if !path.is_empty() => Ok(path.into()),
+		_ => Err("expected csv=<path>".into()),
+	}
+}
+
+/// `--query-duration`, given in seconds
+fn parse_query_duration(secs: &str) -> Result<std::time::Duration, String> {
+	let secs: f64 = secs
+		.parse()
+		.map_err(|err| format!("expected seconds, {err}"))?;
+	std::time::Duration::try_from_secs_f64(secs).map_err(|err| err.to_string())
+}
+
+/// `--bandpass`, given as `<low>,<high>` in Hz
+fn parse_band_pass(band: &str) -> Result<encoder::filters::BandPass, String> {
+	let cutoffs = band
+		.split_once(',')
+		.and_then(|(low, high)| Some((low.trim().parse().ok()?, high.trim().parse().ok()?)));
+	match cutoffs {
+		Some((low_hz, high_hz)) if 0. <= low_hz && low_hz < high_hz => {
+			Ok(encoder::filters::BandPass { low_hz, high_hz })
+		}
+		Some(_) => Err("expected 0 <= low < high".into()),
+		None => Err("expected <low>,<high> in Hz".into()),
+	}
+}
+
+/// A row of the summary table of `match --dir`
+struct DirMatch {
+	file: String,
+	elapsed: std::time::Duration,
+	/// The best match, if the file could be matched and any song was
+	best: Result<Option<database::Match>, String>,
+	identified: bool,
+}
+impl DirMatch {
+	/// The file, best match, score, offset, elapsed and identified columns
+	fn columns(&self, db: &database::Database) -> [String; 6] {
+		let (best, score, offset) = match &self.best {
+			Ok(Some(best)) => (
+				db.song_display_name(best.id)
+					.unwrap_or_else(|| format!("unknown song {}", best.id)),
+				format!("{:.2}", best.score),
+				format!("{:.2}", best.offset.as_secs_f64()),
+			),
+			Ok(None) => ("no match".into(), String::new(), String::new()),
+			Err(error) => (error.clone(), String::new(), String::new()),
+		};
+		[
+			self.file.clone(),
+			best,
+			score,
+			offset,
+			format!("{:.3}", self.elapsed.as_secs_f64()),
+			self.identified.to_string(),
+		]
+	}
+}
+const DIR_MATCH_HEADER: [&str; 6] = [
+	"file",
+	"best_match",
+	"score",
+	"offset_secs",
+	"elapsed_secs",
+	"identified",
+];
+
+/// Matches every audio file directly in `dir` in parallel, then prints a table of the
+/// best match of each, or with `OutputFormat::Json` what `match_file` would. A
+/// `report` path gets the table as CSV. Returns whether every file was identified
+fn match_dir(
+	db: &database::Database,
+	dir: &std::path::Path,
+	top: usize,
+	policy: database::MatchPolicy,
+	output: OutputFormat,
+	report: Option<&std::path::Path>,
+) -> bool {
+	let filter = database::SongFilter {
+		max_depth: 0,
+		extensions: encoder::Song::SUPPORTED_EXTENSIONS
+			.iter()
+			.map(|ext| ext.to_string())
+			.collect(),
+		exclude: Vec::new(),
+	};
+	let files = match filter.find_songs(dir) {
+		Ok(files) => files,
+		Err(err) => {
+			error!("Failed to read {dir:?}, {err}");
+			return false;
+		}
+	};
+	let start = std::time::Instant::now();
+	let results: Vec<_> = db.install(|| {
+		files
+			.par_iter()
+			.map(|file| {
+				let name = file.to_string_lossy();
+				let start = std::time::Instant::now();
+				let matches = match_sample(db, &dir.join(file), &name, top);
+				(name, matches, start.elapsed())
+			})
+			.collect()
+	});
+	info!("Matched {} files in {:?}", files.len(), start.elapsed());
+	let rows: Vec<_> = results
+		.into_iter()
+		.map(|(name, matches, elapsed)| {
+			let identified = matches
+				.as_ref()
+				.is_ok_and(|matches| policy.pick(matches).is_some());
+			let best = matches
+				.as_ref()
+				.map(|matches| matches.first().copied())
+				.map_err(Clone::clone);
+			if let OutputFormat::Json = output {
+				print_matches(db, &name, matches, elapsed, top, policy, output);
+			}
+			DirMatch {
+				file: name.into_owned(),
+				elapsed,
+				best,
+				identified,
+			}
+		})
+		.collect();
+	let table: Vec<_> = std::iter::once(DIR_MATCH_HEADER.map(String::from))
+		.chain(rows.iter().map(|row| row.columns(db)))
+		.collect();
+	if let OutputFormat::Table = output {
+		print_table(&table);
+	}
+	let identified = rows.iter().filter(|row| row.identified).count();
+	info!("Identified {identified}/{} files in {dir:?}", rows.len());
+	if let Some(report) = report {
+		match std::fs::write(report, to_csv(&table)) {
+			Ok(()) => info!("Wrote the report to {report:?}"),
+			Err(err) => error!("Failed to write the report to {report:?}, {err}"),
+		}
+	}
+	identified == rows.len()
+}
+
+/// Prints `rows` in columns as wide as their widest field
+fn print_table(rows: &[impl AsRef<[String]>]) {
+	let mut widths = Vec::new();
+	for row in rows {
+		for (i, field) in row.as_ref().iter().enumerate() {
+			match widths.get_mut(i) {
+				Some(width) => *width = field.chars().count().max(*width),
+				None => widths.push(field.chars().count()),
+			}
+		}
+	}
+	for row in rows {
+		let line: Vec<_> = row
+			.as_ref()
+			.iter()
+			.zip(&widths)
+			.map(|(field, &width)| format!("{field:width$}"))
+			.collect();
+		println!("{}", line.join("  ").trim_end());
+	}
+}
+
+/// `rows` as CSV, one line each
+fn to_csv(rows: &[impl AsRef<[String]>]) -> String {
+	rows.iter()
+		.map(|row| {
+			let fields: Vec<_> = row.as_ref().iter().map(|field| csv_field(field)).collect();
+			fields.join(",") + "\n"
+		})
+		.collect()
+}
+
+/// `field` quoted if it holds a comma, quote or line break, as `metadata` reads them
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+	match field.contains([',', '"', '\n', '\r']) {
+		true => format!("\"{}\"", field.replace('"', "\"\"")).into(),
+		false => field.into(),
+	}
+}
+
+/// Up to `top`, and at least two, best matches for the sample at `path`, `-` reading
+/// it from stdin. Errors name the sample `name`
+fn match_sample(
+	db: &database::Database,
+	path: &std::path::Path,
+	name: &str,
+	top: usize,
+) -> Result<Vec<database::Match>, String> {
+	match_decoded(db, &load_sample(db, path, name)?, top)
+}
+
+/// The sample at `path` at the database's rate, `-` reading it from stdin. Errors name
+/// the sample `name`
+fn load_sample(
+	db: &database::Database,
+	path: &std::path::Path,
+	name: &str,
+) -> Result<encoder::Song, String> {
+	let db_config = db.config();
+	match path.as_os_str() == "-" {
+		true => db_config.load_wav(std::io::stdin().lock()),
+		false => db_config.load_song(path),
+	}
+	.map_err(|err| format!("Failed to decode {name}, {err}"))
+}
+
+/// Up to `top`, and at least two, best matches for `sample`, unless it's too short to
+/// have any
+fn match_decoded(
+	db: &database::Database,
+	sample: &encoder::Song,
+	top: usize,
+) -> Result<Vec<database::Match>, String> {
+	check_sample_length(db, sample)?;
+	// The runner up decides whether the best match is trusted, so it's always kept
+	Ok(db.best_matches(sample, top.max(2), 0.))
+}
+
+/// Why `sample` can't be matched if it's too short to have signatures
+fn check_sample_length(db: &database::Database, sample: &encoder::Song) -> Result<(), String> {
+	let db_config = db.config();
+	// Matching filters and trims the sample first, so that's what has to be long enough
+	let (matched, _) = db.prepare_sample(sample);
+	let trimmed = match (db.silence_trim(), db.query_duration()) {
+		(_, Some(max)) if sample.length() >= max => ", see --query-duration".to_owned(),
+		(Some(_), _) => " once trimmed of silence".to_owned(),
+		(None, _) => String::new(),
+	};
+	if db_config.is_too_short(&matched) {
+		return Err(format!(
+			"Sample too short: need at least {} ms, got {} ms{trimmed}",
+			db_config.min_sample_length().as_millis(),
+			matched.length().as_millis()
+		));
+	}
+	Ok(())
+}
+
+/// The config described by the tuning flags of `args`. With a `base` config file they
+/// only override it where given, warning when that changes its value
+pub fn db_config(
+	args: &Args,
+	matches: &clap::ArgMatches,
+	base: Option<&(std::path::PathBuf, database::DatabaseConfigBuilder)>,
+) -> Result<database::DatabaseConfig, database::ConfigError> {
+	config_builder(args, matches, base).try_build()
+}
+
+/// The builder `db_config` builds, defaults left as they are so they can still follow
+/// the parameters they derive from, like the hop size does the slice size
+fn config_builder(
+	args: &Args,
+	matches: &clap::ArgMatches,
+	base: Option<&(std::path::PathBuf, database::DatabaseConfigBuilder)>,
+) -> database::DatabaseConfigBuilder {
+	use database::DatabaseConfigBuilder as Builder;
+	use std::time::Duration;
+	// By argument id, which clap takes from the field name
+	let flags: [(&str, &dyn Fn(Builder) -> Builder); 22] = [
+		("ms_timeslice_size", &|builder| {
+			builder.with_slice_size(Duration::from_millis(args.ms_timeslice_size))
+		}),
+		("fft_size", &|builder| builder.with_fft_size(args.fft_size)),
+		("hop_ms", &|builder| match args.hop_ms {
+			Some(hop_ms) => builder.with_hop_size(Duration::from_millis(hop_ms)),
+			None => builder,
+		}),
+		("freq_per_slice", &|builder| {
+			builder.with_freq_per_slice(args.freq_per_slice)
+		}),
+		("size_bucket", &|builder| {
+			builder.with_bucket_size(args.size_bucket)
+		}),
+		("count_bucket", &|builder| {
+			builder.with_bucket_count(args.count_bucket)
+		}),
+		("width_target_zone", &|builder| {
+			builder.with_target_zone_width(Duration::from_millis(args.width_target_zone))
+		}),
+		("target_zone_height", &|builder| {
+			builder.with_target_zone_height(args.target_zone_height)
+		}),
+		("target_zone_offset", &|builder| {
+			builder.with_target_zone_offset(args.target_zone_offset)
+		}),
+		("max_fanout_per_anchor", &|builder| {
+			builder.with_max_fanout_per_anchor(args.max_fanout_per_anchor)
+		}),
+		("max_signatures_per_second", &|builder| {
+			builder.with_max_signatures_per_second(args.max_signatures_per_second)
+		}),
+		("freq_fuzz", &|builder| {
+			builder.with_freq_fuzz(args.freq_fuzz)
+		}),
+		("dedupe_window", &|builder| {
+			builder.with_dedupe_window(args.dedupe_window)
+		}),
+		("sample_rate", &|builder| {
+			builder.with_sample_rate(args.sample_rate)
+		}),
+		("min_freq", &|builder| builder.with_min_freq(args.min_freq)),
+		("window", &|builder| builder.with_window(args.window)),
+		("bucketing", &|builder| {
+			builder.with_bucketing(args.bucketing)
+		}),
+		("noise_floor", &|builder| {
+			builder.with_noise_floor(args.noise_floor)
+		}),
+		("whitening_width", &|builder| {
+			builder.with_whitening_width(args.whitening_width)
+		}),
+		("peak_picking", &|builder| {
+			builder.with_peak_picking(args.peak_picking)
+		}),
+		("peak_time_radius", &|builder| {
+			builder.with_peak_time_radius(args.peak_time_radius)
+		}),
+		("peak_freq_radius", &|builder| {
+			builder.with_peak_freq_radius(args.peak_freq_radius)
+		}),
+	];
+	let Some((path, base)) = base else {
+		return flags
+			.iter()
+			.fold(database::DatabaseConfig::builder(), |builder, (_, set)| {
+				set(builder)
+			});
+	};
+	flags
+		.iter()
+		.filter(|(id, _)| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine))
+		.fold(*base, |builder, (id, set)| {
+			let overridden = set(builder);
+			if overridden != builder {
+				warn!("--{} overrides the value in {path:?}", id.replace('_', "-"));
+			}
+			overridden
+		})
+}
+
+/// Where the config a database was built with is written next to it
+fn stored_config_path(db_file: &str) -> std::path::PathBuf {
+	format!("{db_file}.config.json").into()
+}
+
+/// Reads a config file written as TOML or, unless its extension is `toml`, JSON
+fn read_config_file(path: &std::path::Path) -> Result<database::DatabaseConfigBuilder, String> {
+	let contents = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+	match path
+		.extension()
+		.is_some_and(|extension| extension == "toml")
+	{
+		true => toml::from_str(&contents).map_err(|err| err.to_string()),
+		false => serde_json::from_str(&contents).map_err(|err| err.to_string()),
+	}
+}
+
+/// The config file the tuning flags apply on top of, `--config` if given. Otherwise
+/// commands using a saved database start from the config it was built with, so its
+/// fingerprints line up with the samples' even if the flags building it aren't given
+fn base_config(
+	args: &Args,
+) -> Result<Option<(std::path::PathBuf, database::DatabaseConfigBuilder)>, String> {
+	if let Some(path) = &args.config {
+		return read_config_file(path)
+			.map(|config| Some((path.clone(), config)))
+			.map_err(|err| format!("Failed to read the config in {path:?}, {err}"));
+	}
+	let db_file = match (&args.command, &args.db_file) {
+		(Command::Build, _) | (_, None) => return Ok(None),
+		(_, Some(db_file)) => db_file,
+	};
+	let path = stored_config_path(db_file);
+	if !path.exists() {
+		return Ok(None);
+	}
+	info!("Using the config {db_file:?} was built with, from {path:?}");
+	read_config_file(&path)
+		.map(|config| Some((path.clone(), config)))
+		.map_err(|err| format!("Failed to read the config in {path:?}, {err}"))
+}
+
+fn build_database(
+	args: &Args,
+	song_filter: &database::SongFilter,
+	db_config: database::DatabaseConfig,
+	thread_pool: Option<&std::sync::Arc<rayon::ThreadPool>>,
+) -> database::Database {
 	let mut db_builder =
-		database::DatabaseBuilder::new(db_config, &args.songs_dir, Some(&args.cache_dir));
+		database::DatabaseBuilder::new(db_config, &args.songs_dir, Some(&args.cache_dir))
+			.with_cache_compression(args.cache_compression)
+			.with_duplicates_allowed(args.allow_duplicates)
+			.with_thread_pool(thread_pool.cloned());
 
-	let entries = match std::fs::read_dir(&args.songs_dir) {
+	let songs = match song_filter.find_songs(args.songs_dir.as_ref()) {
 		Ok(x) => x,
 		Err(err) => {
 			error!("Failed to read songs directory {:?}", args.songs_dir);
@@ -49,70 +1668,185 @@ fn main() {
 		}
 	};
 
-	for dir_entry in entries {
-		match dir_entry {
-			Ok(x) if !x.path().is_file() => {
-				debug!("Skipping {x:?}");
+	let mut song_meta = metadata::read_sidecars(args.songs_dir.as_ref());
+	for song in songs {
+		match db_builder.add_song(&song) {
+			Ok(cache) => {
+				info!("{cache:?} for {song:?}");
+				if let Some(meta) = song_meta.remove(&song) {
+					db_builder.set_song_meta(&song, meta);
+				}
+			}
+			Err(database::AddSongError::NotFound) => {
+				error!(
+					"{song:?} disappeared from {:?} while scanning it",
+					args.songs_dir
+				)
+			}
+			Err(database::AddSongError::NotAFile) => {
+				error!("{song:?} is not a regular file, skipping it")
+			}
+			Err(database::AddSongError::UnsupportedExtension) => error!(
+				"Skipping {song:?}, only {:?} files can be decoded, check --extensions",
+				encoder::Song::SUPPORTED_EXTENSIONS
+			),
+			Err(database::AddSongError::Io(err)) => {
+				error!("Failed to read {song:?}, check its permissions, {err}")
 			}
-			Ok(file) => match db_builder.add_song(&file.file_name()) {
-				Some(cache) => info!("{cache:?} for {file:?}"),
-				None => error!("Failed to add {file:?} to the database"),
-			},
-			Err(err) => error!("{err}"),
 		}
 	}
 
+	for song in song_meta.keys() {
+		warn!("Metadata for {song:?}, which isn't in the songs directory");
+	}
+
 	let start = std::time::Instant::now();
-	let db = db_builder.build(db_config);
+	let (db, errors) = db_builder.build(db_config);
 	info!("DB Build Took {:?}", start.elapsed());
+	if !errors.is_empty() {
+		warn!("Left {} songs out of the database:", errors.len());
+		for err in &errors {
+			warn!("  {err}");
+		}
+	}
+	db
+}
 
-	for snr in testing::snrs.iter().rev() {
-		let mut accuracies = Vec::new();
-		for offset in testing::offsets {
-			let mut input_sample_path = format!("test/{}/{}.wav", snr, offset);
-			// print!("Enter file path: ");
-			// std::io::stdout().flush().unwrap();
-			// std::io::stdin().read_line(&mut input_sample_path).unwrap();
-			let start = std::time::Instant::now();
-			match std::fs::read(input_sample_path.trim()) {
-				Ok(byte_array) => {
-					let sample = encoder::Song::from_wav(byte_array);
-					let mut matches = db.match_sample(sample);
-					matches.sort_unstable_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-					if let Some(best_match) = matches.first() {
-						let mut total_score = 0.;
-						println!(
-							"Match for {input_sample_path}, is {}",
-							db.song_name(best_match.id)
-						);
-						if best_match.id != 0 {
-							error!("Match failed!");
-							accuracies.push(0.);
-							continue;
-						}
-						info!(
-							"Best Match: {:?}, Score: {:.2}",
-							db.song_name(best_match.id),
-							best_match.score
-						);
-						for (i, m) in matches.iter().enumerate() {
-							debug!("{i}: Match: {m:?}");
-							total_score += m.score;
-						}
-						accuracies.push(best_match.score / total_score);
-					}
-					info!("Match Count: {}, in {:?}", matches.len(), start.elapsed());
-				}
-				Err(err) => {
-					error!("Try again, {err:?}");
-				}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// `shazoom` run with `args`, along with the matches flags are told apart by
+	fn parse(args: &[&str]) -> (Args, clap::ArgMatches) {
+		let matches = Args::command()
+			.try_get_matches_from(std::iter::once("shazoom").chain(args.iter().copied()))
+			.unwrap_or_else(|err| panic!("{args:?} doesn't parse, {err}"));
+		let parsed = Args::from_arg_matches(&matches).unwrap();
+		(parsed, matches)
+	}
+
+	fn command(args: &[&str]) -> Command {
+		parse(args).0.command
+	}
+
+	#[test]
+	fn every_subcommand_parses() {
+		assert!(matches!(command(&["build"]), Command::Build));
+		assert!(matches!(
+			command(&["match", "a.wav", "b.wav", "--top", "3"]),
+			Command::Match { files, top: 3, dir: None, .. } if files.len() == 2
+		));
+		assert!(matches!(
+			command(&["evaluate", "--snrs", "-5,10", "--held-out", "a.wav"]),
+			Command::Evaluate { snrs, held_out, .. } if snrs == [-5, 10] && held_out == ["a.wav"]
+		));
+		assert!(matches!(
+			command(&["tune", "--grid", "grid.toml", "--queries", "queries.json"]),
+			Command::Tune { out, .. } if out.as_os_str() == "tune"
+		));
+		assert!(matches!(
+			command(&["gen-fixtures", "--song", "a.wav", "--song", "b.wav"]),
+			Command::GenFixtures { song, duration: 15, .. } if song.len() == 2
+		));
+		assert!(matches!(
+			command(&["listen", "--seconds", "5"]),
+			Command::Listen { seconds, list_devices: false, .. } if seconds == 5.
+		));
+		assert!(matches!(
+			command(&["repl", "--top", "2"]),
+			Command::Repl { top: 2 }
+		));
+		assert!(matches!(
+			command(&["serve", "--bind", "0.0.0.0:9000"]),
+			Command::Serve { bind, top: 5, .. } if bind == "0.0.0.0:9000"
+		));
+		assert!(matches!(
+			command(&["dedupe", "--min-ratio", "0.3"]),
+			Command::Dedupe { min_ratio, .. } if min_ratio == 0.3
+		));
+		assert!(matches!(
+			command(&["align", "a.wav", "b.wav"]),
+			Command::Align { a, b, .. } if a.as_os_str() == "a.wav" && b.as_os_str() == "b.wav"
+		));
+		assert!(matches!(
+			command(&["inspect", "a.wav", "--peaks-only"]),
+			Command::Inspect {
+				out: None,
+				peaks_only: true,
+				..
 			}
+		));
+		assert!(matches!(
+			command(&["cache", "prune", "--confirm"]),
+			Command::Cache {
+				command: cache::CacheCommand::Prune { confirm: true }
+			}
+		));
+	}
+
+	#[test]
+	fn rejects_bad_usage() {
+		for args in [
+			&[][..],
+			&["match"],
+			&["match", "a.wav", "--report", "csv=report.csv"],
+			&["tune", "--grid", "grid.toml"],
+			&["gen-fixtures"],
+			&["align", "a.wav"],
+			&["frobnicate"],
+		] {
+			let parsed =
+				Args::try_parse_from(std::iter::once("shazoom").chain(args.iter().copied()));
+			assert!(parsed.is_err(), "{args:?} parses");
 		}
-		let mut acc = 0.;
-		for a in accuracies.iter() {
-			acc += a;
-		}
-		acc /= 12.;
-		info!("For SNR: {snr}%, Accuracy: {acc}, Raw: {accuracies:?}")
+	}
+
+	#[test]
+	fn tuning_flags_are_global() {
+		let (args, matches) = parse(&["match", "a.wav", "--fft-size", "32768", "--hop-ms", "65"]);
+		let config = db_config(&args, &matches, None).unwrap();
+		assert_eq!(config.constellation().fft_size, 32768);
+		assert_eq!(
+			config.constellation().hop_size,
+			std::time::Duration::from_millis(65)
+		);
+	}
+
+	#[test]
+	fn songs_of_every_supported_format_are_scanned() {
+		assert_eq!(
+			parse(&["build"]).0.extensions,
+			encoder::Song::SUPPORTED_EXTENSIONS
+		);
+		assert_eq!(
+			parse(&["build", "--extensions", "wav,flac"]).0.extensions,
+			["wav", "flac"]
+		);
+	}
+
+	#[test]
+	fn samples_a_sample_short_of_a_slice_are_too_short() {
+		let config = database::DatabaseConfig::builder().build();
+		let db = database::Database::new(config);
+		let (window, _) = encoder::slice_samples(
+			config.sample_rate(),
+			config.slice_size(),
+			config.constellation().hop_size,
+		);
+		let sample = |len| encoder::Song::from_pcm_i16(&vec![1000; len], config.sample_rate());
+		assert_eq!(
+			check_sample_length(&db, &sample(window - 1)),
+			Err("Sample too short: need at least 260 ms, got 259 ms".to_owned())
+		);
+		assert_eq!(check_sample_length(&db, &sample(window)), Ok(()));
+	}
+
+	#[test]
+	fn invalid_config_is_an_error() {
+		let (args, matches) = parse(&["build", "--target-zone-offset", "0"]);
+		assert_eq!(
+			db_config(&args, &matches, None).err(),
+			Some(database::ConfigError::TargetZoneOffsetZero)
+		);
 	}
 }
@@ -0,0 +1,158 @@
+//! Optional song metadata, read from sidecar files in the songs directory
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+/// Sidecar holding a JSON object from song path to `SongMeta`
+pub const JSON_SIDECAR: &str = "metadata.json";
+/// Sidecar holding a CSV table with a header row naming a `path` column and any of
+/// `title`, `artist`, `album` and `duration`
+pub const CSV_SIDECAR: &str = "metadata.csv";
+
+/// What's known about a song beyond its path, every field is optional
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SongMeta {
+	/// Title of the song
+	pub title: Option<String>,
+	/// Artist performing it
+	pub artist: Option<String>,
+	/// Album it's from
+	pub album: Option<String>,
+	/// In seconds
+	pub duration: Option<f64>,
+}
+impl SongMeta {
+	/// "Artist - Title", or just the title without an artist
+	pub fn display_name(&self) -> Option<String> {
+		match (&self.artist, &self.title) {
+			(Some(artist), Some(title)) => Some(format!("{artist} - {title}")),
+			(None, Some(title)) => Some(title.clone()),
+			_ => None,
+		}
+	}
+}
+
+/// Metadata of the songs in `songs_dir` from its sidecar files, keyed by path relative
+/// to it. Both sidecars are optional, for songs in both the JSON one wins
+pub fn read_sidecars(songs_dir: &Path) -> HashMap<PathBuf, SongMeta> {
+	let mut meta = HashMap::new();
+	if let Some(csv) = read_sidecar(&songs_dir.join(CSV_SIDECAR)) {
+		match parse_csv(&csv) {
+			Ok(entries) => meta.extend(entries),
+			Err(err) => error!("Failed to parse {CSV_SIDECAR:?} in {songs_dir:?}, {err}"),
+		}
+	}
+	if let Some(json) = read_sidecar(&songs_dir.join(JSON_SIDECAR)) {
+		match serde_json::from_str::<HashMap<PathBuf, SongMeta>>(&json) {
+			Ok(entries) => meta.extend(entries),
+			Err(err) => error!("Failed to parse {JSON_SIDECAR:?} in {songs_dir:?}, {err}"),
+		}
+	}
+	if !meta.is_empty() {
+		info!("Read metadata of {} songs", meta.len());
+	}
+	meta
+}
+
+fn read_sidecar(path: &Path) -> Option<String> {
+	match std::fs::read_to_string(path) {
+		Ok(contents) => Some(contents),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+		Err(err) => {
+			error!("Failed to read {path:?}, {err}");
+			None
+		}
+	}
+}
+
+fn parse_csv(csv: &str) -> Result<Vec<(PathBuf, SongMeta)>, String> {
+	let mut rows = csv_rows(csv)?.into_iter();
+	let header = rows.next().ok_or("no header row")?;
+	let column = |name: &str| {
+		header
+			.iter()
+			.position(|column| column.trim().eq_ignore_ascii_case(name))
+	};
+	let path = column("path").ok_or("no path column")?;
+	let (title, artist, album, duration) = (
+		column("title"),
+		column("artist"),
+		column("album"),
+		column("duration"),
+	);
+	rows.enumerate()
+		.filter(|(_, row)| row.iter().any(|field| !field.trim().is_empty()))
+		.map(|(i, row)| {
+			let field = |column: Option<usize>| {
+				column
+					.and_then(|column| row.get(column))
+					.map(|field| field.trim())
+					.filter(|field| !field.is_empty())
+					.map(str::to_owned)
+			};
+			// Counting the header as the first row
+			let row_number = i + 2;
+			let path = field(Some(path)).ok_or(format!("row {row_number} has no path"))?;
+			let duration = field(duration)
+				.map(|duration| {
+					duration
+						.parse()
+						.map_err(|err| format!("row {row_number} has duration {duration:?}, {err}"))
+				})
+				.transpose()?;
+			Ok((
+				PathBuf::from(path),
+				SongMeta {
+					title: field(title),
+					artist: field(artist),
+					album: field(album),
+					duration,
+				},
+			))
+		})
+		.collect()
+}
+
+/// Splits `csv` into rows of fields. Fields may be quoted to hold commas and line breaks,
+/// with `""` standing for a quote inside them
+fn csv_rows(csv: &str) -> Result<Vec<Vec<String>>, String> {
+	let mut rows = Vec::new();
+	let mut row = Vec::new();
+	let mut field = String::new();
+	let mut quoted = false;
+	let mut chars = csv.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'"' if quoted => {
+				if chars.peek() == Some(&'"') {
+					chars.next();
+					field.push('"');
+				} else {
+					quoted = false;
+				}
+			}
+			'"' if field.is_empty() => quoted = true,
+			',' if !quoted => row.push(std::mem::take(&mut field)),
+			'\n' if !quoted => {
+				row.push(std::mem::take(&mut field));
+				rows.push(std::mem::take(&mut row));
+			}
+			'\r' if !quoted => {}
+			c => field.push(c),
+		}
+	}
+	if quoted {
+		return Err("unterminated quoted field".into());
+	}
+	if !field.is_empty() || !row.is_empty() {
+		row.push(field);
+		rows.push(row);
+	}
+	Ok(rows)
+}
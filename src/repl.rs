@@ -0,0 +1,227 @@
+//! Interactive matching against a database loaded once, reading commands from stdin
+
+use std::io::{BufRead, IsTerminal, Write};
+
+use log::error;
+
+use shazoom::database::{Database, MatchPolicy};
+
+const HELP: &str = "\
+match <path>       print the songs the sample at <path> matches best
+list               print every song in the database
+stats              print statistics of the database's index
+threshold [ratio]  print or set the score ratio the best match needs to be identified
+top [count]        print or set the number of matches listed
+help               print this
+quit               leave, as does the end of input";
+
+/// A line of input to the REPL
+#[derive(Debug)]
+enum ReplCommand {
+	Match(std::path::PathBuf),
+	List,
+	Stats,
+	Threshold(Option<f32>),
+	Top(Option<usize>),
+	Help,
+	Quit,
+}
+impl ReplCommand {
+	/// The command on `line`, `None` for a blank one. Paths are the rest of the line, so
+	/// they may hold spaces
+	fn parse(line: &str) -> Result<Option<Self>, String> {
+		let line = line.trim();
+		let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+		let rest = rest.trim();
+		let no_argument = |parsed: Self| match rest.is_empty() {
+			true => Ok(parsed),
+			false => Err(format!("{command} takes no arguments")),
+		};
+		Ok(Some(match command {
+			"" => return Ok(None),
+			"match" if rest.is_empty() => return Err("match takes a path".into()),
+			"match" => Self::Match(rest.into()),
+			"list" => no_argument(Self::List)?,
+			"stats" => no_argument(Self::Stats)?,
+			"threshold" => Self::Threshold(optional_argument(command, rest, "a ratio")?),
+			"top" => Self::Top(optional_argument(command, rest, "a count")?),
+			"help" => no_argument(Self::Help)?,
+			"quit" | "exit" => no_argument(Self::Quit)?,
+			_ => return Err(format!("unknown command {command:?}, see help")),
+		}))
+	}
+}
+
+/// `rest` of the line of `command` parsed as its argument, describing it as `what` if it
+/// doesn't parse
+fn optional_argument<T: std::str::FromStr>(
+	command: &str,
+	rest: &str,
+	what: &str,
+) -> Result<Option<T>, String>
+where
+	T::Err: std::fmt::Display,
+{
+	match rest.is_empty() {
+		true => Ok(None),
+		false => rest
+			.parse()
+			.map(Some)
+			.map_err(|err| format!("{command} takes {what}, {err}")),
+	}
+}
+
+/// State carried from one command to the next
+pub struct Session<'a> {
+	db: &'a Database,
+	policy: MatchPolicy,
+	top: usize,
+}
+impl<'a> Session<'a> {
+	/// Matches against `db`, identifying samples by `policy` and listing `top` matches
+	/// until told otherwise
+	pub fn new(db: &'a Database, policy: MatchPolicy, top: usize) -> Self {
+		Self { db, policy, top }
+	}
+	/// Reads and runs commands from `input` until it ends or says to quit, prompting for
+	/// them if stdin is a terminal
+	pub fn run(&mut self, mut input: impl BufRead) {
+		let prompt = std::io::stdin().is_terminal();
+		let mut line = String::new();
+		loop {
+			if prompt {
+				print!("> ");
+				let _ = std::io::stdout().flush();
+			}
+			line.clear();
+			match input.read_line(&mut line) {
+				Ok(0) => break,
+				Ok(_) => {}
+				Err(err) => {
+					error!("Failed to read a command, {err}");
+					break;
+				}
+			}
+			match ReplCommand::parse(&line) {
+				Ok(Some(ReplCommand::Quit)) => break,
+				Ok(Some(command)) => self.execute(command),
+				Ok(None) => {}
+				Err(err) => error!("{err}"),
+			}
+		}
+	}
+	fn execute(&mut self, command: ReplCommand) {
+		match command {
+			ReplCommand::Match(path) => {
+				let start = std::time::Instant::now();
+				let name = path.to_string_lossy();
+				let matches = crate::match_sample(self.db, &path, &name, self.top);
+				crate::print_matches(
+					self.db,
+					&name,
+					matches,
+					start.elapsed(),
+					self.top,
+					self.policy,
+					crate::OutputFormat::Table,
+				);
+				println!("  in {:?}", start.elapsed());
+			}
+			ReplCommand::List => {
+				for id in self.db.song_ids() {
+					println!(
+						"{id}: {}",
+						self.db.song_display_name(id).unwrap_or_default()
+					);
+				}
+			}
+			ReplCommand::Stats => crate::print_stats(self.db),
+			ReplCommand::Threshold(ratio) => {
+				if let Some(ratio) = ratio {
+					self.policy.min_score_ratio = ratio;
+				}
+				println!("Score ratio threshold: {}", self.policy.min_score_ratio);
+			}
+			ReplCommand::Top(top) => {
+				if let Some(top) = top {
+					self.top = top;
+				}
+				println!("Listing {} matches", self.top);
+			}
+			ReplCommand::Help => println!("{HELP}"),
+			ReplCommand::Quit => {}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use shazoom::database::DatabaseConfig;
+
+	#[test]
+	fn parses_commands() {
+		assert!(matches!(
+			ReplCommand::parse("  match my song.wav \n"),
+			Ok(Some(ReplCommand::Match(path))) if path.as_os_str() == "my song.wav"
+		));
+		assert!(matches!(
+			ReplCommand::parse("list"),
+			Ok(Some(ReplCommand::List))
+		));
+		assert!(matches!(
+			ReplCommand::parse("stats"),
+			Ok(Some(ReplCommand::Stats))
+		));
+		assert!(matches!(
+			ReplCommand::parse("threshold"),
+			Ok(Some(ReplCommand::Threshold(None)))
+		));
+		assert!(matches!(
+			ReplCommand::parse("threshold 2.5"),
+			Ok(Some(ReplCommand::Threshold(Some(ratio)))) if ratio == 2.5
+		));
+		assert!(matches!(
+			ReplCommand::parse("top 3"),
+			Ok(Some(ReplCommand::Top(Some(3))))
+		));
+		assert!(matches!(
+			ReplCommand::parse("help"),
+			Ok(Some(ReplCommand::Help))
+		));
+		assert!(matches!(
+			ReplCommand::parse("exit"),
+			Ok(Some(ReplCommand::Quit))
+		));
+		assert!(matches!(ReplCommand::parse(" \n"), Ok(None)));
+	}
+
+	#[test]
+	fn rejects_bad_commands() {
+		for line in [
+			"match",
+			"list all",
+			"threshold high",
+			"top -1",
+			"play song.wav",
+		] {
+			assert!(ReplCommand::parse(line).is_err(), "{line:?} parses");
+		}
+	}
+
+	#[test]
+	fn session_keeps_settings_until_quit() {
+		let db = Database::new(DatabaseConfig::builder().build());
+		let policy = MatchPolicy {
+			min_aligned: 50,
+			min_score_ratio: 1.5,
+		};
+		let mut session = Session::new(&db, policy, 5);
+		// Neither a bad command nor a sample that can't be read ends the session
+		let input = "threshold 2.5\nfrobnicate\nmatch /nonexistent.wav\ntop 3\nquit\ntop 9\n";
+		session.run(input.as_bytes());
+		assert_eq!(session.policy.min_score_ratio, 2.5);
+		assert_eq!(session.top, 3);
+	}
+}
@@ -0,0 +1,311 @@
+//! A small HTTP/1.1 server answering identification requests against a built database,
+//! one thread per connection and one request per connection
+
+use std::{
+	collections::HashMap,
+	io::{BufRead, BufReader, Read, Write},
+	net::{TcpListener, TcpStream},
+	sync::Arc,
+};
+
+use log::{debug, error, info, warn};
+use serde::Serialize;
+
+use shazoom::database::{self, Database, MatchPolicy};
+use shazoom::metadata::SongMeta;
+
+/// Most bytes the request line and headers may take up together
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// Connections idle for longer than this are dropped, so stalled clients don't pin a
+/// thread forever
+const IO_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How requests are answered
+#[derive(Debug, Clone, Copy)]
+pub struct ServeOptions {
+	/// Matches returned for a sample, unless the request asks for another number
+	pub top: usize,
+	/// Largest request body accepted, in bytes
+	pub max_body_size: usize,
+	/// When the best match is reported as identified
+	pub policy: MatchPolicy,
+}
+
+/// Answers requests on `listener` until it fails:
+/// - `POST /match[?top=N]` with a WAV file as the body, returning a `QueryReport`
+/// - `GET /songs`, listing every indexed song
+/// - `GET /healthz`
+pub fn serve(listener: TcpListener, db: Arc<Database>, options: ServeOptions) {
+	for stream in listener.incoming() {
+		let stream = match stream {
+			Ok(stream) => stream,
+			Err(err) => {
+				error!("Failed to accept a connection, {err}");
+				continue;
+			}
+		};
+		let db = Arc::clone(&db);
+		std::thread::spawn(move || {
+			let peer = stream.peer_addr().ok();
+			if let Err(err) = handle_connection(stream, &db, options) {
+				debug!("Connection from {peer:?} failed, {err}");
+			}
+		});
+	}
+}
+
+/// A response status along with the JSON body explaining it
+struct Response {
+	status: u16,
+	body: String,
+}
+impl Response {
+	fn json(status: u16, body: &impl Serialize) -> Self {
+		Self {
+			status,
+			body: serde_json::to_string(body).expect("responses always serialize"),
+		}
+	}
+	fn error(status: u16, error: impl Into<String>) -> Self {
+		#[derive(Serialize)]
+		struct Error {
+			error: String,
+		}
+		Self::json(
+			status,
+			&Error {
+				error: error.into(),
+			},
+		)
+	}
+	fn write_to(&self, stream: &mut impl Write) -> std::io::Result<()> {
+		write!(
+			stream,
+			"HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: \
+			 close\r\n\r\n{}",
+			self.status,
+			reason_phrase(self.status),
+			self.body.len(),
+			self.body
+		)?;
+		stream.flush()
+	}
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+	match status {
+		200 => "OK",
+		400 => "Bad Request",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		411 => "Length Required",
+		413 => "Payload Too Large",
+		422 => "Unprocessable Entity",
+		431 => "Request Header Fields Too Large",
+		501 => "Not Implemented",
+		_ => "",
+	}
+}
+
+/// The request line and headers of a request, header names lowercased
+struct RequestHead {
+	method: String,
+	path: String,
+	query: HashMap<String, String>,
+	headers: HashMap<String, String>,
+}
+
+fn handle_connection(
+	stream: TcpStream,
+	db: &Database,
+	options: ServeOptions,
+) -> std::io::Result<()> {
+	stream.set_read_timeout(Some(IO_TIMEOUT))?;
+	stream.set_write_timeout(Some(IO_TIMEOUT))?;
+	let mut writer = stream.try_clone()?;
+	let mut reader = BufReader::new(stream);
+	let start = std::time::Instant::now();
+	let response = match read_head(&mut reader)? {
+		Ok(head) => {
+			let response = respond(&head, &mut reader, &mut writer, db, options)?;
+			info!(
+				"{} {} answered {} in {:?}",
+				head.method,
+				head.path,
+				response.status,
+				start.elapsed()
+			);
+			response
+		}
+		Err(response) => response,
+	};
+	response.write_to(&mut writer)
+}
+
+/// Reads the request line and headers, or the response rejecting them
+fn read_head(reader: &mut impl BufRead) -> std::io::Result<Result<RequestHead, Response>> {
+	let mut lines = Vec::new();
+	let mut head_size = 0;
+	loop {
+		let mut line = Vec::new();
+		let remaining = (MAX_HEAD_SIZE - head_size) as u64;
+		let read = reader
+			.by_ref()
+			.take(remaining)
+			.read_until(b'\n', &mut line)?;
+		head_size += read;
+		if read == 0 || !line.ends_with(b"\n") {
+			return Ok(Err(match head_size >= MAX_HEAD_SIZE {
+				true => Response::error(431, "request head too large"),
+				false => Response::error(400, "incomplete request head"),
+			}));
+		}
+		let line = String::from_utf8_lossy(&line).trim_end().to_owned();
+		if line.is_empty() {
+			break;
+		}
+		lines.push(line);
+	}
+	let mut lines = lines.into_iter();
+	let request_line = lines.next().unwrap_or_default();
+	let mut parts = request_line.split_whitespace();
+	let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+	else {
+		return Ok(Err(Response::error(400, "malformed request line")));
+	};
+	let (path, query) = target.split_once('?').unwrap_or((target, ""));
+	let query = query
+		.split('&')
+		.filter(|pair| !pair.is_empty())
+		.map(|pair| {
+			let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+			(key.to_owned(), value.to_owned())
+		})
+		.collect();
+	let mut headers = HashMap::new();
+	for line in lines {
+		let Some((name, value)) = line.split_once(':') else {
+			return Ok(Err(Response::error(
+				400,
+				format!("malformed header {line:?}"),
+			)));
+		};
+		headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+	}
+	Ok(Ok(RequestHead {
+		method: method.to_owned(),
+		path: path.to_owned(),
+		query,
+		headers,
+	}))
+}
+
+fn respond(
+	head: &RequestHead,
+	reader: &mut impl Read,
+	writer: &mut impl Write,
+	db: &Database,
+	options: ServeOptions,
+) -> std::io::Result<Response> {
+	Ok(match (head.method.as_str(), head.path.as_str()) {
+		("GET", "/healthz") => {
+			#[derive(Serialize)]
+			struct Health {
+				status: &'static str,
+			}
+			Response::json(200, &Health { status: "ok" })
+		}
+		("GET", "/songs") => Response::json(200, &songs(db)),
+		("POST", "/match") => match read_body(head, reader, writer, options.max_body_size)? {
+			Ok(body) => match_body(head, &body, db, options),
+			Err(response) => response,
+		},
+		(_, "/healthz" | "/songs" | "/match") => Response::error(405, "method not allowed"),
+		_ => Response::error(404, "no such endpoint"),
+	})
+}
+
+/// Reads the body of a request with a `Content-Length` of at most `max_size` bytes, or
+/// the response rejecting it
+fn read_body(
+	head: &RequestHead,
+	reader: &mut impl Read,
+	writer: &mut impl Write,
+	max_size: usize,
+) -> std::io::Result<Result<Vec<u8>, Response>> {
+	if head.headers.contains_key("transfer-encoding") {
+		return Ok(Err(Response::error(
+			501,
+			"chunked bodies aren't supported, send a Content-Length",
+		)));
+	}
+	let Some(length) = head.headers.get("content-length") else {
+		return Ok(Err(Response::error(411, "missing Content-Length")));
+	};
+	let Ok(length) = length.parse::<usize>() else {
+		return Ok(Err(Response::error(400, "malformed Content-Length")));
+	};
+	if length > max_size {
+		return Ok(Err(Response::error(
+			413,
+			format!("body of {length} bytes is over the limit of {max_size}"),
+		)));
+	}
+	// Clients waiting to be told to send the body, like curl with larger ones
+	let expects_continue = head
+		.headers
+		.get("expect")
+		.is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
+	if expects_continue {
+		writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+		writer.flush()?;
+	}
+	let mut body = vec![0; length];
+	reader.read_exact(&mut body)?;
+	Ok(Ok(body))
+}
+
+fn match_body(head: &RequestHead, body: &[u8], db: &Database, options: ServeOptions) -> Response {
+	let top = match head.query.get("top").map(|top| top.parse()) {
+		None => options.top,
+		Some(Ok(top)) => top,
+		Some(Err(_)) => return Response::error(400, "top must be a whole number"),
+	};
+	let start = std::time::Instant::now();
+	let sample = match db.config().load_wav(body) {
+		Ok(sample) => sample,
+		Err(err) => return Response::error(400, format!("Failed to decode the body, {err}")),
+	};
+	let matches = match crate::match_decoded(db, &sample, top) {
+		Ok(matches) => matches,
+		Err(err) => return Response::error(422, err),
+	};
+	let identified = options.policy.pick(&matches);
+	let matches = &matches[..top.min(matches.len())];
+	if identified.is_none() {
+		warn!("No match for a {:?} sample", sample.length());
+	}
+	Response::json(
+		200,
+		&crate::QueryReport::new(db, "body", start.elapsed(), identified, matches),
+	)
+}
+
+/// A song listed by `GET /songs`
+#[derive(Serialize)]
+struct SongReport<'a> {
+	id: database::SongId,
+	name: String,
+	#[serde(flatten)]
+	meta: Option<&'a SongMeta>,
+}
+
+fn songs(db: &Database) -> Vec<SongReport<'_>> {
+	db.song_ids()
+		.map(|id| SongReport {
+			id,
+			name: db.song_name(id).unwrap_or_default().into_owned(),
+			meta: db.song_meta(id),
+		})
+		.collect()
+}
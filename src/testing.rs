@@ -1,18 +1,79 @@
-use crate::encoder;
-pub const snrs: [u8; 10] = [10, 20, 30, 40, 50, 60, 70, 80, 90, 100];
-pub const offsets: [usize; 12] = [15, 30, 45, 60, 75, 90, 105, 120, 135, 150, 165, 180];
+use std::{path::Path, time::Duration};
 
-pub fn test() {
-	let song = encoder::Song::from_wav(std::fs::read("test/song.wav").unwrap());
-	let noise = encoder::Song::from_wav(std::fs::read("test/noise.wav").unwrap());
-	for snr in snrs {
-		for offset in offsets {
-			let test_song = encoder::Song::mix(&song, &noise, snr as f32 / 100., offset, 15);
-			std::fs::write(
-				dbg!(format!("test/{}/{}.wav", snr, offset)),
-				encoder::Song::to_wav(test_song),
-			)
-			.unwrap();
+use log::{info, warn};
+
+use shazoom::encoder;
+
+use crate::eval::{EvalDataset, EvalQuery};
+
+pub const SNRS: [i8; 6] = [-5, 0, 5, 10, 15, 20];
+pub const OFFSETS: [u64; 12] = [15, 30, 45, 60, 75, 90, 105, 120, 135, 150, 165, 180];
+
+/// Writes `duration` of every one of `songs`, named as in the database, from each of
+/// `offset_secs` mixed with the start of `noise` at each of `snr_dbs` dB, to
+/// `<out>/<song>/<snr>/<offset>.wav`, stretching `noise` by `short_noise` if needed.
+/// Returns the dataset describing them, with paths relative to `out`
+pub fn write_fixtures(
+	songs: &[(String, encoder::Song)],
+	noise: &encoder::Song,
+	out: &Path,
+	snr_dbs: &[i8],
+	offset_secs: &[u64],
+	duration: Duration,
+	short_noise: encoder::ShortNoise,
+) -> Result<EvalDataset, String> {
+	if noise.length() < duration && short_noise == encoder::ShortNoise::Loop {
+		info!("The noise is only {:?} long, looping it", noise.length());
+	}
+	let mut dataset = EvalDataset {
+		queries: Vec::new(),
+		noise_queries: offset_secs.len(),
+	};
+	for (name, song) in songs {
+		for &offset in offset_secs {
+			let offset = Duration::from_secs(offset);
+			if song.length() <= offset {
+				warn!(
+					"{name:?} is only {:?} long, skipping offset {offset:?}",
+					song.length()
+				);
+				continue;
+			}
+			for &snr in snr_dbs {
+				let dir = out.join(name).join(snr.to_string());
+				std::fs::create_dir_all(&dir)
+					.map_err(|err| format!("Failed to create {dir:?}, {err}"))?;
+				let sample = match encoder::Song::mix(
+					song,
+					noise,
+					snr as f32,
+					offset,
+					duration,
+					short_noise,
+				) {
+					Ok(sample) => sample,
+					Err(encoder::MixError::SilentSignal) => {
+						warn!("{name:?} is silent from {offset:?}, skipping it");
+						break;
+					}
+					Err(err) => return Err(format!("Failed to mix {name:?}, {err}")),
+				};
+				let path = Path::new(name)
+					.join(snr.to_string())
+					.join(format!("{}.wav", offset.as_secs()));
+				std::fs::write(
+					out.join(&path),
+					encoder::Song::to_wav(sample, encoder::WavOptions::default()),
+				)
+				.map_err(|err| format!("Failed to write {:?}, {err}", out.join(&path)))?;
+				dataset.queries.push(EvalQuery {
+					path,
+					expected_song: Some(name.clone()),
+					expected_offset_secs: Some(offset.as_secs_f64()),
+					snr: Some(snr),
+				});
+			}
 		}
 	}
+	Ok(dataset)
 }
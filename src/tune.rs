@@ -0,0 +1,257 @@
+//! Sweeping a grid of configs, each built and evaluated against the same queries, see
+//! `sweep`
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use shazoom::database::{DatabaseConfig, DatabaseConfigBuilder, DatabaseStats};
+
+use crate::eval::EvalReport;
+
+/// Values to try for fields of the config, named as in config files. Every combination
+/// of them is a config of the sweep
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct Grid {
+	fields: BTreeMap<String, Vec<serde_json::Value>>,
+}
+impl Grid {
+	/// Reads a grid written as TOML or, unless its extension is `toml`, JSON, such as
+	/// `freq_per_slice = [6, 8]` on one line and `slice_size_ms = [200, 260]` on the next
+	pub fn read(path: &Path) -> Result<Self, String> {
+		let contents = std::fs::read_to_string(path)
+			.map_err(|err| format!("Failed to read the grid {path:?}, {err}"))?;
+		let grid: Self = match path
+			.extension()
+			.is_some_and(|extension| extension == "toml")
+		{
+			true => toml::from_str(&contents).map_err(|err| err.to_string()),
+			false => serde_json::from_str(&contents).map_err(|err| err.to_string()),
+		}
+		.map_err(|err| format!("Failed to parse the grid {path:?}, {err}"))?;
+		if let Some((field, _)) = grid.fields.iter().find(|(_, values)| values.is_empty()) {
+			return Err(format!("The grid {path:?} has no values for {field}"));
+		}
+		Ok(grid)
+	}
+	/// `base` with every combination of the values of the grid, the last field varying
+	/// fastest. Fields a config file can't hold are an error
+	pub fn configs(
+		&self,
+		base: DatabaseConfigBuilder,
+	) -> Result<Vec<DatabaseConfigBuilder>, String> {
+		let serde_json::Value::Object(base) =
+			serde_json::to_value(base).expect("configs always serialize")
+		else {
+			unreachable!("configs serialize to objects")
+		};
+		if let Some(field) = self.fields.keys().find(|&field| !base.contains_key(field)) {
+			return Err(format!(
+				"{field:?} isn't a config field, see the config written next to --db-file for \
+				 those"
+			));
+		}
+		let lengths: Vec<_> = self.fields.values().map(Vec::len).collect();
+		let mut configs = Vec::new();
+		// Which value of every field the next config takes, counting up like an odometer
+		let mut indices = vec![0; lengths.len()];
+		loop {
+			let mut config = base.clone();
+			for ((field, values), &i) in self.fields.iter().zip(&indices) {
+				config.insert(field.clone(), values[i].clone());
+			}
+			let config = serde_json::from_value(serde_json::Value::Object(config))
+				.map_err(|err| format!("Invalid value in the grid, {err}"))?;
+			configs.push(config);
+			let Some(field) = (0..lengths.len())
+				.rev()
+				.find(|&field| indices[field] + 1 < lengths[field])
+			else {
+				return Ok(configs);
+			};
+			indices[field] += 1;
+			indices[field + 1..].fill(0);
+		}
+	}
+	/// The values of the grid's fields in `config`, as `field=value`
+	fn describe(&self, config: &DatabaseConfigBuilder) -> Vec<String> {
+		let config = serde_json::to_value(config).expect("configs always serialize");
+		self.fields
+			.keys()
+			.map(|field| format!("{field}={}", field_value(&config[field])))
+			.collect()
+	}
+}
+
+/// `value` of a config field as written in a grid, strings without their quotes
+fn field_value(value: &serde_json::Value) -> String {
+	match value {
+		serde_json::Value::String(value) => value.clone(),
+		value => value.to_string(),
+	}
+}
+
+/// How one config of a sweep fared, as written to `<out>/<cached dir name>.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TuneResult {
+	pub config: DatabaseConfigBuilder,
+	/// See `EvalReport::accuracy`
+	pub accuracy: f32,
+	/// Queries of indexed songs identified as them
+	pub identified: usize,
+	/// Queries of indexed songs
+	pub positives: usize,
+	/// Negatives identified as a song
+	pub false_positives: usize,
+	pub negatives: usize,
+	/// Postings in the index
+	pub signatures: usize,
+	pub distinct_signatures: usize,
+	/// See `DatabaseStats::estimated_memory`
+	pub estimated_memory: usize,
+	/// Seconds building took, which only fingerprints the songs not cached yet
+	pub build_secs: f64,
+	/// Average milliseconds matching a query took, see `EvalReport::mean_match_time`
+	pub match_ms: Option<f64>,
+}
+impl TuneResult {
+	pub fn new(
+		config: DatabaseConfigBuilder,
+		report: &EvalReport,
+		stats: &DatabaseStats,
+		build_time: std::time::Duration,
+	) -> Self {
+		Self {
+			config,
+			accuracy: report.accuracy(),
+			identified: report.groups.iter().map(|group| group.identified).sum(),
+			positives: report.groups.iter().map(|group| group.queries).sum(),
+			false_positives: report.false_positives,
+			negatives: report.negatives,
+			signatures: stats.signature_count,
+			distinct_signatures: stats.distinct_signatures,
+			estimated_memory: stats.estimated_memory,
+			build_secs: build_time.as_secs_f64(),
+			match_ms: report
+				.mean_match_time()
+				.map(|time| time.as_secs_f64() * 1000.),
+		}
+	}
+}
+
+/// Evaluates every config of `grid` on top of `base` with `evaluate`, best first. Each
+/// result is saved to `out` as soon as it's in, and configs already saved there aren't
+/// evaluated again, so an interrupted sweep picks up where it stopped. That assumes
+/// the same queries and matching flags, so a sweep with others needs another `out`
+pub fn sweep(
+	grid: &Grid,
+	base: DatabaseConfigBuilder,
+	out: &Path,
+	mut evaluate: impl FnMut(DatabaseConfigBuilder, DatabaseConfig) -> TuneResult,
+) -> Result<Vec<TuneResult>, String> {
+	let configs = grid.configs(base)?;
+	std::fs::create_dir_all(out).map_err(|err| format!("Failed to create {out:?}, {err}"))?;
+	let count = configs.len();
+	let mut results = Vec::new();
+	for (i, builder) in configs.into_iter().enumerate() {
+		let description = grid.describe(&builder).join(", ");
+		let config = match builder.try_build() {
+			Ok(config) => config,
+			Err(err) => {
+				warn!("Skipping {description}, {err}");
+				continue;
+			}
+		};
+		let mut file_name = config.cached_dir_name();
+		file_name.push(".json");
+		let path = out.join(file_name);
+		let saved = match std::fs::read_to_string(&path) {
+			Ok(json) => match serde_json::from_str(&json) {
+				Ok(result) => Some(result),
+				Err(err) => {
+					warn!("Evaluating {description} again, {path:?} is unreadable, {err}");
+					None
+				}
+			},
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+			Err(err) => return Err(format!("Failed to read {path:?}, {err}")),
+		};
+		if let Some(result) = saved {
+			info!("Already evaluated {description}, in {path:?}");
+			results.push(result);
+			continue;
+		}
+		info!("Evaluating config {}/{count}: {description}", i + 1);
+		let result = evaluate(builder, config);
+		info!(
+			"{description}: accuracy {}, identified {}/{}, saving it to {path:?}",
+			result.accuracy, result.identified, result.positives
+		);
+		let json = serde_json::to_string_pretty(&result).expect("results always serialize");
+		// Through a temporary file, so an interrupted write isn't taken for a result
+		let tmp_path = path.with_extension("json.tmp");
+		if let Err(err) =
+			std::fs::write(&tmp_path, json).and_then(|_| std::fs::rename(&tmp_path, &path))
+		{
+			error!("Failed to save the result to {path:?}, {err}");
+		}
+		results.push(result);
+	}
+	results.sort_by(|a, b| {
+		b.accuracy
+			.total_cmp(&a.accuracy)
+			.then(a.false_positives.cmp(&b.false_positives))
+			.then(
+				a.match_ms
+					.unwrap_or(f64::INFINITY)
+					.total_cmp(&b.match_ms.unwrap_or(f64::INFINITY)),
+			)
+	});
+	Ok(results)
+}
+
+/// Rows ranking `results`, as returned by `sweep`, the first being the headings. The
+/// grid's fields get a column each
+pub fn ranking(grid: &Grid, results: &[TuneResult]) -> Vec<Vec<String>> {
+	let header = ["rank"]
+		.into_iter()
+		.chain(grid.fields.keys().map(String::as_str))
+		.chain([
+			"accuracy",
+			"identified",
+			"positives",
+			"false_positives",
+			"negatives",
+			"signatures",
+			"distinct_signatures",
+			"estimated_memory",
+			"build_secs",
+			"match_ms",
+		])
+		.map(str::to_owned)
+		.collect();
+	let rows = results.iter().enumerate().map(|(i, result)| {
+		let config = serde_json::to_value(result.config).expect("configs always serialize");
+		std::iter::once((i + 1).to_string())
+			.chain(grid.fields.keys().map(|field| field_value(&config[field])))
+			.chain([
+				format!("{:.4}", result.accuracy),
+				result.identified.to_string(),
+				result.positives.to_string(),
+				result.false_positives.to_string(),
+				result.negatives.to_string(),
+				result.signatures.to_string(),
+				result.distinct_signatures.to_string(),
+				result.estimated_memory.to_string(),
+				format!("{:.2}", result.build_secs),
+				result
+					.match_ms
+					.map_or(String::new(), |ms| format!("{ms:.2}")),
+			])
+			.collect()
+	});
+	std::iter::once(header).chain(rows).collect()
+}
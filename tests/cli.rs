@@ -0,0 +1,34 @@
+//! Exit codes of the command line tool, run as a separate process
+
+use std::process::Command;
+
+/// `INVALID_CONFIG_EXIT_CODE` of the binary
+const INVALID_CONFIG_EXIT_CODE: i32 = 3;
+
+fn shazoom(args: &[&str]) -> Option<i32> {
+	Command::new(env!("CARGO_BIN_EXE_shazoom"))
+		.args(args)
+		.current_dir(std::env::temp_dir())
+		.output()
+		.expect("the binary runs")
+		.status
+		.code()
+}
+
+#[test]
+fn invalid_config_exits_with_its_own_code() {
+	assert_eq!(
+		shazoom(&["build", "--target-zone-offset", "0"]),
+		Some(INVALID_CONFIG_EXIT_CODE)
+	);
+	assert_eq!(
+		shazoom(&["match", "a.wav", "--fft-size", "10000"]),
+		Some(INVALID_CONFIG_EXIT_CODE)
+	);
+}
+
+#[test]
+fn bad_usage_exits_with_clap_code() {
+	assert_eq!(shazoom(&["frobnicate"]), Some(2));
+	assert_eq!(shazoom(&[]), Some(2));
+}
@@ -0,0 +1,139 @@
+//! `serve` answering requests over HTTP, run as a separate process on a free port
+
+use std::{
+	io::{Read, Write},
+	net::{TcpListener, TcpStream},
+	path::PathBuf,
+	process::{Child, Command, Stdio},
+	time::{Duration, Instant},
+};
+
+use serde_json::Value;
+use shazoom::encoder::{Song, WavOptions};
+
+const SAMPLE_RATE: usize = 44100;
+/// How long building the database and starting to listen may take
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A running `shazoom serve`, killed along with its songs when dropped
+struct Server {
+	child: Child,
+	dir: PathBuf,
+	addr: String,
+}
+impl Server {
+	/// Serves three white noise songs, `song0.wav` to `song2.wav`, from a directory
+	/// named after the test
+	fn start(test: &str) -> Server {
+		let dir = std::env::temp_dir().join(format!("shazoom-{test}-{}", std::process::id()));
+		let songs_dir = dir.join("songs");
+		std::fs::create_dir_all(&songs_dir).unwrap();
+		for seed in 0..3 {
+			let song = Song::white_noise(Duration::from_secs(10), SAMPLE_RATE, seed);
+			let wav = Song::to_wav(song, WavOptions::default());
+			std::fs::write(songs_dir.join(format!("song{seed}.wav")), wav).unwrap();
+		}
+		// A port nothing listens on, freed for the server to take
+		let port = TcpListener::bind("127.0.0.1:0")
+			.unwrap()
+			.local_addr()
+			.unwrap()
+			.port();
+		let addr = format!("127.0.0.1:{port}");
+		let child = Command::new(env!("CARGO_BIN_EXE_shazoom"))
+			.args(["serve", "--bind", &addr, "--songs-dir"])
+			.arg(&songs_dir)
+			.arg("--cache-dir")
+			.arg(dir.join("cache"))
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+			.expect("the binary runs");
+		let mut server = Server { child, dir, addr };
+		let start = Instant::now();
+		while TcpStream::connect(&server.addr).is_err() {
+			if let Some(status) = server.child.try_wait().unwrap() {
+				panic!("serve exited with {status} before listening");
+			}
+			assert!(start.elapsed() < STARTUP_TIMEOUT, "serve never listened");
+			std::thread::sleep(Duration::from_millis(50));
+		}
+		server
+	}
+	/// Status and JSON body of the response to a request with `head` and `body`
+	fn request(&self, head: &str, body: &[u8]) -> (u16, Value) {
+		let mut stream = TcpStream::connect(&self.addr).unwrap();
+		stream.write_all(head.as_bytes()).unwrap();
+		stream.write_all(body).unwrap();
+		let mut response = Vec::new();
+		stream.read_to_end(&mut response).unwrap();
+		let response = String::from_utf8(response).unwrap();
+		let (head, body) = response
+			.split_once("\r\n\r\n")
+			.expect("a complete response");
+		let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+		(status, serde_json::from_str(body).expect("a JSON body"))
+	}
+	fn get(&self, path: &str) -> (u16, Value) {
+		self.request(&format!("GET {path} HTTP/1.1\r\nHost: test\r\n\r\n"), &[])
+	}
+	fn post(&self, path: &str, body: &[u8]) -> (u16, Value) {
+		let head = format!(
+			"POST {path} HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n",
+			body.len()
+		);
+		self.request(&head, body)
+	}
+}
+impl Drop for Server {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+		let _ = std::fs::remove_dir_all(&self.dir);
+	}
+}
+
+#[test]
+fn serves_health_songs_and_matches() {
+	let server = Server::start("serves_health_songs_and_matches");
+
+	assert_eq!(
+		server.get("/healthz"),
+		(200, serde_json::json!({ "status": "ok" }))
+	);
+
+	let (status, songs) = server.get("/songs");
+	assert_eq!(status, 200);
+	let names: Vec<_> = songs
+		.as_array()
+		.unwrap()
+		.iter()
+		.map(|song| song["name"].as_str().unwrap())
+		.collect();
+	assert_eq!(names, ["song0.wav", "song1.wav", "song2.wav"]);
+	let id = songs[1]["id"].clone();
+
+	let sample = Song::white_noise(Duration::from_secs(10), SAMPLE_RATE, 1)
+		.slice(Duration::from_secs(3), Duration::from_secs(5));
+	let (status, report) =
+		server.post("/match?top=2", &Song::to_wav(sample, WavOptions::default()));
+	assert_eq!(status, 200);
+	assert_eq!(report["identified"], id);
+	assert_eq!(report["matches"][0]["id"], id);
+	assert!(report["matches"].as_array().unwrap().len() <= 2);
+	let offset = report["matches"][0]["offset_secs"].as_f64().unwrap();
+	assert!((offset - 3.).abs() < 0.1, "matched at {offset} s");
+}
+
+#[test]
+fn rejects_bad_requests() {
+	let server = Server::start("rejects_bad_requests");
+	let (status, report) = server.post("/match", b"not a wav file");
+	assert_eq!(status, 400);
+	assert!(report["error"].is_string());
+	assert_eq!(server.post("/match?top=many", b"").0, 400);
+	let (status, _) = server.request("POST /match HTTP/1.1\r\nHost: test\r\n\r\n", &[]);
+	assert_eq!(status, 411);
+	assert_eq!(server.get("/match").0, 405);
+	assert_eq!(server.get("/nowhere").0, 404);
+}